@@ -157,7 +157,7 @@ impl VirtAddr {
         }
     }
 
-    pub fn raw(&self) -> u64 {
+    pub const fn raw(&self) -> u64 {
         self.0
     }
 
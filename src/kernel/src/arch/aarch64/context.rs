@@ -1,12 +1,13 @@
 use arm64::registers::{TTBR0_EL1, TTBR1_EL1};
+use twizzler_abi::device::CacheType;
 
 use crate::{
     arch::memory::pagetables::{Entry, EntryFlags, Table},
     memory::{
         frame::{alloc_frame, free_frame, get_frame, PhysicalFrameFlags},
         pagetables::{
-            DeferredUnmappingOps, MapReader, Mapper, MappingCursor, MappingSettings,
-            PhysAddrProvider,
+            DeferredUnmappingOps, InvalidationBatch, MapReader, Mapper, MappingCursor,
+            MappingSettings, PhysAddrProvider,
         },
         PhysAddr,
     },
@@ -78,6 +79,21 @@ impl ArchContext {
         Self::new_kernel()
     }
 
+    /// Can memory be mapped executable but not readable? User mappings can be, by giving EL0 no
+    /// data access while still allowing it to execute.
+    pub fn supports_execute_only() -> bool {
+        true
+    }
+
+    /// Can memory be mapped with the given caching type? Normal memory is always mapped
+    /// write-back, so write-through and write-combining are not supported.
+    pub fn supports_cache_type(cache: CacheType) -> bool {
+        matches!(
+            cache,
+            CacheType::WriteBack | CacheType::Uncacheable | CacheType::MemoryMappedIO
+        )
+    }
+
     pub fn switch_to(&self) {
         unsafe {
             Self::switch_to_target(&self.target);
@@ -134,6 +150,60 @@ impl ArchContext {
         }
     }
 
+    /// Change the mapping settings of a region, queuing the TLB invalidations in batch.
+    pub fn change_batched(
+        &self,
+        cursor: MappingCursor,
+        settings: &MappingSettings,
+        batch: &mut InvalidationBatch,
+    ) {
+        if cursor.start().is_kernel() {
+            KERNEL_MAPPER.lock().change_batched(cursor, settings, batch);
+        } else {
+            self.inner
+                .lock()
+                .mapper
+                .change_batched(cursor, settings, batch);
+        }
+    }
+
+    /// Invalidate the TLB entries for the present mappings within a region, without changing the
+    /// mappings.
+    pub fn flush(&self, cursor: MappingCursor) {
+        if cursor.start().is_kernel() {
+            KERNEL_MAPPER.lock().flush(cursor);
+        } else {
+            self.inner.lock().mapper.flush(cursor);
+        }
+    }
+
+    /// Map a region like [Self::map], but return false instead of panicking if a page table could
+    /// not be allocated, in which case the region may be only partly mapped.
+    #[must_use]
+    pub fn try_map(
+        &self,
+        cursor: MappingCursor,
+        phys: &mut impl PhysAddrProvider,
+        settings: &MappingSettings,
+    ) -> bool {
+        if cursor.start().is_kernel() {
+            KERNEL_MAPPER.lock().try_map(cursor, phys, settings)
+        } else {
+            self.inner.lock().mapper.try_map(cursor, phys, settings)
+        }
+    }
+
+    /// Allocate the page tables needed to map a region, without mapping any pages. Returns false
+    /// if a page table could not be allocated.
+    #[must_use]
+    pub fn reserve(&self, cursor: MappingCursor) -> bool {
+        if cursor.start().is_kernel() {
+            KERNEL_MAPPER.lock().reserve(cursor)
+        } else {
+            self.inner.lock().mapper.reserve(cursor)
+        }
+    }
+
     pub fn unmap(&self, cursor: MappingCursor) {
         let ops = if cursor.start().is_kernel() {
             KERNEL_MAPPER.lock().unmap(cursor)
@@ -143,8 +213,84 @@ impl ArchContext {
         ops.run_all();
     }
 
-    pub fn readmap<R>(&self, _cursor: MappingCursor, _f: impl Fn(MapReader) -> R) -> R {
-        todo!("readmap")
+    /// Unmap a region, queuing the TLB invalidations and page table frees in batch.
+    pub fn unmap_batched(&self, cursor: MappingCursor, batch: &mut InvalidationBatch) {
+        if cursor.start().is_kernel() {
+            KERNEL_MAPPER.lock().unmap_batched(cursor, batch);
+        } else {
+            self.inner.lock().mapper.unmap_batched(cursor, batch);
+        }
+    }
+
+    /// Find the dirty mappings within a region, calling `f` with the virtual address and length of
+    /// each. If `clear` is set, the dirty state of those mappings is also cleared.
+    pub fn collect_dirty(
+        &self,
+        cursor: MappingCursor,
+        clear: bool,
+        mut f: impl FnMut(VirtAddr, usize),
+    ) {
+        if cursor.start().is_kernel() {
+            KERNEL_MAPPER.lock().collect_dirty(cursor, clear, &mut f);
+        } else {
+            self.inner
+                .lock()
+                .mapper
+                .collect_dirty(cursor, clear, &mut f);
+        }
+    }
+
+    /// Read the accessed state of the mappings within a region, calling `f` with the virtual
+    /// address and length of each mapping, and whether it has been accessed. If `clear` is set,
+    /// the accessed state of those mappings is also cleared.
+    pub fn harvest_accessed(
+        &self,
+        cursor: MappingCursor,
+        clear: bool,
+        mut f: impl FnMut(VirtAddr, usize, bool),
+    ) {
+        if cursor.start().is_kernel() {
+            KERNEL_MAPPER.lock().harvest_accessed(cursor, clear, &mut f);
+        } else {
+            self.inner
+                .lock()
+                .mapper
+                .harvest_accessed(cursor, clear, &mut f);
+        }
+    }
+
+    /// Mark the mappings within a region as accessed, resolving an access flag fault on them.
+    pub fn set_accessed(&self, cursor: MappingCursor) {
+        if cursor.start().is_kernel() {
+            KERNEL_MAPPER.lock().set_accessed(cursor);
+        } else {
+            self.inner.lock().mapper.set_accessed(cursor);
+        }
+    }
+
+    /// Flush any pending writes to the memory mapped by the cursor (e.g. in write-combining
+    /// buffers), and then unmap it.
+    pub fn flush_unmap(&self, cursor: MappingCursor) {
+        // Wait for any outstanding writes on this CPU to complete. Other CPUs synchronize as part
+        // of the TLB invalidation during the unmap.
+        unsafe {
+            core::arch::asm!("dsb sy");
+        }
+        self.unmap(cursor);
+    }
+
+    pub fn readmap<R>(&self, cursor: MappingCursor, f: impl FnOnce(MapReader) -> R) -> R {
+        if cursor.start().is_kernel() {
+            f(KERNEL_MAPPER.lock().readmap(cursor))
+        } else {
+            f(self.inner.lock().mapper.readmap(cursor))
+        }
+    }
+
+    /// The number of frames occupied by this context's own page tables, including the root. The
+    /// shared kernel tables are not counted.
+    pub fn table_frames(&self) -> usize {
+        self.inner.lock().mapper.table_frames()
     }
 }
 
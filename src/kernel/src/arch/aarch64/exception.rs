@@ -18,7 +18,19 @@ use twizzler_abi::{
 };
 
 use super::thread::UpcallAble;
-use crate::memory::{context::virtmem::PageFaultFlags, VirtAddr};
+use crate::{
+    memory::{
+        context::{
+            kernel_context,
+            virtmem::{page_fault_failed, PageFaultFlags},
+        },
+        pagetables::MappingCursor,
+        VirtAddr,
+    },
+    obj::PageNumber,
+    security::KERNEL_SCTX,
+    thread::current_memory_context,
+};
 
 core::arch::global_asm!(r#"
 /// Exception Vector Table Definition for EL1 (Kernel)
@@ -462,27 +474,25 @@ fn sync_handler(ctx: &mut ExceptionContext) {
             // TODO: support for PRESENT and INVALID flags
             let flags = PageFaultFlags::empty();
 
-            let far_va = match VirtAddr::new(far as u64) {
-                Ok(v) => v,
-                Err(_) => panic!("non canonical address: {:x}", far),
-            };
-
             // DFSC bits[5:0] indicate the type of fault
             let dfsc = iss & 0b111111;
-            if dfsc & 0b111100 == 0b001000 {
-                // we have an access fault
-                let level = dfsc & 0b11;
-                todo!("Access flag fault, level {}", level);
-                // TODO: set the access flag
-            } else if dfsc & 0b001100 == 0b001100 {
+            // we have an access fault
+            let access_flag = dfsc & 0b111100 == 0b001000;
+            if !access_flag && dfsc & 0b001100 == 0b001100 {
                 let level = dfsc & 0b11;
-                todo!("Permission fault, level {} {:?} {:?}", level, cause, far_va);
+                todo!("Permission fault, level {} {:?} {:#x}", level, cause, far);
             }
             crate::thread::enter_kernel();
             crate::interrupt::set(true);
             let elr = ctx.elr;
-            if let Ok(elr_va) = VirtAddr::new(elr) {
-                crate::memory::context::virtmem::page_fault(far_va, cause, flags, elr_va);
+            if access_flag {
+                access_flag_fault(far);
+            } else if let Ok(elr_va) = VirtAddr::new(elr) {
+                if let Err(err) =
+                    crate::memory::context::virtmem::page_fault(far as u64, cause, flags, elr_va)
+                {
+                    page_fault_failed(far as u64, flags, err);
+                }
             } else {
                 todo!("send upcall exception info");
             }
@@ -529,16 +539,11 @@ fn handle_inst_abort(
     // user space, so we know that the page fault is user
     let flags = PageFaultFlags::USER;
 
-    let far_va = VirtAddr::new(far as u64).unwrap();
-
     // IFSC bits[5:0] indicate the type of fault
     let ifsc = iss & 0b111111;
-    if ifsc & 0b111100 == 0b001000 {
-        // we have an access fault
-        let level = ifsc & 0b11;
-        todo!("Access flag fault, level {}", level);
-        // TODO: set the access flag
-    } else if ifsc & 0b001100 == 0b001100 {
+    // we have an access fault
+    let access_flag = ifsc & 0b111100 == 0b001000;
+    if !access_flag && ifsc & 0b001100 == 0b001100 {
         let level = ifsc & 0b11;
         todo!("Permission fault, level {}", level);
     } else if ifsc & 0b0000100 == 0b0000100 {
@@ -549,9 +554,15 @@ fn handle_inst_abort(
     crate::thread::enter_kernel();
     crate::interrupt::set(true);
     let elr = ctx.elr;
-    if let Ok(elr_va) = VirtAddr::new(elr) {
+    if access_flag {
+        access_flag_fault(far);
+    } else if let Ok(elr_va) = VirtAddr::new(elr) {
         // logln!("fault {:?} from {:?}", far_va, elr_va);
-        crate::memory::context::virtmem::page_fault(far_va, cause, flags, elr_va);
+        if let Err(err) =
+            crate::memory::context::virtmem::page_fault(far as u64, cause, flags, elr_va)
+        {
+            page_fault_failed(far as u64, flags, err);
+        }
     } else {
         todo!("send upcall exception info");
     }
@@ -559,6 +570,24 @@ fn handle_inst_abort(
     crate::thread::exit_kernel();
 }
 
+/// Handle an access flag fault at the faulting address far. The page is mapped, but its access
+/// flag was cleared to track whether it's accessed (see [VirtContext::harvest_accessed]), so
+/// setting the flag again resolves the fault, and the access is retried.
+///
+/// [VirtContext::harvest_accessed]: crate::memory::context::virtmem::VirtContext::harvest_accessed
+fn access_flag_fault(far: u64) {
+    // Only present mappings take access flag faults, so the address must be canonical.
+    let addr = VirtAddr::new(far).unwrap();
+    let page = addr.align_down(PageNumber::PAGE_SIZE as u64).unwrap();
+    let cursor = MappingCursor::new(page, PageNumber::PAGE_SIZE);
+    let ctx = if addr.is_kernel() {
+        kernel_context().clone()
+    } else {
+        current_memory_context().expect("access flag fault without a memory context")
+    };
+    ctx.with_arch(KERNEL_SCTX, |arch| arch.set_accessed(cursor));
+}
+
 /// Initializes the exception vector table by writing the address of
 /// the table to the Vector Base Address Register (VBAR).
 pub fn init() {
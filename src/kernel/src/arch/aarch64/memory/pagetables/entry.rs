@@ -34,10 +34,10 @@ impl Entry {
             // attributes, so it is safe to always enable them
             | EntryFlags::SH1_SHAREABLE
             | EntryFlags::SH0_INNER_OR_OUTER
-            // TODO: access flag is managed by software on base
-            // ARMv8-A. This only works since these entries were
-            // already present before when switching from bootstrap
-            // mappings.
+            // The access flag is managed by software on base
+            // ARMv8-A. New mappings start out accessed, and once
+            // the flag is cleared, the next access takes an access
+            // flag fault that sets it again.
             | EntryFlags::ACCESS,
         )
     }
@@ -128,6 +128,34 @@ impl Entry {
         *self = Entry::new_internal(self.addr(super::Table::last_level()), flags);
     }
 
+    /// Has the page mapped by this entry been written since the dirty bit was last cleared?
+    pub fn is_dirty(&self) -> bool {
+        // TODO: we don't enable hardware dirty state management (FEAT_HAFDBS), so be conservative
+        // and report every writable page as dirty.
+        !self.flags().contains(EntryFlags::AP2_READ_OR_RW)
+    }
+
+    /// Clear the dirty bit.
+    pub fn clear_dirty(&mut self) {
+        // Without hardware dirty state management, there is nothing to clear.
+    }
+
+    /// Has the page mapped by this entry been accessed since the accessed bit was last cleared?
+    pub fn is_accessed(&self) -> bool {
+        self.flags().contains(EntryFlags::ACCESS)
+    }
+
+    /// Clear the accessed bit. The next access to the page takes an access flag fault, which sets
+    /// it again (see [Self::set_accessed]).
+    pub fn clear_accessed(&mut self) {
+        self.0 &= !EntryFlags::ACCESS.bits();
+    }
+
+    /// Set the accessed bit, on an access flag fault.
+    pub fn set_accessed(&mut self) {
+        self.0 |= EntryFlags::ACCESS.bits();
+    }
+
     const NEXT_LVL_TABLE_ADDR_MASK: u64 = 0x0000_FFFF_FFFF_F000;
 
     /// Get the base address of the next page table.
@@ -252,14 +280,25 @@ impl EntryFlags {
         if !self.contains(EntryFlags::NOT_GLOBAL) {
             flags.insert(MappingFlags::GLOBAL);
         }
-        if self.contains(EntryFlags::AP1_USER_OR_KERNEL) {
+        if self.contains(EntryFlags::AP1_USER_OR_KERNEL) || self.is_user_execute_only() {
             flags.insert(MappingFlags::USER);
         }
         flags
     }
 
+    /// Is this an execute-only user mapping? These have no EL0 data access, and are only
+    /// executable by EL0. No kernel mapping is executable by EL0 alone.
+    fn is_user_execute_only(&self) -> bool {
+        !self.contains(Self::AP1_USER_OR_KERNEL)
+            && self.contains(Self::KERNEL_NO_EXECUTE)
+            && !self.contains(Self::USER_NO_EXECUTE)
+    }
+
     /// Get the represented permissions as a [Protections].
     pub fn perms(&self) -> Protections {
+        if self.is_user_execute_only() {
+            return Protections::EXEC;
+        }
         let rw = if self.contains(Self::AP2_READ_OR_RW) {
             Protections::READ
         } else {
@@ -341,6 +380,23 @@ impl From<&MappingSettings> for EntryFlags {
         // it gets set as the default (WriteBack)
         let c = EntryFlags::from(settings.cache());
 
+        let is_user = settings.flags().contains(MappingFlags::USER);
+        if is_user
+            && settings.perms().contains(Protections::EXEC)
+            && !settings
+                .perms()
+                .intersects(Protections::READ | Protections::WRITE)
+        {
+            // execute-only: EL0 may execute, but has no data access, and
+            // EL1 may only read (and never execute) the memory
+            let f = if settings.flags().contains(MappingFlags::GLOBAL) {
+                EntryFlags::empty()
+            } else {
+                EntryFlags::NOT_GLOBAL
+            };
+            return c | f | EntryFlags::AP2_READ_OR_RW | EntryFlags::KERNEL_NO_EXECUTE;
+        }
+
         let mut p = EntryFlags::empty();
         if !settings.perms().contains(Protections::WRITE) {
             // set this flag if we only want read-only permissions
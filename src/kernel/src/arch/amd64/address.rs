@@ -109,7 +109,7 @@ impl VirtAddr {
         }
     }
 
-    pub fn raw(&self) -> u64 {
+    pub const fn raw(&self) -> u64 {
         self.0
     }
 
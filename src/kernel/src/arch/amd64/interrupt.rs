@@ -493,10 +493,12 @@ fn generic_isr_handler(ctx: *mut IsrContext, number: u64, user: bool) {
             }
             crate::thread::enter_kernel();
             crate::interrupt::set(true);
-            if let Ok(cr2_va) = VirtAddr::new(cr2 as u64)
-                && let Ok(rip_va) = VirtAddr::new(ctx.rip)
-            {
-                crate::memory::context::virtmem::page_fault(cr2_va, cause, flags, rip_va);
+            if let Ok(rip_va) = VirtAddr::new(ctx.rip) {
+                if let Err(err) =
+                    crate::memory::context::virtmem::page_fault(cr2 as u64, cause, flags, rip_va)
+                {
+                    crate::memory::context::virtmem::page_fault_failed(cr2 as u64, flags, err);
+                }
             } else {
                 // TODO: do we need to do something better?
                 let t = current_thread_ref().unwrap();
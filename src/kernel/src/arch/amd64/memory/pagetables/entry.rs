@@ -81,6 +81,26 @@ impl Entry {
         *self = Entry::new_internal(self.addr(0), flags);
     }
 
+    /// Has the page mapped by this entry been written since the dirty bit was last cleared?
+    pub fn is_dirty(&self) -> bool {
+        self.flags().contains(EntryFlags::DIRTY)
+    }
+
+    /// Clear the dirty bit.
+    pub fn clear_dirty(&mut self) {
+        self.0 &= !EntryFlags::DIRTY.bits();
+    }
+
+    /// Has the page mapped by this entry been accessed since the accessed bit was last cleared?
+    pub fn is_accessed(&self) -> bool {
+        self.flags().contains(EntryFlags::ACCESSED)
+    }
+
+    /// Clear the accessed bit.
+    pub fn clear_accessed(&mut self) {
+        self.0 &= !EntryFlags::ACCESSED.bits();
+    }
+
     /// Get the base address of the next page table.
     pub fn table_addr(&self) -> PhysAddr {
         self.addr(0)
@@ -19,6 +19,7 @@
 #![feature(let_chains)]
 #![feature(btree_extract_if)]
 #![feature(extract_if)]
+#![feature(allocator_api)]
 
 #[macro_use]
 pub mod log;
@@ -173,6 +174,7 @@ pub fn idle_main() -> ! {
     );
     loop {
         sched::schedule(true);
+        obj::pages::refill_zero_pool();
         arch::processor::halt_and_wait();
     }
 }
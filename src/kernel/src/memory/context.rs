@@ -6,7 +6,11 @@
 //! and kernel mappings in the higher-half on x86).
 
 use alloc::sync::Arc;
-use core::{alloc::Layout, ops::Range, ptr::NonNull};
+use core::{
+    alloc::{AllocError, Layout},
+    ops::Range,
+    ptr::NonNull,
+};
 
 use twizzler_abi::{
     device::CacheType,
@@ -15,6 +19,7 @@ use twizzler_abi::{
 
 use self::virtmem::KernelObjectVirtHandle;
 use crate::{
+    memory::pagetables::MappingFlags,
     obj::{InvalidateMode, ObjectRef, PageNumber},
     syscall::object::ObjectHandle,
 };
@@ -43,7 +48,8 @@ pub trait UserContext {
     /// Insert a range of an object into the context. The implementation may choose to use start and
     /// len as hints, but should keep in mind that calls to `insert_object` may be generated by
     /// faults, and so should strive to resolve the fault by correctly mapping the object as
-    /// requested.
+    /// requested. Inserting a mapping identical to one already present takes another reference
+    /// to it, rather than failing.
     fn insert_object(
         self: &Arc<Self>,
         mapping_info: Self::MappingInfo,
@@ -54,7 +60,8 @@ pub trait UserContext {
     fn lookup_object(&self, info: Self::MappingInfo) -> Option<ObjectContextInfo>;
     /// Invalidate any mappings for a particular object.
     fn invalidate_object(&self, obj: ObjID, range: &Range<PageNumber>, mode: InvalidateMode);
-    /// Remove an object from the context.
+    /// Remove an object from the context. If the mapping was inserted more than once, this only
+    /// drops one reference, and the mapping is removed along with the last one.
     fn remove_object(&self, info: Self::MappingInfo);
 }
 
@@ -64,6 +71,15 @@ pub struct ObjectContextInfo {
     object: ObjectRef,
     perms: Protections,
     cache: CacheType,
+    guard_pages: usize,
+    prefault: bool,
+    offset: PageNumber,
+    contiguous: usize,
+    flags: MappingFlags,
+    allow_global: bool,
+    numa_node: Option<u8>,
+    len: Option<usize>,
+    write_protected: bool,
 }
 
 impl ObjectContextInfo {
@@ -72,9 +88,31 @@ impl ObjectContextInfo {
             object,
             perms,
             cache,
+            guard_pages: 0,
+            prefault: false,
+            offset: PageNumber::from(0),
+            contiguous: 0,
+            flags: MappingFlags::USER,
+            allow_global: false,
+            numa_node: None,
+            len: None,
+            write_protected: false,
         }
     }
 
+    /// Reserve a number of guard pages at the start of the mapping, directly after the null page.
+    /// Accesses to guard pages are reported as faults, and guard pages are never mapped.
+    pub fn with_guard_pages(mut self, pages: usize) -> Self {
+        self.guard_pages = pages;
+        self
+    }
+
+    /// Map a different object, keeping the rest of the settings.
+    pub fn with_object(mut self, object: ObjectRef) -> Self {
+        self.object = object;
+        self
+    }
+
     /// The object.
     pub fn object(&self) -> &ObjectRef {
         &self.object
@@ -89,11 +127,142 @@ impl ObjectContextInfo {
     pub fn cache(&self) -> CacheType {
         self.cache
     }
+
+    /// The number of guard pages after the null page.
+    pub fn guard_pages(&self) -> usize {
+        self.guard_pages
+    }
+
+    /// Map the pages of the object that are already present when the object is inserted, instead
+    /// of waiting for them to be faulted in. Pages that are not present are still faulted in
+    /// lazily.
+    pub fn with_prefault(mut self, prefault: bool) -> Self {
+        self.prefault = prefault;
+        self
+    }
+
+    /// Should the object's present pages be mapped on insert?
+    pub fn prefault(&self) -> bool {
+        self.prefault
+    }
+
+    /// Map the object starting at the given page, so that this page of the object appears at the
+    /// start of the slot. Pages of the object before it are not visible in the slot.
+    pub fn with_offset(mut self, offset: PageNumber) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    /// The page of the object that appears at the start of the slot.
+    pub fn offset(&self) -> PageNumber {
+        self.offset
+    }
+
+    /// Back the first pages of the mapping (starting at the first page that may be mapped) with a
+    /// run of physically contiguous pages, allocated when the object is inserted. The pages stay
+    /// in place for as long as the mapping exists, so their physical addresses can be handed to
+    /// devices for DMA. A value of 0 (the default) disables this.
+    pub fn with_contiguous(mut self, pages: usize) -> Self {
+        self.contiguous = pages;
+        self
+    }
+
+    /// The number of physically contiguous pages backing the mapping, or 0 if the mapping is not
+    /// backed by contiguous memory.
+    pub fn contiguous(&self) -> usize {
+        self.contiguous
+    }
+
+    /// Set the flags used to map the object's pages, replacing the default of
+    /// [MappingFlags::USER]. Setting [MappingFlags::GLOBAL] also requires opting in with
+    /// [Self::with_allow_global], since a global mapping is not flushed on a context switch, and so
+    /// may be visible to other contexts.
+    pub fn with_mapping_flags(mut self, flags: MappingFlags) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// The flags used to map the object's pages. For info returned by
+    /// [UserContext::lookup_object], these are the flags the object was inserted with.
+    pub fn mapping_flags(&self) -> MappingFlags {
+        self.flags
+    }
+
+    /// Allow the object to be mapped with [MappingFlags::GLOBAL].
+    pub fn with_allow_global(mut self, allow: bool) -> Self {
+        self.allow_global = allow;
+        self
+    }
+
+    /// May the object be mapped with [MappingFlags::GLOBAL]?
+    pub fn allow_global(&self) -> bool {
+        self.allow_global
+    }
+
+    /// Prefer to allocate the pages faulted in through this mapping from the memory attached to the
+    /// given NUMA node. This is advisory: if that node has no free memory, pages are allocated as
+    /// if no node was given. The boot info does not report memory affinity yet, so all memory
+    /// is currently attached to node 0, and any other node always falls back.
+    pub fn with_numa_node(mut self, node: Option<u8>) -> Self {
+        self.numa_node = node;
+        self
+    }
+
+    /// The NUMA node that pages faulted in through this mapping are allocated from, if any.
+    pub fn numa_node(&self) -> Option<u8> {
+        self.numa_node
+    }
+
+    /// Limit the mapping to the first len bytes of the object. Accesses past the end of the object
+    /// fault with [twizzler_abi::upcall::ObjectMemoryError::OutOfBounds], instead of being filled
+    /// with zero pages, like accesses past the end of a file mapped with mmap. By default, the
+    /// whole slot may be accessed.
+    pub fn with_object_len(mut self, len: usize) -> Self {
+        self.len = Some(len);
+        self
+    }
+
+    /// The length of the object, in bytes, past which accesses fault, if one was set.
+    pub fn object_len(&self) -> Option<usize> {
+        self.len
+    }
+
+    fn with_write_protected(mut self, write_protected: bool) -> Self {
+        self.write_protected = write_protected;
+        self
+    }
+
+    /// Have pages of the mapping been write-protected so that they can be shared copy-on-write?
+    /// If so, writes through the mapping may have to copy pages first. This is only ever set on
+    /// info returned by [UserContext::lookup_object].
+    pub fn write_protected(&self) -> bool {
+        self.write_protected
+    }
 }
 
 /// Errors for inserting objects into a [Context].
 pub enum InsertError {
-    Occupied,
+    /// The target slot already has a different mapping, which is returned.
+    Occupied(ObjectContextInfo),
+    /// The target slot has no object mapped into it.
+    NotMapped,
+    /// A physically contiguous run of the requested size could not be allocated, or does not fit
+    /// in the slot.
+    NoContiguousMemory,
+    /// The target slot does not lie entirely within user memory.
+    InvalidRange,
+    /// The mapping would be both writable and executable, and the context enforces W^X.
+    WxViolation,
+    /// The mapping flags are not allowed, e.g. a global mapping without opting in.
+    InvalidFlags,
+    /// The caching type cannot be used for mappings on this architecture.
+    UnsupportedCache,
+    /// The protections cannot be mapped on this architecture, e.g. execute-only memory.
+    UnsupportedProt,
+    /// The slot is locked against removal, see [virtmem::VirtContext::lock_slot].
+    Locked,
+    /// There was not enough memory to allocate the pages needed.
+    OutOfMemory,
 }
 
 /// A trait for kernel-related memory context actions.
@@ -107,6 +276,9 @@ pub trait KernelMemoryContext {
     /// using an actual allocator. Returns a pointer to the allocated memory and the size of the
     /// allocation (must be greater than layout's size).
     fn allocate_chunk(&self, layout: Layout) -> NonNull<u8>;
+    /// Like [Self::allocate_chunk], but returns an error instead of panicking if the memory cannot
+    /// be allocated.
+    fn try_allocate_chunk(&self, layout: Layout) -> Result<NonNull<u8>, AllocError>;
     /// Deallocate a previously allocated chunk.
     ///
     /// # Safety
@@ -1,14 +1,25 @@
 //! This mod implements [UserContext] and [KernelMemoryContext] for virtual memory systems.
 
-use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
-use core::{intrinsics::size_of, marker::PhantomData, ptr::NonNull};
+use alloc::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    alloc::AllocError,
+    intrinsics::size_of,
+    marker::PhantomData,
+    ops::Range,
+    ptr::NonNull,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
 
 use twizzler_abi::{
     device::CacheType,
     object::{ObjID, Protections, MAX_SIZE, NULLPAGE_SIZE},
     upcall::{
-        MemoryAccessKind, MemoryContextViolationInfo, ObjectMemoryError, ObjectMemoryFaultInfo,
-        UpcallInfo,
+        MemoryAccessKind, MemoryContextViolationInfo, MemoryPressureInfo, ObjectMemoryError,
+        ObjectMemoryFaultInfo, OutOfMemoryInfo, PageSampleInfo, UpcallInfo, UPCALL_EXIT_CODE,
     },
 };
 
@@ -23,14 +34,23 @@ use crate::{
     },
     idcounter::{Id, IdCounter, StableId},
     memory::{
+        frame::{alloc_frame, free_frame, get_frame, FrameRef, PhysicalFrameFlags},
         pagetables::{
-            ContiguousProvider, Mapper, MappingCursor, MappingFlags, MappingSettings,
-            PhysAddrProvider, Table, ZeroPageProvider,
+            ContiguousProvider, InvalidationBatch, MapInfo, Mapper, MappingCursor, MappingFlags,
+            MappingSettings, PhysAddrProvider, Table, ZeroPageProvider,
         },
         PhysAddr,
     },
     mutex::Mutex,
-    obj::{self, pages::Page, ObjectRef, PageNumber},
+    obj::{
+        self,
+        copy::copy_ranges,
+        pages::{Page, PageRef},
+        range::PageRangeTree,
+        register_object, InvalidateMode, LookupFlags, LookupResult, Object, ObjectRef, PageNumber,
+    },
+    once::Once,
+    processor::{current_processor, tls_ready, AtomicCpuSet, CpuSet},
     security::KERNEL_SCTX,
     spinlock::Spinlock,
     thread::{current_memory_context, current_thread_ref},
@@ -46,19 +66,156 @@ pub struct VirtContext {
     slots: Mutex<SlotMgr>,
     id: Id<'static>,
     is_kernel: bool,
+    // Number of pages allocated by faults in this context that are still mapped here.
+    resident: AtomicUsize,
+    fault_stats: FaultStats,
+    fault_observer: Spinlock<Option<FaultObserver>>,
+    // The processors that currently have this context loaded.
+    active_cpus: Arc<AtomicCpuSet>,
+    page_sampling: AtomicBool,
+    // Reject any mapping that is both writable and executable.
+    wx_enforce: bool,
+    // Number of pages allocated by breaking copy-on-write sharing in this context that are still
+    // mapped here, and the soft limit on that number.
+    cow_pages: AtomicUsize,
+    cow_limit: AtomicUsize,
+}
+
+/// A callback invoked after a page fault in a context maps a page of an object.
+pub type FaultObserver = fn(ObjID, PageNumber, MemoryAccessKind);
+
+/// A callback invoked when a fault can't allocate a page for an object because physical memory is
+/// exhausted, before the faulting thread is sent an [UpcallInfo::OutOfMemory] upcall. Returns true
+/// if it freed any memory, in which case the faulting access is retried instead.
+pub type OomHook = fn(ObjID, PageNumber) -> bool;
+
+static OOM_HOOKS: Mutex<Vec<OomHook>> = Mutex::new(Vec::new());
+
+/// Register a hook to be called whenever a fault runs out of memory, e.g. so that a kernel cache
+/// can be shrunk. The hooks are called in the order they were registered, with no context locks
+/// held, but must not register other hooks.
+pub fn register_oom_hook(hook: OomHook) {
+    OOM_HOOKS.lock().push(hook);
+}
+
+/// Run the registered out-of-memory hooks, returning true if any of them freed memory.
+fn run_oom_hooks(id: ObjID, page_number: PageNumber) -> bool {
+    OOM_HOOKS
+        .lock()
+        .iter()
+        .fold(false, |freed, hook| hook(id, page_number) || freed)
+}
+
+/// Counters for the page faults handled in a context.
+#[derive(Default)]
+pub struct FaultStats {
+    minor: AtomicU64,
+    cow: AtomicU64,
+    zero_fill: AtomicU64,
+    violations: AtomicU64,
+    spurious: AtomicU64,
+}
+
+/// A point-in-time copy of a context's [FaultStats].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FaultStatsSnapshot {
+    /// Faults resolved by mapping a page that was already present in the object.
+    pub minor: u64,
+    /// Faults resolved by breaking copy-on-write sharing.
+    pub cow: u64,
+    /// Faults resolved by allocating a new, zeroed page.
+    pub zero_fill: u64,
+    /// Faults that resulted in an upcall to the faulting thread.
+    pub violations: u64,
+    /// Faults on pages that were already mapped with the needed protections by the time the fault
+    /// was handled, e.g. because another thread faulted the page in first.
+    pub spurious: u64,
+}
+
+impl FaultStats {
+    fn record(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> FaultStatsSnapshot {
+        FaultStatsSnapshot {
+            minor: self.minor.load(Ordering::Relaxed),
+            cow: self.cow.load(Ordering::Relaxed),
+            zero_fill: self.zero_fill.load(Ordering::Relaxed),
+            violations: self.violations.load(Ordering::Relaxed),
+            spurious: self.spurious.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The result of probing an access with [VirtContext::probe].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeResult {
+    /// The access would succeed without faulting.
+    Ok,
+    /// The access would fault, but the fault would be resolved by mapping a page, without
+    /// involving the faulting thread.
+    WouldFaultMinor,
+    /// The access would fault, and the fault would be reported to the faulting thread, e.g.
+    /// because nothing is mapped there, the slot doesn't allow the access, the page must be
+    /// supplied by a user pager, or a copy-on-write copy would exceed the context's limit.
+    Violation,
+}
+
+/// The result of trying to evict a page with [VirtContext::evict_page].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictResult {
+    /// The page was evicted, and was not dirty in this context.
+    Clean,
+    /// The page was evicted, and was dirty in this context, so it should be written back.
+    Dirty,
+    /// The page is pinned, and was not evicted.
+    Pinned,
+}
+
+/// Errors for accessing memory through a context, with [VirtContext::read_bytes] and
+/// [VirtContext::write_bytes].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemErr {
+    /// The address is not backed by a page of an object mapped in the context.
+    NotMapped,
+    /// The address is in a slot that is not mapped writable.
+    ReadOnly,
+    /// A page could not be allocated to copy a page shared copy-on-write.
+    OutOfMemory,
 }
 
 static CONTEXT_IDS: IdCounter = IdCounter::new();
 
+// Every registered context, by the value of its ID. Contexts remove themselves when dropped.
+static CONTEXTS: Mutex<BTreeMap<u64, Weak<VirtContext>>> = Mutex::new(BTreeMap::new());
+
+/// Find a registered context by the value of its ID. Returns None if no such context exists, or
+/// if it is being dropped.
+pub fn context_by_id(id: u64) -> Option<Arc<VirtContext>> {
+    CONTEXTS.lock().get(&id)?.upgrade()
+}
+
 struct KernelSlotCounter {
     cur_kernel_slot: usize,
     kernel_slots_nums: Vec<Slot>,
 }
 
-#[derive(Default)]
 struct SlotMgr {
     slots: BTreeMap<Slot, VirtContextSlot>,
     objs: BTreeMap<ObjID, Vec<Slot>>,
+    // The number of pages at the start of each object that are reserved as a null guard.
+    null_guard: usize,
+}
+
+impl Default for SlotMgr {
+    fn default() -> Self {
+        Self {
+            slots: BTreeMap::new(),
+            objs: BTreeMap::new(),
+            null_guard: 1,
+        }
+    }
 }
 
 lazy_static::lazy_static! {
@@ -68,36 +225,81 @@ lazy_static::lazy_static! {
     });
 }
 
+/// The number of slots that cover user memory. The slots of user memory are the ones numbered below
+/// this.
+pub const MAX_SLOTS: usize = VirtAddr::end_user_memory().raw() as usize / MAX_SIZE;
+
 /// A representation of a slot number.
 #[repr(transparent)]
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Ord, Eq)]
 pub struct Slot(usize);
 
 impl Slot {
-    fn start_vaddr(&self) -> VirtAddr {
+    /// The virtual address of the start of the slot.
+    pub fn start_vaddr(&self) -> VirtAddr {
         VirtAddr::new((self.0 * MAX_SIZE) as u64).unwrap()
     }
 
-    fn raw(&self) -> usize {
+    /// The slot number.
+    pub fn raw(&self) -> usize {
         self.0
     }
+
+    /// The range of virtual addresses covered by the slot.
+    pub fn byte_range(&self) -> Range<u64> {
+        let start = self.start_vaddr().raw();
+        start..(start + MAX_SIZE as u64)
+    }
+
+    /// Does the whole slot lie within user memory?
+    fn is_user(&self) -> bool {
+        let start = self.start_vaddr().raw();
+        start >= VirtAddr::start_user_memory().raw()
+            && start
+                .checked_add(MAX_SIZE as u64)
+                .is_some_and(|end| end <= VirtAddr::end_user_memory().raw())
+    }
+}
+
+impl core::fmt::Display for Slot {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let range = self.byte_range();
+        write!(f, "slot {} [{:#x}, {:#x})", self.0, range.start, range.end)
+    }
+}
+
+/// Get the slot that an address falls in, along with the byte offset of the address within the
+/// slot. Returns None for kernel addresses, except for those in kernel object memory.
+pub fn slot_of(addr: VirtAddr) -> Option<(Slot, usize)> {
+    let slot = Slot::try_from(addr).ok()?;
+    Some((slot, addr.raw() as usize % MAX_SIZE))
+}
+
+/// Errors for converting a slot number or an address into a [Slot].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlotError {
+    /// The address is kernel memory outside of kernel object memory, which has no slots.
+    KernelAddress,
+    /// The slot number is too large for the slot to have a valid address.
+    OutOfRange,
 }
 
 impl TryFrom<usize> for Slot {
-    type Error = ();
+    type Error = SlotError;
 
     fn try_from(value: usize) -> Result<Self, Self::Error> {
-        let vaddr = VirtAddr::new((value * MAX_SIZE) as u64).map_err(|_| ())?;
+        let start = value.checked_mul(MAX_SIZE).ok_or(SlotError::OutOfRange)?;
+        let vaddr = VirtAddr::new(start as u64).map_err(|_| SlotError::OutOfRange)?;
         vaddr.try_into()
     }
 }
 
 impl TryFrom<VirtAddr> for Slot {
-    type Error = ();
+    type Error = SlotError;
 
     fn try_from(value: VirtAddr) -> Result<Self, Self::Error> {
         if value.is_kernel() && !value.is_kernel_object_memory() {
-            Err(())
+            Err(SlotError::KernelAddress)
         } else {
             Ok(Self(value.raw() as usize / MAX_SIZE))
         }
@@ -109,6 +311,10 @@ impl SlotMgr {
         self.slots.get(slot)
     }
 
+    fn get_mut(&mut self, slot: &Slot) -> Option<&mut VirtContextSlot> {
+        self.slots.get_mut(slot)
+    }
+
     fn insert(&mut self, slot: Slot, id: ObjID, info: VirtContextSlot) {
         self.slots.insert(slot, info);
         let list = self.objs.entry(id).or_default();
@@ -117,55 +323,200 @@ impl SlotMgr {
 
     fn remove(&mut self, slot: Slot) -> Option<VirtContextSlot> {
         if let Some(info) = self.slots.remove(&slot) {
-            let v = self.objs.get_mut(&info.obj.id()).unwrap();
-            let pos = v.iter().position(|item| *item == slot).unwrap();
-            v.remove(pos);
+            let id = info.obj.id();
+            let v = self.objs.get_mut(&id).unwrap();
+            v.retain(|item| *item != slot);
+            // Only forget the object once none of its slots are left.
+            if v.is_empty() {
+                self.objs.remove(&id);
+            }
             Some(info)
         } else {
             None
         }
     }
 
+    /// Remove every slot in user memory that is not locked, returning their entries.
+    fn remove_user(&mut self) -> Vec<VirtContextSlot> {
+        let first = Slot(VirtAddr::start_user_memory().raw() as usize / MAX_SIZE);
+        let end = Slot(MAX_SLOTS);
+        let user: Vec<_> = self
+            .slots
+            .range(first..end)
+            .filter(|(_, info)| !info.locked)
+            .map(|(slot, _)| *slot)
+            .collect();
+        user.into_iter()
+            .filter_map(|slot| self.remove(slot))
+            .collect()
+    }
+
     fn obj_to_slots(&self, id: ObjID) -> Option<&[Slot]> {
         self.objs.get(&id).map(|x| x.as_slice())
     }
+
+    /// Check that the reverse index from objects to slots agrees with the slots, panicking if not.
+    #[cfg(debug_assertions)]
+    fn validate(&self) {
+        for (slot, info) in &self.slots {
+            let id = info.obj.id();
+            let count = self
+                .objs
+                .get(&id)
+                .map_or(0, |slots| slots.iter().filter(|s| *s == slot).count());
+            assert_eq!(
+                count, 1,
+                "{} maps object {:?}, which lists it {} times",
+                slot, id, count
+            );
+        }
+        for (id, slots) in &self.objs {
+            assert!(!slots.is_empty(), "object {:?} has an empty slot list", id);
+            for slot in slots {
+                let info = self
+                    .slots
+                    .get(slot)
+                    .unwrap_or_else(|| panic!("object {:?} lists unmapped {}", id, slot));
+                assert_eq!(
+                    info.obj.id(),
+                    *id,
+                    "object {:?} lists {}, which maps another object",
+                    id,
+                    slot
+                );
+            }
+        }
+    }
+
+    /// Find the lowest run of `count` unoccupied, contiguous slots in user memory.
+    fn find_free_run(&self, count: usize) -> Option<Slot> {
+        if count == 0 {
+            return None;
+        }
+        let first = Slot::try_from(VirtAddr::start_user_memory()).ok()?;
+        let end = MAX_SLOTS;
+        let mut run_start = first.raw();
+        for slot in self.slots.range(first..Slot(end)).map(|(slot, _)| slot) {
+            if slot.raw() - run_start >= count {
+                break;
+            }
+            run_start = slot.raw() + 1;
+        }
+        if run_start.checked_add(count)? > end {
+            return None;
+        }
+        Slot::try_from(run_start).ok()
+    }
+
+    /// Pick a free slot in user memory using `entropy`. The free slots are numbered in order, and
+    /// the one whose number is `entropy` modulo the number of free slots is chosen.
+    fn find_random_free(&self, entropy: u64) -> Option<Slot> {
+        let first = Slot::try_from(VirtAddr::start_user_memory()).ok()?.raw();
+        let end = MAX_SLOTS;
+        let occupied = self.slots.range(Slot(first)..Slot(end));
+        let free = (end - first).checked_sub(occupied.clone().count())?;
+        if free == 0 {
+            return None;
+        }
+        let mut target = first + (entropy % free as u64) as usize;
+        // Every occupied slot at or below the target shifts it up by one.
+        for (slot, _) in occupied {
+            if slot.raw() > target {
+                break;
+            }
+            target += 1;
+        }
+        Slot::try_from(target).ok()
+    }
 }
 
 struct ObjectPageProvider<'a> {
     page: &'a Page,
+    len: usize,
 }
 
 impl<'a> PhysAddrProvider for ObjectPageProvider<'a> {
     fn peek(&mut self) -> (crate::arch::address::PhysAddr, usize) {
-        (self.page.physical_address(), PageNumber::PAGE_SIZE)
+        (self.page.physical_address(), self.len)
     }
 
     fn consume(&mut self, _len: usize) {}
 }
 
+/// Provides a sequence of object pages, one page at a time.
+struct ObjectPagesProvider<'a> {
+    pages: &'a [(PageRef, bool)],
+}
+
+impl<'a> PhysAddrProvider for ObjectPagesProvider<'a> {
+    fn peek(&mut self) -> (crate::arch::address::PhysAddr, usize) {
+        (self.pages[0].0.physical_address(), PageNumber::PAGE_SIZE)
+    }
+
+    fn consume(&mut self, len: usize) {
+        let nr = len / PageNumber::PAGE_SIZE;
+        self.pages = &self.pages[nr.min(self.pages.len())..];
+    }
+}
+
+static ZERO_FRAME: Once<FrameRef> = Once::new();
+
+/// A frame of zeros, mapped read-only in place of holes in objects that are read but not yet
+/// written. It is never freed.
+fn zero_frame() -> FrameRef {
+    *ZERO_FRAME.call_once(|| alloc_frame(PhysicalFrameFlags::ZEROED))
+}
+
+/// The number of pages (including the faulting page) that a page fault will try to map at once, by
+/// default. Only pages already present in the object are mapped ahead of the faulting page. See
+/// [VirtContext::set_prefault_window].
+const FAULT_AHEAD_PAGES: usize = 8;
+
 impl VirtContext {
-    fn __new(is_kernel: bool) -> Self {
+    fn __new(is_kernel: bool, wx_enforce: bool) -> Self {
         Self {
             slots: Mutex::new(SlotMgr::default()),
             is_kernel,
             id: CONTEXT_IDS.next(),
             secctx: Mutex::new(BTreeMap::new()),
             target_cache: Spinlock::new(BTreeMap::new()),
+            resident: AtomicUsize::new(0),
+            fault_stats: FaultStats::default(),
+            fault_observer: Spinlock::new(None),
+            active_cpus: Arc::new(AtomicCpuSet::default()),
+            page_sampling: AtomicBool::new(false),
+            wx_enforce,
+            cow_pages: AtomicUsize::new(0),
+            cow_limit: AtomicUsize::new(usize::MAX),
         }
     }
 
     /// Construct a new context for the kernel.
     pub fn new_kernel() -> Self {
-        let this = Self::__new(true);
+        let this = Self::__new(true, false);
         this.register_sctx(KERNEL_SCTX, ArchContext::new_kernel());
         this
     }
 
     /// Construct a new context for userspace.
-    pub fn new() -> Self {
-        let this = Self::__new(false);
+    pub fn new() -> Arc<Self> {
+        Self::new_with_wx_enforce(false)
+    }
+
+    /// Construct a new context for userspace, optionally enforcing W^X. If wx_enforce is set, no
+    /// slot of the context may be mapped both writable and executable, and attempts to do so fail
+    /// with [InsertError::WxViolation].
+    ///
+    /// The context is registered so that it can be found by [context_by_id]. The registry only
+    /// holds a weak reference, so it does not keep the context alive.
+    pub fn new_with_wx_enforce(wx_enforce: bool) -> Arc<Self> {
+        let this = Self::__new(false, wx_enforce);
         // TODO: remove this once we have full support for user security contexts
         this.register_sctx(KERNEL_SCTX, ArchContext::new());
+        let this = Arc::new(this);
+        CONTEXTS
+            .lock()
+            .insert(this.id().value(), Arc::downgrade(&this));
         this
     }
 
@@ -200,6 +551,7 @@ impl VirtContext {
             VirtAddr::start_kernel_memory(),
             usize::MAX,
         ));
+        // Runs are only coalesced if their settings match, so each keeps its own cache type.
         for map in rm.coalesce() {
             let cursor = MappingCursor::new(map.vaddr(), map.len());
             let mut phys = ContiguousProvider::new(map.paddr(), map.len());
@@ -243,578 +595,4961 @@ impl VirtContext {
     pub fn lookup_slot(&self, slot: usize) -> Option<VirtContextSlot> {
         self.slots.lock().get(&Slot::try_from(slot).ok()?).cloned()
     }
-}
 
-impl UserContext for VirtContext {
-    type MappingInfo = Slot;
+    /// Get a snapshot of all the occupied slots in this context. The slots lock is not held while
+    /// the caller iterates.
+    pub fn iter_slots(&self) -> impl Iterator<Item = (Slot, ObjectContextInfo)> {
+        let slots = self.slots.lock();
+        let snapshot: Vec<_> = slots
+            .slots
+            .iter()
+            .map(|(slot, info)| (*slot, info.into()))
+            .collect();
+        snapshot.into_iter()
+    }
 
-    fn switch_to(&self, sctx: ObjID) {
-        let tc = self.target_cache.lock();
-        let target = tc
-            .get(&sctx)
-            .expect("tried to switch to a non-registered sctx");
-        // Safety: we get the target from an ArchContext that we track.
-        unsafe {
-            ArchContext::switch_to_target(target);
-        }
+    /// The number of slots in user memory that have an object mapped in them.
+    pub fn used_slots(&self) -> usize {
+        self.slots.lock().slots.range(..Slot(MAX_SLOTS)).count()
     }
 
-    fn insert_object(
-        self: &Arc<Self>,
-        slot: Slot,
-        object_info: &ObjectContextInfo,
-    ) -> Result<(), InsertError> {
-        let new_slot_info = VirtContextSlot {
-            obj: object_info.object().clone(),
-            slot,
-            prot: object_info.prot(),
-            cache: object_info.cache(),
-        };
-        object_info.object().add_context(self);
-        let mut slots = self.slots.lock();
-        if let Some(info) = slots.get(&slot) {
-            if info != &new_slot_info {
-                return Err(InsertError::Occupied);
-            }
-            return Ok(());
-        }
-        slots.insert(slot, object_info.object().id(), new_slot_info);
-        Ok(())
+    /// Find the lowest unoccupied slot in user memory.
+    pub fn find_free_slot(&self) -> Option<Slot> {
+        self.find_free_slots(1)
     }
 
-    fn lookup_object(&self, info: Self::MappingInfo) -> Option<ObjectContextInfo> {
-        if info.start_vaddr().is_kernel_object_memory() && !self.is_kernel {
-            kernel_context().lookup_object(info)
-        } else {
-            let slots = self.slots.lock();
-            slots.get(&info).map(|info| info.into())
-        }
+    /// Find the lowest run of `count` contiguous unoccupied slots in user memory, returning the
+    /// first slot of the run.
+    pub fn find_free_slots(&self, count: usize) -> Option<Slot> {
+        self.slots.lock().find_free_run(count)
     }
 
-    fn invalidate_object(
+    /// Find an unoccupied slot in user memory, chosen among all the free slots using the provided
+    /// entropy. Callers should supply a random value to randomize the placement of objects.
+    pub fn find_random_free_slot(&self, entropy: u64) -> Option<Slot> {
+        self.slots.lock().find_random_free(entropy)
+    }
+
+    /// Check the internal consistency of the context's slot bookkeeping, panicking if it is
+    /// broken. Only available in debug builds.
+    #[cfg(debug_assertions)]
+    pub fn validate(&self) {
+        self.slots.lock().validate();
+    }
+
+    /// Start a batch of page table updates. Operations that accept a batch queue their TLB
+    /// invalidations in it instead of performing them right away, so that a bulk operation can
+    /// finish with a single shootdown by calling [InvalidationBatch::flush]. The batch is flushed
+    /// when dropped.
+    pub fn begin_batch(&self) -> InvalidationBatch {
+        InvalidationBatch::new()
+    }
+
+    /// Unmap a range of pages within a slot, leaving the slot's mapping in place so that the pages
+    /// may be faulted back in later. The range is clamped to the size of the slot. If a batch is
+    /// provided, the TLB invalidations are queued in it.
+    pub fn unmap_range(
         &self,
-        obj: ObjID,
-        range: &core::ops::Range<PageNumber>,
-        mode: obj::InvalidateMode,
+        slot: Slot,
+        range: Range<PageNumber>,
+        batch: Option<&mut InvalidationBatch>,
     ) {
-        let start = range.start.as_byte_offset();
-        let len = range.end.as_byte_offset() - start;
+        let start = range.start.as_byte_offset().min(MAX_SIZE);
+        let end = range.end.as_byte_offset().min(MAX_SIZE);
+        if start >= end {
+            return;
+        }
+        let mut slots = self.slots.lock();
+        let Some(info) = slots.get_mut(&slot) else {
+            return;
+        };
+        let arches = self.secctx.lock();
+        self.unmap_locked(info, &arches, start, end, batch);
+    }
+
+    /// Invalidate the TLB entries for a range of pages within a slot on all CPUs, without changing
+    /// the slot's mappings. This keeps other CPUs coherent after the contents of the pages are
+    /// changed through another mapping, such as the physical memory map. Pages that are not
+    /// mapped are skipped. The range is clamped to the size of the slot.
+    pub fn flush_slot(&self, slot: Slot, range: Range<PageNumber>) {
+        let start = range.start.as_byte_offset().min(MAX_SIZE);
+        let end = range.end.as_byte_offset().min(MAX_SIZE);
+        if start >= end {
+            return;
+        }
         let slots = self.slots.lock();
+        let Some(info) = slots.get(&slot) else {
+            return;
+        };
+        let cursor = info.mapping_cursor(start, end - start);
         let arches = self.secctx.lock();
         for arch in arches.values() {
-            if let Some(maps) = slots.obj_to_slots(obj) {
-                for map in maps {
-                    let info = slots
-                        .get(map)
-                        .expect("invalid slot info for a mapped object");
-                    match mode {
-                        obj::InvalidateMode::Full => {
-                            arch.unmap(info.mapping_cursor(start, len));
-                        }
-                        obj::InvalidateMode::WriteProtect => {
-                            arch.change(
-                                info.mapping_cursor(start, len),
-                                &info.mapping_settings(true, self.is_kernel),
-                            );
-                        }
-                    }
-                }
-            }
+            arch.flush(cursor);
         }
     }
 
-    fn remove_object(&self, info: Self::MappingInfo) {
-        let mut slots = self.slots.lock();
-        if let Some(slot) = slots.remove(info) {
-            let arches = self.secctx.lock();
-            for arch in arches.values() {
-                arch.unmap(slot.mapping_cursor(0, MAX_SIZE));
+    fn unmap_locked(
+        &self,
+        info: &mut VirtContextSlot,
+        arches: &BTreeMap<ObjID, ArchContext>,
+        start: usize,
+        end: usize,
+        mut batch: Option<&mut InvalidationBatch>,
+    ) {
+        let cursor = info.mapping_cursor(start, end - start);
+        for arch in arches.values() {
+            match batch.as_deref_mut() {
+                Some(batch) => arch.unmap_batched(cursor, batch),
+                None => arch.unmap(cursor),
             }
-            slot.obj.remove_context(self.id.value());
         }
+        // Release exactly the pages in the range that faults in this slot allocated.
+        let first = info.object_page(PageNumber::from(start / PageNumber::PAGE_SIZE));
+        let last = info.object_page(PageNumber::from(end.div_ceil(PageNumber::PAGE_SIZE)));
+        let mut released_cow = info.cow_committed.split_off(&first);
+        info.cow_committed
+            .append(&mut released_cow.split_off(&last));
+        self.cow_pages
+            .fetch_sub(released_cow.len(), Ordering::SeqCst);
+        let mut released = info.committed.split_off(&first);
+        info.committed.append(&mut released.split_off(&last));
+        self.resident.fetch_sub(released.len(), Ordering::SeqCst);
     }
-}
-
-#[derive(Clone, Eq, PartialEq, Ord, PartialOrd)]
-pub struct VirtContextSlot {
-    obj: ObjectRef,
-    slot: Slot,
-    prot: Protections,
-    cache: CacheType,
-}
 
-impl From<&VirtContextSlot> for ObjectContextInfo {
-    fn from(info: &VirtContextSlot) -> Self {
-        ObjectContextInfo::new(info.obj.clone(), info.prot, info.cache)
+    /// Release the accounting for the pages allocated by faults in a slot that is being removed.
+    fn release_committed(&self, info: &VirtContextSlot) {
+        self.resident
+            .fetch_sub(info.committed.len(), Ordering::SeqCst);
+        self.cow_pages
+            .fetch_sub(info.cow_committed.len(), Ordering::SeqCst);
     }
-}
 
-impl VirtContextSlot {
-    fn mapping_cursor(&self, start: usize, len: usize) -> MappingCursor {
-        MappingCursor::new(self.slot.start_vaddr().offset(start).unwrap(), len)
+    /// Set a soft limit on the number of pages allocated by breaking copy-on-write sharing in this
+    /// context that may be mapped at once, or remove the limit. Once the limit is reached, a write
+    /// fault that would break sharing sends an [UpcallInfo::MemoryPressure] upcall to the faulting
+    /// thread instead of allocating a page.
+    pub fn set_cow_limit(&self, limit: Option<usize>) {
+        self.cow_limit
+            .store(limit.unwrap_or(usize::MAX), Ordering::SeqCst);
     }
 
-    pub fn mapping_settings(&self, wp: bool, is_kern_obj: bool) -> MappingSettings {
-        let mut prot = self.prot;
-        if wp {
-            prot.remove(Protections::WRITE);
-        }
-        MappingSettings::new(
-            prot,
-            self.cache,
-            if is_kern_obj {
-                MappingFlags::GLOBAL
-            } else {
-                MappingFlags::USER
-            },
-        )
+    /// The number of pages allocated by breaking copy-on-write sharing in this context that are
+    /// still mapped here.
+    pub fn cow_pages(&self) -> usize {
+        self.cow_pages.load(Ordering::SeqCst)
     }
 
-    pub fn object(&self) -> &ObjectRef {
-        &self.obj
+    /// The number of pages allocated by faults in this context that are still mapped here.
+    pub fn resident_pages(&self) -> usize {
+        self.resident.load(Ordering::SeqCst)
     }
 
-    fn phys_provider<'a>(&self, page: &'a Page) -> ObjectPageProvider<'a> {
-        ObjectPageProvider { page }
+    /// The number of bytes of memory occupied by the page tables of this context, across all of
+    /// its security contexts. Together with [Self::resident_pages], this is the memory cost of the
+    /// context.
+    pub fn page_table_bytes(&self) -> usize {
+        let arches = self.secctx.lock();
+        arches
+            .values()
+            .map(|arch| arch.table_frames())
+            .sum::<usize>()
+            * PageNumber::PAGE_SIZE
     }
-}
 
-impl Drop for VirtContext {
-    fn drop(&mut self) {
-        let id = self.id().value();
-        // cleanup and object's context info
-        for info in self.slots.get_mut().slots.values() {
-            info.obj.remove_context(id)
-        }
+    /// The processors that currently have this context loaded. A processor stays in the set
+    /// until it loads a different context, even if it is running a kernel thread.
+    pub fn active_cpus(&self) -> CpuSet {
+        self.active_cpus.load()
     }
-}
-
-pub const HEAP_MAX_LEN: usize = 0x0000001000000000 / 16; //4GB
-
-struct GlobalPageAlloc {
-    alloc: linked_list_allocator::Heap,
-    end: VirtAddr,
-}
 
-impl GlobalPageAlloc {
-    fn extend(&mut self, len: usize, mapper: &VirtContext) {
-        let cursor = MappingCursor::new(self.end, len);
-        let mut phys = ZeroPageProvider::default();
-        let settings = MappingSettings::new(
-            Protections::READ | Protections::WRITE,
-            CacheType::WriteBack,
-            MappingFlags::GLOBAL,
-        );
-        mapper.with_arch(KERNEL_SCTX, |arch| {
-            arch.map(cursor, &mut phys, &settings);
-        });
-        self.end = self.end.offset(len).unwrap();
-        // Safety: the extension is backed by memory that is directly after the previous call to
-        // extend.
-        unsafe {
-            self.alloc.extend(len);
-        }
+    /// The number of slots in this context that map the given object.
+    pub fn slot_count_for(&self, obj: ObjID) -> usize {
+        self.slots
+            .lock()
+            .obj_to_slots(obj)
+            .map_or(0, |slots| slots.len())
     }
 
-    fn init(&mut self, mapper: &VirtContext) {
-        let len = 2 * 1024 * 1024;
-        let cursor = MappingCursor::new(self.end, len);
-        let mut phys = ZeroPageProvider::default();
-        let settings = MappingSettings::new(
-            Protections::READ | Protections::WRITE,
-            CacheType::WriteBack,
-            MappingFlags::GLOBAL,
-        );
-        mapper.with_arch(KERNEL_SCTX, |arch| {
-            arch.map(cursor, &mut phys, &settings);
-        });
-        self.end = self.end.offset(len).unwrap();
-        // Safety: the initial is backed by memory.
-        unsafe {
-            self.alloc.init(VirtAddr::HEAP_START.as_mut_ptr(), len);
-        }
+    /// The slots in this context that map the given object. This is a snapshot, and the slots may
+    /// change once this function returns.
+    pub fn slots_for(&self, obj: ObjID) -> Vec<Slot> {
+        self.slots
+            .lock()
+            .obj_to_slots(obj)
+            .map_or_else(Vec::new, |slots| slots.to_vec())
     }
-}
-
-// Safety: the internal heap contains raw pointers, which are not Send. However, the heap is
-// globally mapped and static for the lifetime of the kernel.
-unsafe impl Send for GlobalPageAlloc {}
 
-static GLOBAL_PAGE_ALLOC: Spinlock<GlobalPageAlloc> = Spinlock::new(GlobalPageAlloc {
-    alloc: linked_list_allocator::Heap::empty(),
-    end: VirtAddr::HEAP_START,
-});
+    /// Get the virtual address at which a byte offset within an object is mapped in this context.
+    /// If the object is mapped in more than one slot that covers the offset, the lowest address is
+    /// returned. See [Self::address_of_all].
+    pub fn address_of(&self, obj: ObjID, offset: usize) -> Option<VirtAddr> {
+        self.address_of_all(obj, offset).into_iter().min()
+    }
 
-impl KernelMemoryContext for VirtContext {
-    fn allocate_chunk(&self, layout: core::alloc::Layout) -> NonNull<u8> {
-        let mut glb = GLOBAL_PAGE_ALLOC.lock();
-        let res = glb.alloc.allocate_first_fit(layout);
-        match res {
-            Err(_) => {
-                let size = layout
-                    .pad_to_align()
-                    .size()
-                    .next_multiple_of(Table::level_to_page_size(Table::last_level()))
-                    * 2;
-                glb.extend(size, self);
-                glb.alloc.allocate_first_fit(layout).unwrap()
-            }
-            Ok(x) => x,
-        }
+    /// Get every virtual address at which a byte offset within an object is mapped in this
+    /// context, in no particular order. This is a snapshot, like [Self::slots_for].
+    pub fn address_of_all(&self, obj: ObjID, offset: usize) -> Vec<VirtAddr> {
+        let slots = self.slots.lock();
+        let Some(maps) = slots.obj_to_slots(obj) else {
+            return Vec::new();
+        };
+        maps.iter()
+            .filter_map(|slot| {
+                let info = slots.get(slot)?;
+                let slot_offset = offset.checked_sub(info.offset.as_byte_offset())?;
+                if slot_offset >= MAX_SIZE {
+                    return None;
+                }
+                slot.start_vaddr().offset(slot_offset).ok()
+            })
+            .collect()
     }
 
-    unsafe fn deallocate_chunk(&self, layout: core::alloc::Layout, ptr: NonNull<u8>) {
-        let mut glb = GLOBAL_PAGE_ALLOC.lock();
-        glb.alloc.deallocate(ptr, layout);
+    /// Get a snapshot of the page fault counters for this context.
+    pub fn fault_stats(&self) -> FaultStatsSnapshot {
+        self.fault_stats.snapshot()
     }
 
-    fn init_allocator(&self) {
-        let mut glb = GLOBAL_PAGE_ALLOC.lock();
-        glb.init(self);
+    /// Populate a range of pages within a slot, creating any pages missing from the object and
+    /// mapping them all in one pass. The range is clamped to exclude the null page and any guard
+    /// pages, and to lie within the slot. If memory runs out, only the pages before the first that
+    /// could not be created are populated.
+    pub fn populate_range(&self, slot: Slot, range: Range<PageNumber>, cause: MemoryAccessKind) {
+        let mut slots = self.slots.lock();
+        let Some(info) = slots.get_mut(&slot) else {
+            return;
+        };
+        if info.user_paged {
+            // Pages in a user-paged slot are only ever supplied by the user's handler.
+            return;
+        }
+        let start = info
+            .object_page(range.start)
+            .max(info.first_mappable_page());
+        let end = info.object_page(range.end).min(info.end_page());
+        if start >= end {
+            return;
+        }
+        let is_write = cause == MemoryAccessKind::Write;
+        let mut created = Vec::new();
+        let mut obj_page_tree = info.obj.lock_page_tree();
+        let pages: Vec<_> = (0..(end - start))
+            .map_while(|i| {
+                let pn = start.offset(i);
+                let (page, shared, filled) =
+                    obj_page_tree.try_get_or_add_page(pn, is_write, |pn| info.fill_page(pn))?;
+                if filled {
+                    created.push(pn);
+                }
+                Some((page, shared))
+            })
+            .collect();
+        let committed = created
+            .iter()
+            .filter(|pn| info.committed.insert(**pn))
+            .count();
+        self.resident.fetch_add(committed, Ordering::SeqCst);
+        if !created.is_empty() && info.obj.is_zero_mapped() {
+            // The holes we filled may still map the zero page elsewhere. Drop those mappings, and
+            // then try again, which will find every page present.
+            let obj = info.obj.clone();
+            drop(obj_page_tree);
+            drop(slots);
+            obj.invalidate_zero_mapped(start..end);
+            self.populate_range(slot, range, cause);
+            return;
+        }
+        self.with_arch(KERNEL_SCTX, |arch| {
+            info.map_pages(arch, start, &pages, self.is_kernel);
+        });
     }
 
-    fn prep_smp(&self) {
+    /// Get the physical address of the start of the physically contiguous run of pages backing a
+    /// slot, if the object was inserted with [ObjectContextInfo::with_contiguous]. The run starts
+    /// at the first page of the slot that may be mapped, and stays in place for as long as the
+    /// slot is mapped.
+    pub fn dma_phys_base(&self, slot: Slot) -> Option<PhysAddr> {
+        let slots = self.slots.lock();
+        let info = slots.get(&slot)?;
+        info.dma_pages.first().map(|page| page.physical_address())
+    }
+
+    /// Allocate the page tables needed to map a range of pages of a slot ahead of time, without
+    /// mapping any pages, so that faults in the range only need to allocate the pages themselves.
+    /// The range is clamped to the size of the slot. The tables are freed when the object is
+    /// removed from the slot, or when the range is otherwise unmapped. Fails with
+    /// [InsertError::OutOfMemory] if a table could not be allocated, in which case only some of
+    /// the tables may have been allocated.
+    pub fn reserve_page_tables(
+        &self,
+        slot: Slot,
+        range: Range<PageNumber>,
+    ) -> Result<(), InsertError> {
+        let slots = self.slots.lock();
+        let info = slots.get(&slot).ok_or(InsertError::NotMapped)?;
+        let start = range.start.as_byte_offset().min(MAX_SIZE);
+        let end = range.end.as_byte_offset().min(MAX_SIZE);
+        if start >= end {
+            return Ok(());
+        }
+        let arches = self.secctx.lock();
+        for arch in arches.values() {
+            if !arch.reserve(info.mapping_cursor(start, end - start)) {
+                return Err(InsertError::OutOfMemory);
+            }
+        }
+        Ok(())
+    }
+
+    /// Limit the number of pages of a slot that faults may leave mapped in this context at once.
+    /// Once the limit is reached, faulting in another page unmaps the page that was faulted in the
+    /// longest ago, which will fault again on its next access. The pages stay present in the
+    /// object. If the slot already has more pages faulted in than the new limit, the oldest are
+    /// unmapped right away. A limit of 0 removes the limit. Pages mapped other than by faults
+    /// (e.g. by prefaulting) are not counted.
+    pub fn set_resident_cap(&self, slot: Slot, pages: usize) -> Result<(), InsertError> {
+        let mut slots = self.slots.lock();
+        let info = slots.get_mut(&slot).ok_or(InsertError::NotMapped)?;
+        if pages == 0 {
+            info.resident_cap = None;
+            info.faulted.clear();
+            info.faulted_set.clear();
+            return Ok(());
+        }
+        info.resident_cap = Some(pages);
+        let over = info.over_cap(pages);
+        let arches = self.secctx.lock();
+        for page in over {
+            let start = (page - info.offset) * PageNumber::PAGE_SIZE;
+            self.unmap_locked(info, &arches, start, start + PageNumber::PAGE_SIZE, None);
+        }
+        Ok(())
+    }
+
+    /// Set the number of pages following a faulting page of a slot that a fault also maps, if they
+    /// are already present in the object. A large window suits objects that are accessed
+    /// sequentially, and a window of 0 disables mapping ahead for objects that are accessed
+    /// randomly.
+    pub fn set_prefault_window(&self, slot: Slot, pages: usize) -> Result<(), InsertError> {
+        let mut slots = self.slots.lock();
+        let info = slots.get_mut(&slot).ok_or(InsertError::NotMapped)?;
+        info.fault_ahead = pages;
+        Ok(())
+    }
+
+    /// Lock a slot against removal, for mappings that must stay in place while other threads run,
+    /// such as a thread's control block. [UserContext::remove_object] and
+    /// [Self::clear_user_mappings] skip a locked slot, and moving or replacing its mapping fails
+    /// with [InsertError::Locked]. The slot can still be faulted in and write-protected as usual.
+    pub fn lock_slot(&self, slot: Slot) -> Result<(), InsertError> {
+        let mut slots = self.slots.lock();
+        let info = slots.get_mut(&slot).ok_or(InsertError::NotMapped)?;
+        info.locked = true;
+        Ok(())
+    }
+
+    /// Undo [Self::lock_slot], allowing the slot to be removed again.
+    pub fn unlock_slot(&self, slot: Slot) -> Result<(), InsertError> {
+        let mut slots = self.slots.lock();
+        let info = slots.get_mut(&slot).ok_or(InsertError::NotMapped)?;
+        info.locked = false;
+        Ok(())
+    }
+
+    /// Mark a slot as user-paged, or not. A fault on a page of a user-paged slot that is not
+    /// present in the object sends an [ObjectMemoryError::UserPagedRegion] fault upcall, instead of
+    /// allocating a page. The handler can then provide the page with [Self::supply_page].
+    pub fn set_user_paged(&self, slot: Slot, user_paged: bool) -> Result<(), InsertError> {
+        let mut slots = self.slots.lock();
+        let info = slots.get_mut(&slot).ok_or(InsertError::NotMapped)?;
+        info.user_paged = user_paged;
+        Ok(())
+    }
+
+    /// Supply a page of the object mapped in a slot, copying its contents from the page at
+    /// physical address src, and map it. If the page is already present in the object, it is left
+    /// as is. Fails if the slot is not mapped, or if the page cannot be mapped in the slot, and
+    /// with [InsertError::OutOfMemory] if no page could be allocated for the copy.
+    pub fn supply_page(
+        &self,
+        slot: Slot,
+        page: PageNumber,
+        src: PhysAddr,
+    ) -> Result<(), InsertError> {
+        let mut slots = self.slots.lock();
+        let info = slots.get_mut(&slot).ok_or(InsertError::NotMapped)?;
+        let page = info.object_page(page);
+        if page < info.first_mappable_page() || page >= info.end_page() {
+            return Err(InsertError::NotMapped);
+        }
+        let mut tree = info.obj.lock_page_tree();
+        let (new, shared, filled) = tree
+            .try_get_or_add_page(page, false, |_| Page::new_copy_of(src))
+            .ok_or(InsertError::OutOfMemory)?;
+        if filled {
+            if info.committed.insert(page) {
+                self.resident.fetch_add(1, Ordering::SeqCst);
+            }
+            if info.obj.is_zero_mapped() {
+                // Let the zero page mappings be dropped, and the page will be faulted in.
+                let obj = info.obj.clone();
+                drop(tree);
+                drop(slots);
+                obj.invalidate_zero_mapped(page..page.next());
+                return Ok(());
+            }
+        }
+        let pages = [(new, shared)];
         self.with_arch(KERNEL_SCTX, |arch| {
-            arch.unmap(MappingCursor::new(
-                VirtAddr::start_user_memory(),
-                VirtAddr::end_user_memory() - VirtAddr::start_user_memory(),
-            ))
+            info.map_pages(arch, page, &pages, self.is_kernel);
+        });
+        Ok(())
+    }
+
+    /// Remove every object mapped in user memory from this context, and unmap all of user memory
+    /// in one go. Kernel mappings, and slots locked with [Self::lock_slot], are left in place.
+    pub fn clear_user_mappings(&self) {
+        let mut slots = self.slots.lock();
+        let removed = slots.remove_user();
+        let locked = slots.slots.keys().any(|slot| slot.is_user());
+        let arches = self.secctx.lock();
+        for arch in arches.values() {
+            if locked {
+                for info in &removed {
+                    arch.unmap(info.mapping_cursor(0, MAX_SIZE));
+                }
+            } else {
+                arch.unmap(MappingCursor::new(
+                    VirtAddr::start_user_memory(),
+                    VirtAddr::end_user_memory() - VirtAddr::start_user_memory(),
+                ));
+            }
+        }
+        drop(arches);
+        for info in &removed {
+            self.release_committed(info);
+        }
+        // Objects that are still mapped in a kernel slot stay registered with this context.
+        let mut objs: Vec<_> = removed
+            .into_iter()
+            .map(|info| info.obj)
+            .filter(|obj| slots.obj_to_slots(obj.id()).map_or(true, |s| s.is_empty()))
+            .collect();
+        drop(slots);
+        objs.sort_by_key(|obj| obj.id());
+        objs.dedup_by_key(|obj| obj.id());
+        for obj in objs {
+            obj.remove_context(self.id.value());
+        }
+    }
+
+    /// Reserve the first pages of every object mapped in this context as a null guard, so that
+    /// accesses to them are reported as [ObjectMemoryError::NullPageAccess]. This catches accesses
+    /// at small offsets from a null base pointer, as well as null pointers themselves. Guard pages
+    /// requested for a mapping come after the null guard. The default is 1, and the first page is
+    /// always reserved. Pages that become part of the guard are unmapped.
+    pub fn set_null_guard_pages(&self, pages: usize) {
+        let pages = pages.max(1);
+        let mut slots = self.slots.lock();
+        slots.null_guard = pages;
+        let arches = self.secctx.lock();
+        for info in slots.slots.values_mut() {
+            let old = info.first_mappable_page();
+            info.null_guard = pages;
+            let new = info.first_mappable_page();
+            if new > old {
+                let start = ((old - info.offset) * PageNumber::PAGE_SIZE).min(MAX_SIZE);
+                let end = ((new - info.offset) * PageNumber::PAGE_SIZE).min(MAX_SIZE);
+                if start < end {
+                    self.unmap_locked(info, &arches, start, end, None);
+                }
+            }
+        }
+    }
+
+    /// The number of pages at the start of each object that are reserved as a null guard.
+    pub fn null_guard_pages(&self) -> usize {
+        self.slots.lock().null_guard
+    }
+
+    /// Remove every slot that maps an object from this context, however many times each mapping
+    /// was inserted. Used to tear down an object's mappings, see [drain_object].
+    pub fn remove_all_mappings(&self, obj: ObjID) {
+        let mut slots = self.slots.lock();
+        let Some(targets) = slots.obj_to_slots(obj).map(<[Slot]>::to_vec) else {
+            return;
+        };
+        let removed = self.remove_slots_locked(&mut slots, targets);
+        drop(slots);
+        for slot in removed {
+            slot.obj.remove_context(self.id.value());
+        }
+    }
+
+    /// Remove and unmap slots. The removed slots are returned, still registered with their
+    /// objects, so that they can be unregistered after the slots lock is released.
+    fn remove_slots_locked(
+        &self,
+        slots: &mut SlotMgr,
+        targets: impl IntoIterator<Item = Slot>,
+    ) -> Vec<VirtContextSlot> {
+        let mut removed = Vec::new();
+        for target in targets {
+            if let Some(slot) = slots.remove(target) {
+                let arches = self.secctx.lock();
+                for arch in arches.values() {
+                    arch.unmap(slot.mapping_cursor(0, MAX_SIZE));
+                }
+                self.release_committed(&slot);
+                removed.push(slot);
+            }
+        }
+        removed
+    }
+
+    /// Check that an object may be inserted into a slot with the given settings.
+    fn check_insert(&self, slot: Slot, info: &ObjectContextInfo) -> Result<(), InsertError> {
+        if !slot.is_user() {
+            return Err(InsertError::InvalidRange);
+        }
+        if info.mapping_flags().contains(MappingFlags::GLOBAL) && !info.allow_global() {
+            return Err(InsertError::InvalidFlags);
+        }
+        // Checked here, rather than on fault, so that a misconfigured mapping is caught before any
+        // memory is mapped with the wrong caching.
+        if !ArchContext::supports_cache_type(info.cache()) {
+            return Err(InsertError::UnsupportedCache);
+        }
+        self.check_prot(info.prot())
+    }
+
+    /// Check the protections for a mapping against this context's W^X policy, and against what
+    /// the architecture is able to map.
+    fn check_prot(&self, prot: Protections) -> Result<(), InsertError> {
+        if prot.contains(Protections::EXEC)
+            && !prot.contains(Protections::READ)
+            && !ArchContext::supports_execute_only()
+        {
+            return Err(InsertError::UnsupportedProt);
+        }
+        if self.wx_enforce && prot.contains(Protections::WRITE | Protections::EXEC) {
+            return Err(InsertError::WxViolation);
+        }
+        Ok(())
+    }
+
+    /// Change the protections of an already-mapped slot without unmapping its pages. If a batch is
+    /// provided, the TLB invalidations are queued in it.
+    pub fn protect_slot(
+        &self,
+        slot: Slot,
+        new_prot: Protections,
+        mut batch: Option<&mut InvalidationBatch>,
+    ) -> Result<(), InsertError> {
+        self.check_prot(new_prot)?;
+        let mut slots = self.slots.lock();
+        let info = slots.get_mut(&slot).ok_or(InsertError::NotMapped)?;
+        info.prot = new_prot;
+        // Present pages are left write-protected, since some of them may be shared copy-on-write.
+        // A subsequent write fault will map the page with the new protections.
+        let settings = info.mapping_settings(true, self.is_kernel);
+        let arches = self.secctx.lock();
+        let cursor = info.mapping_cursor(0, MAX_SIZE);
+        for arch in arches.values() {
+            match batch.as_deref_mut() {
+                Some(batch) => arch.change_batched(cursor, &settings, batch),
+                None => arch.change(cursor, &settings),
+            }
+        }
+        Ok(())
+    }
+
+    /// Run f with a slot temporarily writable, for trusted kernel code that needs to write to a
+    /// slot that userspace may only read (e.g. to set up an upcall frame). Writes to the slot
+    /// fault in writable mappings as usual, breaking any copy-on-write sharing. Once f returns (or
+    /// panics), the slot's pages are write-protected again, and the TLB is flushed. Calls may
+    /// be nested, or overlap on other threads, in which case the slot stays writable until the
+    /// last of them returns. If nothing is mapped in the slot, f is run anyway. Fails with
+    /// [InsertError::WxViolation], without running f, if the slot is executable and the context
+    /// enforces W^X.
+    pub fn with_writable<R>(&self, slot: Slot, f: impl FnOnce() -> R) -> Result<R, InsertError> {
+        struct Guard<'a> {
+            ctx: &'a VirtContext,
+            slot: Slot,
+        }
+
+        impl Drop for Guard<'_> {
+            fn drop(&mut self) {
+                self.ctx.revoke_write(self.slot);
+            }
+        }
+
+        self.grant_write(slot)?;
+        let _guard = Guard { ctx: self, slot };
+        Ok(f())
+    }
+
+    fn grant_write(&self, slot: Slot) -> Result<(), InsertError> {
+        let mut slots = self.slots.lock();
+        let Some(info) = slots.get_mut(&slot) else {
+            return Ok(());
+        };
+        // The grant makes the slot writable, which must not make it writable and executable at
+        // once in a context that enforces W^X.
+        self.check_prot(info.prot | Protections::WRITE)?;
+        // Pages are made writable lazily, by write faults, since some of them may be shared
+        // copy-on-write.
+        info.write_grants += 1;
+        Ok(())
+    }
+
+    fn revoke_write(&self, slot: Slot) {
+        let mut slots = self.slots.lock();
+        let Some(info) = slots.get_mut(&slot) else {
+            return;
+        };
+        info.write_grants = info.write_grants.saturating_sub(1);
+        if info.write_grants > 0 {
+            return;
+        }
+        let settings = info.mapping_settings(true, self.is_kernel);
+        let arches = self.secctx.lock();
+        for arch in arches.values() {
+            arch.change(info.mapping_cursor(0, MAX_SIZE), &settings);
+        }
+    }
+
+    /// Check if a page of a slot is currently mapped in this context's page tables. A page may be
+    /// present in the object without being mapped here. This never faults in a page.
+    pub fn is_resident(&self, slot: Slot, page: PageNumber) -> bool {
+        if page.as_byte_offset() >= MAX_SIZE {
+            return false;
+        }
+        let cursor = MappingCursor::new(
+            slot.start_vaddr().offset(page.as_byte_offset()).unwrap(),
+            PageNumber::PAGE_SIZE,
+        );
+        self.with_arch(KERNEL_SCTX, |arch| {
+            arch.readmap(cursor, |mut r| r.next().is_some())
+        })
+    }
+
+    /// Get the cache type of the mapping that covers an address in this context, as set when the
+    /// object was inserted or by [Self::set_cache_type]. Returns None if no object is mapped at
+    /// the address. If the page is currently mapped with a different cache type in the page
+    /// tables, which indicates a bug, a warning is logged.
+    pub fn cache_type_at(&self, addr: VirtAddr) -> Option<CacheType> {
+        if addr.is_kernel_object_memory() && !self.is_kernel {
+            return kernel_context().cache_type_at(addr);
+        }
+        let slot = Slot::try_from(addr).ok()?;
+        let slots = self.slots.lock();
+        let cache = slots.get(&slot)?.cache;
+        let cursor = MappingCursor::new(
+            addr.align_down(PageNumber::PAGE_SIZE as u64).ok()?,
+            PageNumber::PAGE_SIZE,
+        );
+        let mapped = self.with_arch(KERNEL_SCTX, |arch| {
+            arch.readmap(cursor, |mut r| r.next().map(|map| map.settings().cache()))
+        });
+        if let Some(mapped) = mapped
+            && mapped != cache
+        {
+            logln!(
+                "warning -- {:?} is mapped with cache type {:?}, but its slot has {:?}",
+                addr,
+                mapped,
+                cache
+            );
+        }
+        Some(cache)
+    }
+
+    /// Translate a virtual address to the physical address currently backing it in this context.
+    /// Returns None if the address is not mapped, or if it is a kernel address and this is not the
+    /// kernel context. This never faults in a page.
+    pub fn virt_to_phys(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        if addr.is_kernel() && !self.is_kernel {
+            return None;
+        }
+        let page_size = Table::level_to_page_size(Table::last_level());
+        let cursor = MappingCursor::new(addr.align_down(page_size as u64).ok()?, page_size);
+        let map = self.with_arch(KERNEL_SCTX, |arch| arch.readmap(cursor, |mut r| r.next()))?;
+        // The mapping may be a huge page, in which case the physical address is the start of it.
+        map.paddr().offset(addr.raw() as usize % map.len()).ok()
+    }
+
+    /// Find the pages of this context's slots that are currently mapped to the physical page
+    /// containing phys, as pairs of a slot and a page number within that slot. This never faults
+    /// in a page.
+    fn slots_mapping_page(&self, phys: PhysAddr) -> Vec<(Slot, PageNumber)> {
+        let slots: Vec<Slot> = self.slots.lock().slots.keys().copied().collect();
+        let phys = phys.raw();
+        let mut found = Vec::new();
+        for slot in slots {
+            let cursor = MappingCursor::new(slot.start_vaddr(), MAX_SIZE);
+            self.with_arch(KERNEL_SCTX, |arch| {
+                arch.readmap(cursor, |r| {
+                    for map in r {
+                        let start = map.paddr().raw();
+                        if (start..(start + map.len() as u64)).contains(&phys) {
+                            // The mapping may be a huge page covering more than the one page.
+                            let offset = map.vaddr() - slot.start_vaddr() + (phys - start) as usize;
+                            found.push((slot, PageNumber::from_offset(offset)));
+                        }
+                    }
+                })
+            });
+        }
+        found
+    }
+
+    /// Get the protections that the page tables of this context currently grant for an address.
+    /// These may be narrower than the protections of the slot, for instance when a page is
+    /// write-protected because it is shared copy-on-write. Returns None if the address is not
+    /// currently mapped, even if it falls within a mapped slot. This never faults in a page.
+    pub fn effective_prot(&self, addr: VirtAddr) -> Option<Protections> {
+        if addr.is_kernel() && !self.is_kernel {
+            return None;
+        }
+        let cursor = MappingCursor::new(
+            addr.align_down(PageNumber::PAGE_SIZE as u64).ok()?,
+            PageNumber::PAGE_SIZE,
+        );
+        self.with_arch(KERNEL_SCTX, |arch| {
+            arch.readmap(cursor, |mut r| r.next().map(|map| map.settings().perms()))
+        })
+    }
+
+    /// Decide what a fault at addr in this context calls for, without changing anything: no pages
+    /// are allocated or mapped, and no fault statistics are updated. Returns
+    /// [FaultOutcome::Mapped] if the fault can be resolved by mapping a page, or is spurious, and
+    /// otherwise the outcome that [page_fault] would report. Whether memory can be allocated for
+    /// the page is not decided here, so [FaultOutcome::OutOfMemory] is never returned, and the
+    /// returned mapping outcome never includes a page sample. Kernel object memory can only be
+    /// resolved in the kernel context, by the kernel.
+    pub fn resolve_fault(
+        &self,
+        addr: VirtAddr,
+        cause: MemoryAccessKind,
+        flags: PageFaultFlags,
+        ip: VirtAddr,
+    ) -> FaultOutcome {
+        let violation = FaultOutcome::Violation(MemoryContextViolationInfo::new(addr.raw(), cause));
+        if addr.is_kernel()
+            && (flags.contains(PageFaultFlags::USER)
+                || !self.is_kernel
+                || !addr.is_kernel_object_memory())
+        {
+            return violation;
+        }
+        let Ok(slot) = Slot::try_from(addr) else {
+            return violation;
+        };
+        let slots = self.slots.lock();
+        let Some(info) = slots.get(&slot) else {
+            return violation;
+        };
+        if self
+            .effective_prot(addr)
+            .is_some_and(|prot| prot.contains(access_prot(cause)))
+        {
+            return FaultOutcome::Mapped(None);
+        }
+        let tree = info.obj.lock_page_tree();
+        self.check_fault(info, &tree, addr, cause, ip)
+            .unwrap_or(FaultOutcome::Mapped(None))
+    }
+
+    /// Check a fault at addr, in the mapped slot info, against the slot and the object's page
+    /// tree. Returns the outcome to report if mapping a page can't resolve the fault. Both
+    /// [Self::resolve_fault] and the page fault handler decide with this, the latter under the
+    /// same locks that it maps with.
+    fn check_fault(
+        &self,
+        info: &VirtContextSlot,
+        tree: &PageRangeTree,
+        addr: VirtAddr,
+        cause: MemoryAccessKind,
+        ip: VirtAddr,
+    ) -> Option<FaultOutcome> {
+        let page_number = info.object_page(PageNumber::from_address(addr));
+        let object_fault = |error: ObjectMemoryError| {
+            Some(FaultOutcome::ObjectFault(ObjectMemoryFaultInfo::new(
+                info.obj.id(),
+                error,
+                cause,
+                addr.into(),
+                ip.into(),
+            )))
+        };
+        if page_number.num() < info.null_guard {
+            return object_fault(ObjectMemoryError::NullPageAccess);
+        }
+        if page_number < info.first_mappable_page() {
+            return object_fault(ObjectMemoryError::GuardPageAccess);
+        }
+        if page_number >= info.end_page() {
+            return object_fault(ObjectMemoryError::OutOfBounds(page_number.as_byte_offset()));
+        }
+        // The slot must allow the access, e.g. execute-only mappings may be fetched from, but not
+        // read.
+        if !info.allowed_prot().contains(access_prot(cause)) {
+            return Some(FaultOutcome::Violation(MemoryContextViolationInfo::new(
+                addr.raw(),
+                cause,
+            )));
+        }
+        let shared = tree.try_get_page(page_number).map(|(_, shared)| shared);
+        if info.user_paged && shared.is_none() {
+            return object_fault(ObjectMemoryError::UserPagedRegion);
+        }
+        // Kernel objects are never limited in how many pages they copy on write.
+        if shared == Some(true) && cause == MemoryAccessKind::Write && !self.is_kernel {
+            let limit = self.cow_limit.load(Ordering::SeqCst);
+            let cow_pages = self.cow_pages.load(Ordering::SeqCst);
+            if cow_pages >= limit {
+                return Some(FaultOutcome::MemoryPressure(MemoryPressureInfo::new(
+                    addr.raw(),
+                    cow_pages,
+                    limit,
+                )));
+            }
+        }
+        None
+    }
+
+    /// Check whether an access by userspace to an address in this context would succeed, without
+    /// faulting or changing anything. This makes the same decisions as the page fault handler, so
+    /// kernel code can check user memory before accessing it. Faults that would send any upcall to
+    /// the thread are reported as [ProbeResult::Violation], including faults on pages of user-paged
+    /// regions that are not present, and writes to shared pages once the context has reached its
+    /// copy-on-write limit.
+    pub fn probe(&self, addr: VirtAddr, kind: MemoryAccessKind) -> ProbeResult {
+        if addr.is_kernel() {
+            return ProbeResult::Violation;
+        }
+        let Ok(slot) = Slot::try_from(addr) else {
+            return ProbeResult::Violation;
+        };
+        let slots = self.slots.lock();
+        let Some(info) = slots.get(&slot) else {
+            return ProbeResult::Violation;
+        };
+        let needed = access_prot(kind);
+        if self
+            .effective_prot(addr)
+            .is_some_and(|prot| prot.contains(needed))
+        {
+            return ProbeResult::Ok;
+        }
+        let page_number = info.object_page(PageNumber::from_address(addr));
+        if page_number.num() < info.null_guard
+            || page_number < info.first_mappable_page()
+            || page_number >= info.end_page()
+            || !info.allowed_prot().contains(needed)
+        {
+            return ProbeResult::Violation;
+        }
+        let shared = info
+            .obj
+            .lock_page_tree()
+            .try_get_page(page_number)
+            .map(|(_, shared)| shared);
+        if info.user_paged && shared.is_none() {
+            return ProbeResult::Violation;
+        }
+        if shared == Some(true)
+            && kind == MemoryAccessKind::Write
+            && self.cow_pages.load(Ordering::SeqCst) >= self.cow_limit.load(Ordering::SeqCst)
+        {
+            return ProbeResult::Violation;
+        }
+        ProbeResult::WouldFaultMinor
+    }
+
+    /// Find the page of an object that is mapped at an address in this context, calling f with the
+    /// page and the offset of the address within it. Pages that are not present in the object are
+    /// not allocated. If write is set, the slot must be mapped writable, and copy-on-write sharing
+    /// of the page is broken first.
+    fn with_page_at<R>(
+        &self,
+        addr: VirtAddr,
+        write: bool,
+        f: impl FnOnce(&Page, usize) -> R,
+    ) -> Result<R, MemErr> {
+        let slot = Slot::try_from(addr).map_err(|_| MemErr::NotMapped)?;
+        let slots = self.slots.lock();
+        let info = slots.get(&slot).ok_or(MemErr::NotMapped)?;
+        let page_number = info.object_page(PageNumber::from_address(addr));
+        if page_number < info.first_mappable_page() || page_number >= info.end_page() {
+            return Err(MemErr::NotMapped);
+        }
+        if write && !info.prot.contains(Protections::WRITE) {
+            return Err(MemErr::ReadOnly);
+        }
+        let mut tree = info.obj.lock_page_tree();
+        let (page, shared) = tree.try_get_page(page_number).ok_or(MemErr::NotMapped)?;
+        let offset = addr.raw() as usize % PageNumber::PAGE_SIZE;
+        if !(write && shared) {
+            return Ok(f(&page, offset));
+        }
+        // The page is present, so this only fails if it can't be copied.
+        let (page, _, _) = tree
+            .try_get_or_add_page(page_number, true, |_| None)
+            .ok_or(MemErr::OutOfMemory)?;
+        let r = f(&page, offset);
+        let obj = info.obj.clone();
+        drop(tree);
+        drop(slots);
+        // Mappings of the page that was shared may still be around, so drop them to let the
+        // new page be faulted in.
+        obj.invalidate(page_number..page_number.next(), InvalidateMode::Full);
+        Ok(r)
+    }
+
+    /// Read bytes from memory at an address in this context, without switching to it, by copying
+    /// out of the pages of the objects mapped there. The read may span pages, and stops early at
+    /// the first address that is not backed by a page present in its object, returning the number
+    /// of bytes read. Fails if no bytes could be read. This never faults in a page.
+    pub fn read_bytes(&self, addr: VirtAddr, buf: &mut [u8]) -> Result<usize, MemErr> {
+        let mut done = 0;
+        while done < buf.len() {
+            let Ok(cur) = addr.offset(done) else {
+                break;
+            };
+            let res = self.with_page_at(cur, false, |page, offset| {
+                let len = (PageNumber::PAGE_SIZE - offset).min(buf.len() - done);
+                buf[done..(done + len)].copy_from_slice(&page.as_slice()[offset..(offset + len)]);
+                len
+            });
+            match res {
+                Ok(len) => done += len,
+                Err(e) if done == 0 => return Err(e),
+                Err(_) => break,
+            }
+        }
+        Ok(done)
+    }
+
+    /// Write bytes to memory at an address in this context, without switching to it, by copying
+    /// into the pages of the objects mapped there. Pages that are shared copy-on-write are copied
+    /// first. The write may span pages, and stops early at the first address that is not backed
+    /// by a page present in its object, returning the number of bytes written. Fails if no bytes
+    /// could be written, including if the address is in a slot that is not mapped writable.
+    pub fn write_bytes(&self, addr: VirtAddr, buf: &[u8]) -> Result<usize, MemErr> {
+        let mut done = 0;
+        while done < buf.len() {
+            let Ok(cur) = addr.offset(done) else {
+                break;
+            };
+            let res = self.with_page_at(cur, true, |page, offset| {
+                let len = (PageNumber::PAGE_SIZE - offset).min(buf.len() - done);
+                page.as_mut_slice()[offset..(offset + len)]
+                    .copy_from_slice(&buf[done..(done + len)]);
+                len
+            });
+            match res {
+                Ok(len) => done += len,
+                Err(e) if done == 0 => return Err(e),
+                Err(_) => break,
+            }
+        }
+        Ok(done)
+    }
+
+    /// Read the mappings currently present in this context's page tables for a slot, coalesced
+    /// into runs that are contiguous in both virtual and physical memory and have the same
+    /// settings. Returns an empty list if no object is mapped in the slot. This never faults in a
+    /// page, and does not change any mappings.
+    pub fn readmap_slot(&self, slot: Slot) -> Vec<MapInfo> {
+        let slots = self.slots.lock();
+        let Some(info) = slots.get(&slot) else {
+            return Vec::new();
+        };
+        self.with_arch(KERNEL_SCTX, |arch| {
+            arch.readmap(info.mapping_cursor(0, MAX_SIZE), |r| r.coalesce().collect())
+        })
+    }
+
+    /// Get the physical frames backing the pages of the object mapped in a slot that are currently
+    /// mapped in this context's page tables, as pairs of the page number within the object and the
+    /// physical address, sorted by page number. Pages mapped to the shared zero page are not
+    /// included. This never faults in a page.
+    pub fn frames_for_slot(&self, slot: Slot) -> Vec<(PageNumber, PhysAddr)> {
+        let mut frames = Vec::new();
+        self.for_each_resident(slot, |pn, phys| frames.push((pn, phys)));
+        frames
+    }
+
+    /// Call f with the page number within the object and the physical address of each page of the
+    /// object mapped in a slot that is currently mapped in this context's page tables, in
+    /// ascending order of page number. Unlike [Self::frames_for_slot], this doesn't allocate, so it
+    /// is suitable for very large objects. Pages mapped to the shared zero page are skipped. The
+    /// callback is invoked with the context's slot and page table locks and the object's page tree
+    /// lock held, so it must not call back into this context or the object.
+    pub fn for_each_resident<F: FnMut(PageNumber, PhysAddr)>(&self, slot: Slot, mut f: F) {
+        let slots = self.slots.lock();
+        let Some(info) = slots.get(&slot) else {
+            return;
+        };
+        let tree = info.obj.lock_page_tree();
+        self.with_arch(KERNEL_SCTX, |arch| {
+            arch.readmap(info.mapping_cursor(0, MAX_SIZE), |r| {
+                for map in r.coalesce() {
+                    let first = PageNumber::from_address(map.vaddr());
+                    let last = first.offset(map.len() / PageNumber::PAGE_SIZE);
+                    for (pn, page, _) in
+                        tree.present_pages(info.object_page(first)..info.object_page(last))
+                    {
+                        f(pn, page.physical_address());
+                    }
+                }
+            })
         });
     }
 
-    type Handle<T> = KernelObjectVirtHandle<T>;
+    /// Handle a fault at addr as if it was taken by a userspace thread running in this context,
+    /// using the same logic as a fault from the hardware. Instead of being delivered, any upcalls
+    /// that the fault would send to the thread are returned.
+    #[cfg(test)]
+    pub fn inject_fault(
+        &self,
+        addr: VirtAddr,
+        cause: MemoryAccessKind,
+        flags: PageFaultFlags,
+    ) -> Result<Vec<UpcallInfo>, PageFaultError> {
+        let outcome = handle_fault_in(
+            Some(self),
+            addr.raw(),
+            cause,
+            flags,
+            VirtAddr::new(0).unwrap(),
+        )?;
+        Ok(outcome.upcall().into_iter().collect())
+    }
+
+    /// Set a callback to be invoked after each page fault in this context that maps a page,
+    /// replacing any existing observer. The callback is called without any context locks held,
+    /// and cannot change how the fault is handled.
+    pub fn set_fault_observer(&self, observer: Option<FaultObserver>) {
+        *self.fault_observer.lock() = observer;
+    }
+
+    /// Enable or disable page sampling. While enabled, a fault in this context that allocates a new
+    /// page for an object sends a [UpcallInfo::PageSample] upcall to the faulting thread.
+    pub fn set_page_sampling(&self, enable: bool) {
+        self.page_sampling.store(enable, Ordering::SeqCst);
+    }
+
+    /// Check if an address falls within a slot of this context that is mapped executable. This
+    /// only checks the slot's mapping, so the page itself need not be present yet.
+    pub fn is_executable(&self, addr: VirtAddr) -> bool {
+        let Ok(slot) = Slot::try_from(addr) else {
+            return false;
+        };
+        let slots = self.slots.lock();
+        let Some(info) = slots.get(&slot) else {
+            return false;
+        };
+        let page = info.object_page(PageNumber::from_address(addr));
+        info.prot.contains(Protections::EXEC)
+            && page >= info.first_mappable_page()
+            && page < info.end_page()
+    }
+
+    /// Map all the pages of the object in a slot that are already present in the object, without
+    /// allocating any new pages.
+    fn prefault(&self, slot: Slot) {
+        let slots = self.slots.lock();
+        let Some(info) = slots.get(&slot) else {
+            return;
+        };
+        self.prefault_locked(info);
+    }
+
+    fn prefault_locked(&self, info: &VirtContextSlot) {
+        let tree = info.obj.lock_page_tree();
+        let end = info.end_page();
+        let mut run_start = info.first_mappable_page();
+        let mut run = Vec::new();
+        self.with_arch(KERNEL_SCTX, |arch| {
+            for (pn, page, shared) in tree.present_pages(info.first_mappable_page()..end) {
+                // Map each run of consecutive pages together, keeping the runs bounded in length.
+                if !run.is_empty()
+                    && (run_start.offset(run.len()) != pn || run.len() == Table::PAGE_TABLE_ENTRIES)
+                {
+                    info.map_pages(arch, run_start, &run, self.is_kernel);
+                    run.clear();
+                }
+                if run.is_empty() {
+                    run_start = pn;
+                }
+                run.push((page, shared));
+            }
+            if !run.is_empty() {
+                info.map_pages(arch, run_start, &run, self.is_kernel);
+            }
+        });
+    }
+
+    /// Get the pages of the object mapped in a slot that have been written through this context
+    /// since their dirty state was last cleared, sorted by page number. If `clear` is set, the
+    /// dirty state of those pages is cleared.
+    pub fn collect_dirty(&self, slot: Slot, clear: bool) -> Vec<PageNumber> {
+        let slots = self.slots.lock();
+        let Some(info) = slots.get(&slot) else {
+            return Vec::new();
+        };
+        let base = slot.start_vaddr().raw() as usize;
+        let mut dirty = Vec::new();
+        let arches = self.secctx.lock();
+        for arch in arches.values() {
+            arch.collect_dirty(info.mapping_cursor(0, MAX_SIZE), clear, |vaddr, len| {
+                let first = PageNumber::from_offset(vaddr.raw() as usize - base);
+                dirty.extend((0..(len / PageNumber::PAGE_SIZE)).map(|i| first.offset(i)));
+            });
+        }
+        dirty.sort();
+        dirty.dedup();
+        dirty
+    }
+
+    /// Get the pages of the object mapped in a slot that are present in this context, sorted by
+    /// page number, along with whether each has been accessed since its accessed state was last
+    /// cleared. If `clear` is set, the accessed state of each page is cleared, so that it will be
+    /// set again on the next access, and the harvest is recorded as a sample for
+    /// [Self::working_set], like [Self::sample_working_set].
+    pub fn harvest_accessed(&self, slot: Slot, clear: bool) -> Vec<(PageNumber, bool)> {
+        let mut slots = self.slots.lock();
+        let Some(info) = slots.get_mut(&slot) else {
+            return Vec::new();
+        };
+        let pages = self.harvest_locked(info, clear);
+        pages.into_iter().collect()
+    }
+
+    /// Estimate the working set of a slot: the number of its pages that have been accessed within
+    /// the last [WORKING_SET_SAMPLES] samples. Samples are taken by [Self::sample_working_set], and
+    /// by [Self::harvest_accessed] when it clears the accessed state; this only reads the estimate,
+    /// and leaves the accessed state alone. Returns 0 if nothing is mapped in the slot.
+    pub fn working_set(&self, slot: Slot) -> usize {
+        let slots = self.slots.lock();
+        slots.get(&slot).map_or(0, |info| info.ws_clock.len())
+    }
+
+    /// Take a sample of which pages of a slot have been accessed since the last sample, clearing
+    /// their accessed state, and return the new [Self::working_set] estimate. This is meant to be
+    /// called at a regular interval (e.g. on each tick of a periodic reclaimer), so that the
+    /// estimate covers a fixed window of time. Returns 0 if nothing is mapped in the slot.
+    pub fn sample_working_set(&self, slot: Slot) -> usize {
+        let mut slots = self.slots.lock();
+        let Some(info) = slots.get_mut(&slot) else {
+            return 0;
+        };
+        self.harvest_locked(info, true);
+        info.ws_clock.len()
+    }
+
+    fn harvest_locked(
+        &self,
+        info: &mut VirtContextSlot,
+        clear: bool,
+    ) -> BTreeMap<PageNumber, bool> {
+        let base = info.slot.start_vaddr().raw() as usize;
+        let mut pages: BTreeMap<PageNumber, bool> = BTreeMap::new();
+        let arches = self.secctx.lock();
+        for arch in arches.values() {
+            arch.harvest_accessed(
+                info.mapping_cursor(0, MAX_SIZE),
+                clear,
+                |vaddr, len, accessed| {
+                    let first = PageNumber::from_offset(vaddr.raw() as usize - base);
+                    for i in 0..(len / PageNumber::PAGE_SIZE) {
+                        *pages.entry(first.offset(i)).or_default() |= accessed;
+                    }
+                },
+            );
+        }
+        if clear {
+            info.sample_accessed(&pages);
+        }
+        pages
+    }
+
+    /// Evict a page of the object mapped in a slot. The page is unmapped from this context, and
+    /// from every other context that maps the object, so that the next access faults. Returns
+    /// [EvictResult::Dirty] if the page was dirty in this context, in which case the caller should
+    /// write it back, and [EvictResult::Pinned] if the page is pinned and was left in place.
+    pub fn evict_page(&self, slot: Slot, page: PageNumber) -> EvictResult {
+        if page.as_byte_offset() >= MAX_SIZE {
+            return EvictResult::Clean;
+        }
+        let start = page.as_byte_offset();
+        let end = start + PageNumber::PAGE_SIZE;
+        let mut dirty = false;
+        let (obj, obj_page) = {
+            let mut slots = self.slots.lock();
+            let Some(info) = slots.get_mut(&slot) else {
+                return EvictResult::Clean;
+            };
+            let obj_page = info.object_page(page);
+            if info
+                .obj
+                .lock_page_tree()
+                .try_get_page(obj_page)
+                .is_some_and(|(page, _)| page.is_pinned())
+            {
+                return EvictResult::Pinned;
+            }
+            let arches = self.secctx.lock();
+            // TODO: a write that lands between reading the dirty state and unmapping the page
+            // could be missed.
+            for arch in arches.values() {
+                let cursor = info.mapping_cursor(start, PageNumber::PAGE_SIZE);
+                arch.collect_dirty(cursor, false, |_, _| dirty = true);
+            }
+            self.unmap_locked(info, &arches, start, end, None);
+            (info.obj.clone(), obj_page)
+        };
+        // Drop the mapping from every other context that maps this object too.
+        obj.invalidate(obj_page..obj_page.next(), obj::InvalidateMode::Full);
+        if dirty {
+            EvictResult::Dirty
+        } else {
+            EvictResult::Clean
+        }
+    }
+
+    /// Pin a range of pages of the object mapped in a slot, so that [Self::evict_page] leaves them
+    /// in place. Pages in the range that are not present in the object are filled, and pages that
+    /// are shared copy-on-write are copied. Pins nest, so each call must be matched by a call to
+    /// [Self::unpin_range] before the pages can be evicted again. Fails with
+    /// [InsertError::InvalidRange] if the range includes pages of the slot that may not be mapped,
+    /// and with [InsertError::OutOfMemory] if a page could not be allocated, in which case no
+    /// pages are left pinned.
+    pub fn pin_range(&self, slot: Slot, range: Range<PageNumber>) -> Result<(), InsertError> {
+        let slots = self.slots.lock();
+        let info = slots.get(&slot).ok_or(InsertError::NotMapped)?;
+        let start = info.object_page(range.start);
+        let end = info.object_page(range.end);
+        if start < info.first_mappable_page() || end > info.end_page() {
+            return Err(InsertError::InvalidRange);
+        }
+        let mut tree = info.obj.lock_page_tree();
+        let mut pinned = Vec::new();
+        let mut filled = false;
+        let mut copied = false;
+        let mut res = Ok(());
+        for pn in start.num()..end.num() {
+            let pn = PageNumber::from(pn);
+            copied |= tree.try_get_page(pn).is_some_and(|(_, shared)| shared);
+            // Break any copy-on-write sharing, so the pinned page is the one that stays mapped.
+            let Some((page, _, hole)) = tree.try_get_or_add_page(pn, true, |pn| info.fill_page(pn))
+            else {
+                pinned.iter().for_each(|page: &PageRef| page.unpin());
+                res = Err(InsertError::OutOfMemory);
+                break;
+            };
+            page.pin();
+            filled |= hole;
+            pinned.push(page);
+        }
+        let obj = info.obj.clone();
+        drop(tree);
+        drop(slots);
+        if copied {
+            // This context, and others, may still map the pages that were shared, or the zero
+            // page in place of holes. Drop those mappings to let the pinned pages be faulted in.
+            obj.invalidate(start..end, InvalidateMode::Full);
+        } else if filled {
+            obj.invalidate_zero_mapped(start..end);
+        }
+        res
+    }
+
+    /// Release a pin on each page in a range of the object mapped in a slot, taken by
+    /// [Self::pin_range]. Pages in the range that are not present are skipped.
+    pub fn unpin_range(&self, slot: Slot, range: Range<PageNumber>) -> Result<(), InsertError> {
+        let slots = self.slots.lock();
+        let info = slots.get(&slot).ok_or(InsertError::NotMapped)?;
+        let start = info.object_page(range.start);
+        let end = info.object_page(range.end);
+        let tree = info.obj.lock_page_tree();
+        for (_, page, _) in tree.present_pages(start..end) {
+            page.unpin();
+        }
+        Ok(())
+    }
+
+    /// Change the cache type used to map the object in a slot. This only affects this mapping of
+    /// the object. Present pages are remapped with the new cache type, keeping their current write
+    /// permissions. Fails with [InsertError::UnsupportedCache] if the architecture can't map memory
+    /// with the cache type.
+    pub fn set_cache_type(&self, slot: Slot, cache: CacheType) -> Result<(), InsertError> {
+        if !ArchContext::supports_cache_type(cache) {
+            return Err(InsertError::UnsupportedCache);
+        }
+        let mut slots = self.slots.lock();
+        let info = slots.get_mut(&slot).ok_or(InsertError::NotMapped)?;
+        info.cache = cache;
+        let arches = self.secctx.lock();
+        for arch in arches.values() {
+            let maps: Vec<_> =
+                arch.readmap(info.mapping_cursor(0, MAX_SIZE), |r| r.coalesce().collect());
+            for map in maps {
+                let wp = !map.settings().perms().contains(Protections::WRITE);
+                arch.change(
+                    MappingCursor::new(map.vaddr(), map.len()),
+                    &info.mapping_settings(wp, self.is_kernel),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove protections from the mapping in a slot. Present pages are remapped with the
+    /// protections removed, and pages that are currently write-protected stay that way. This does
+    /// not return until the TLB of every CPU has been flushed of the old mappings, so once it
+    /// returns no CPU can access the slot with the removed protections.
+    pub fn downgrade_slot(&self, slot: Slot, remove: Protections) -> Result<(), InsertError> {
+        let mut slots = self.slots.lock();
+        let info = slots.get_mut(&slot).ok_or(InsertError::NotMapped)?;
+        info.prot.remove(remove);
+        let arches = self.secctx.lock();
+        for arch in arches.values() {
+            let maps: Vec<_> =
+                arch.readmap(info.mapping_cursor(0, MAX_SIZE), |r| r.coalesce().collect());
+            for map in maps {
+                let wp = !map.settings().perms().contains(Protections::WRITE);
+                // Each change waits for the resulting TLB shootdown to complete on all CPUs
+                // before returning.
+                arch.change(
+                    MappingCursor::new(map.vaddr(), map.len()),
+                    &info.mapping_settings(wp, self.is_kernel),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Create a new context with the same slots as this one. Each object mapped here is copied
+    /// (copy-on-write) into a new object, which is mapped into the same slot of the new context
+    /// with the same settings. The two contexts share physical pages until one of them writes to a
+    /// page, and the new context faults its pages in lazily. Fails if a slot can't be mapped in the
+    /// new context.
+    pub fn fork(self: &Arc<Self>) -> Result<Arc<VirtContext>, InsertError> {
+        assert!(!self.is_kernel, "cannot fork the kernel context");
+        let child = VirtContext::new_with_wx_enforce(self.wx_enforce);
+        child.set_null_guard_pages(self.null_guard_pages());
+        let snapshots: Vec<SlotSnapshot> = self
+            .slots
+            .lock()
+            .slots
+            .values()
+            .map(SlotSnapshot::from)
+            .collect();
+        // Each object is copied once, so that slots mapping the same object, such as the slots of
+        // a large object, map the same copy in the new context too.
+        let mut copies: BTreeMap<ObjID, ObjectRef> = BTreeMap::new();
+        let mut stripes: BTreeMap<Slot, (Stripe, Vec<(Slot, ObjectContextInfo)>)> = BTreeMap::new();
+        for snapshot in &snapshots {
+            let obj = copies
+                .entry(snapshot.info.object().id())
+                .or_insert_with(|| {
+                    let obj = Arc::new(Object::new());
+                    register_object(obj.clone());
+                    obj
+                })
+                .clone();
+            let info = snapshot.cow_copy(&obj);
+            match snapshot.stripe {
+                Some(stripe) => stripes
+                    .entry(stripe.base)
+                    .or_insert_with(|| (stripe, Vec::new()))
+                    .1
+                    .push((snapshot.slot, info)),
+                None => child.insert_object(snapshot.slot, &info)?,
+            }
+        }
+        for (stripe, items) in stripes.into_values() {
+            child
+                .insert_slots(&items, Some(stripe))
+                .map_err(|(_, err)| err)?;
+        }
+        for snapshot in &snapshots {
+            snapshot.apply(&child)?;
+        }
+        Ok(child)
+    }
+
+    /// Share the object mapped in a slot with another context, copy-on-write. The object is copied
+    /// into a new object that shares all of its present pages, and the copy is mapped into the
+    /// same slot of other. The shared pages are write-protected in both contexts (and in any other
+    /// context that maps the object), so the next write to a page in either context gives that
+    /// context its own copy of the page. This is like [Self::fork], but for a single slot.
+    pub fn share_cow(&self, other: &Arc<VirtContext>, slot: Slot) -> Result<(), InsertError> {
+        let snapshot = self
+            .slots
+            .lock()
+            .get(&slot)
+            .map(SlotSnapshot::from)
+            .ok_or(InsertError::NotMapped)?;
+        if let Some(existing) = other.lookup_object(slot) {
+            return Err(InsertError::Occupied(existing));
+        }
+        let obj = Arc::new(Object::new());
+        let copy = snapshot.cow_copy(&obj);
+        register_object(obj);
+        other.insert_object(slot, &copy)?;
+        snapshot.apply(other)
+    }
+
+    /// Move the mapping in slot `from` to slot `to`. Pages currently mapped in `from` are mapped
+    /// at the same offsets in `to`, so they need not be faulted in again. Fails with
+    /// [InsertError::InvalidRange] if `to` is not in user memory or `from` is one of the slots of a
+    /// large object, and with [InsertError::OutOfMemory] if page tables for `to` can't be
+    /// allocated, in which case the mapping stays in `from`.
+    pub fn remap_slot(&self, from: Slot, to: Slot) -> Result<(), InsertError> {
+        if !to.is_user() {
+            return Err(InsertError::InvalidRange);
+        }
+        let mut slots = self.slots.lock();
+        if from == to {
+            return slots.get(&from).map(|_| ()).ok_or(InsertError::NotMapped);
+        }
+        if let Some(existing) = slots.get(&to) {
+            return Err(InsertError::Occupied(existing.into()));
+        }
+        match slots.get(&from) {
+            None => return Err(InsertError::NotMapped),
+            Some(info) if info.locked => return Err(InsertError::Locked),
+            // Moving one slot would split the large object up.
+            Some(info) if info.stripe.is_some() => return Err(InsertError::InvalidRange),
+            Some(_) => {}
+        }
+        // Unwrap-Ok: checked above
+        let mut info = slots.remove(from).unwrap();
+        let old_cursor = info.mapping_cursor(0, MAX_SIZE);
+        info.slot = to;
+        let arches = self.secctx.lock();
+        let mapped = arches.values().all(|arch| {
+            let maps: Vec<_> = arch.readmap(old_cursor, |r| r.coalesce().collect());
+            maps.into_iter().all(|map| {
+                let offset = (map.vaddr().raw() - from.start_vaddr().raw()) as usize;
+                // Keep pages that are currently write-protected that way, since they may be shared
+                // copy-on-write.
+                let wp = !map.settings().perms().contains(Protections::WRITE);
+                arch.try_map(
+                    info.mapping_cursor(offset, map.len()),
+                    &mut ContiguousProvider::new(map.paddr(), map.len()),
+                    &info.mapping_settings(wp, self.is_kernel),
+                )
+            })
+        });
+        if !mapped {
+            // Drop whatever was mapped in the new slot, and leave the mapping where it was.
+            for arch in arches.values() {
+                arch.unmap(info.mapping_cursor(0, MAX_SIZE));
+            }
+            info.slot = from;
+            drop(arches);
+            slots.insert(from, info.obj.id(), info);
+            return Err(InsertError::OutOfMemory);
+        }
+        for arch in arches.values() {
+            arch.unmap(old_cursor);
+        }
+        drop(arches);
+        slots.insert(to, info.obj.id(), info);
+        Ok(())
+    }
+
+    /// Move the mappings of this context down into the lowest free slots of user memory, so that
+    /// the occupied slots are packed together. Returns a map from the old slot of each mapping
+    /// that moved to its new slot, so that callers can update their references to it, since the
+    /// addresses of the moved mappings change. Locked slots and slots of large objects stay where
+    /// they are. This should only be called while nothing else is mapping objects in the context.
+    pub fn compact_slots(&self) -> BTreeMap<Slot, Slot> {
+        let movable: Vec<Slot> = self
+            .slots
+            .lock()
+            .slots
+            .iter()
+            .filter(|(slot, info)| slot.is_user() && !info.locked && info.stripe.is_none())
+            .map(|(slot, _)| *slot)
+            .collect();
+        let mut moved = BTreeMap::new();
+        for from in movable {
+            // Every lower slot is occupied once the mappings below this one have been moved, so
+            // the lowest free slot is the next one to fill.
+            let Some(to) = self.find_free_slot() else {
+                break;
+            };
+            if to < from && self.remap_slot(from, to).is_ok() {
+                moved.insert(from, to);
+            }
+        }
+        moved
+    }
+
+    /// Replace the object mapped in a slot with another, returning the ID of the object that was
+    /// mapped there before. The present pages of the new object are mapped in place of the old
+    /// object's pages. The slots lock is held for the whole swap, and a fault in the slot needs it
+    /// to map a page, so an access from another CPU either uses the old mapping (until its TLB
+    /// entry is invalidated) or faults, waits for the swap to complete, and then sees the new
+    /// object. No access can observe the slot empty.
+    pub fn swap_slot_object(
+        self: &Arc<Self>,
+        slot: Slot,
+        new: &ObjectContextInfo,
+    ) -> Result<ObjID, InsertError> {
+        self.check_insert(slot, new)?;
+        let mut new_slot_info = VirtContextSlot::new(slot, new, self.null_guard_pages());
+        let contiguous = new_slot_info.alloc_contiguous()?;
+        // Register with the new object before taking the slots lock, since object invalidation
+        // takes these locks in the opposite order.
+        new.object().add_context(self);
+        let mut slots = self.slots.lock();
+        if slots.get(&slot).is_some_and(|info| info.locked) {
+            drop(slots);
+            new.object().remove_context(self.id.value());
+            return Err(InsertError::Locked);
+        }
+        let Some(old) = slots.remove(slot) else {
+            drop(slots);
+            new.object().remove_context(self.id.value());
+            return Err(InsertError::NotMapped);
+        };
+        new_slot_info.null_guard = slots.null_guard;
+        let replaced = match contiguous
+            .map(|pages| new_slot_info.populate_contiguous(pages))
+            .transpose()
+        {
+            Ok(replaced) => replaced,
+            Err(err) => {
+                slots.insert(slot, old.obj.id(), old);
+                drop(slots);
+                new.object().remove_context(self.id.value());
+                return Err(err);
+            }
+        };
+        let mut batch = self.begin_batch();
+        {
+            let arches = self.secctx.lock();
+            for arch in arches.values() {
+                arch.unmap_batched(old.mapping_cursor(0, MAX_SIZE), &mut batch);
+            }
+        }
+        self.release_committed(&old);
+        self.prefault_locked(&new_slot_info);
+        slots.insert(slot, new.object().id(), new_slot_info);
+        drop(slots);
+        // Only flush once the new pages are in place. Until then, other CPUs may still use their
+        // cached translations of the old pages, which are kept alive by old.
+        batch.flush();
+        old.obj.remove_context(self.id.value());
+        if let Some(replaced) = replaced {
+            new.object().invalidate(replaced, InvalidateMode::Full);
+        }
+        Ok(old.obj.id())
+    }
+
+    /// Insert a number of objects into the context at once, taking the slots lock only once. If
+    /// any of the insertions fails, the ones before it in the batch are rolled back, and the index
+    /// of the failed item is returned along with the error.
+    pub fn insert_objects(
+        self: &Arc<Self>,
+        items: &[(Slot, ObjectContextInfo)],
+    ) -> Result<(), (usize, InsertError)> {
+        self.insert_slots(items, None)
+    }
+
+    /// Map an object that is too large for a single slot, making the first len bytes of the object
+    /// (starting at the offset in info) addressable as one region. Consecutive slots starting at
+    /// base_slot each map the next [MAX_SIZE] bytes of the object. The slots are inserted, and
+    /// removed by [UserContext::remove_object] on any of them, together. If the object is
+    /// accessed past len, the access is reported as out of bounds.
+    pub fn insert_large_object(
+        self: &Arc<Self>,
+        base_slot: Slot,
+        info: &ObjectContextInfo,
+        len: usize,
+    ) -> Result<(), InsertError> {
+        if len == 0 {
+            return Err(InsertError::InvalidRange);
+        }
+        let count = len.div_ceil(MAX_SIZE);
+        let window = MAX_SIZE / PageNumber::PAGE_SIZE;
+        let items = (0..count)
+            .map(|i| {
+                let slot =
+                    Slot::try_from(base_slot.raw() + i).map_err(|_| InsertError::InvalidRange)?;
+                let mut info = info.clone().with_offset(info.offset().offset(i * window));
+                if i > 0 {
+                    // A contiguous run only ever backs the start of the object.
+                    info = info.with_contiguous(0);
+                }
+                Ok((slot, info))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let stripe = Stripe {
+            base: base_slot,
+            count,
+            end: info.offset().offset(len.div_ceil(PageNumber::PAGE_SIZE)),
+        };
+        self.insert_slots(&items, Some(stripe)).map_err(|(_, e)| e)
+    }
+
+    /// Map anonymous memory into a slot, for scratch regions like stacks and heaps. The slot is
+    /// backed by a new object that only this mapping refers to, so its pages are all demand-zero,
+    /// and are freed when the slot is removed. Accesses past the first len bytes of the slot are
+    /// reported as out of bounds.
+    pub fn insert_anonymous(
+        self: &Arc<Self>,
+        slot: Slot,
+        len: usize,
+        prot: Protections,
+    ) -> Result<(), InsertError> {
+        if len > MAX_SIZE {
+            return Err(InsertError::InvalidRange);
+        }
+        if len == 0 {
+            return Err(InsertError::InvalidRange);
+        }
+        // The object is not registered, so that nothing else can find it and keep it alive.
+        let obj = Arc::new(Object::new());
+        let info = ObjectContextInfo::new(obj, prot, CacheType::WriteBack).with_object_len(len);
+        self.insert_object(slot, &info)
+    }
+
+    fn insert_slots(
+        self: &Arc<Self>,
+        items: &[(Slot, ObjectContextInfo)],
+        stripe: Option<Stripe>,
+    ) -> Result<(), (usize, InsertError)> {
+        let mut contiguous = Vec::new();
+        let null_guard = self.null_guard_pages();
+        for (idx, (slot, object_info)) in items.iter().enumerate() {
+            self.check_insert(*slot, object_info)
+                .map_err(|e| (idx, e))?;
+            let new_slot_info = VirtContextSlot::new(*slot, object_info, null_guard);
+            contiguous.push(new_slot_info.alloc_contiguous().map_err(|e| (idx, e))?);
+        }
+        // Register with the objects before taking the slots lock, since object invalidation takes
+        // these locks in the opposite order.
+        for (_, object_info) in items {
+            object_info.object().add_context(self);
+        }
+        let mut slots = self.slots.lock();
+        let mut inserted = Vec::new();
+        let mut shared = Vec::new();
+        let mut replaced = Vec::new();
+        for (idx, ((slot, object_info), contiguous)) in items.iter().zip(contiguous).enumerate() {
+            let mut new_slot_info = VirtContextSlot::new(*slot, object_info, slots.null_guard);
+            new_slot_info.stripe = stripe;
+            let res = match slots.get_mut(slot) {
+                Some(info) if info.same_mapping(&new_slot_info) => {
+                    info.refs += 1;
+                    shared.push(idx);
+                    continue;
+                }
+                Some(info) => Err(InsertError::Occupied((&*info).into())),
+                None => contiguous
+                    .map(|pages| new_slot_info.populate_contiguous(pages))
+                    .transpose(),
+            };
+            if let Err(err) = res {
+                // Nothing can have been mapped into the new slots, since faults need the slots
+                // lock, so they can just be removed.
+                for slot in inserted {
+                    slots.remove(slot);
+                }
+                for idx in shared {
+                    if let Some(info) = slots.get_mut(&items[idx].0) {
+                        info.refs -= 1;
+                    }
+                }
+                drop(slots);
+                for (_, object_info) in items {
+                    object_info.object().remove_context(self.id.value());
+                }
+                return Err((idx, err));
+            }
+            if let Ok(Some(range)) = res {
+                replaced.push((object_info.object().clone(), range));
+            }
+            slots.insert(*slot, object_info.object().id(), new_slot_info);
+            inserted.push(*slot);
+        }
+        drop(slots);
+        // A slot entry holds a single registration with its object, however many times it was
+        // inserted.
+        for idx in shared {
+            items[idx].1.object().remove_context(self.id.value());
+        }
+        for (obj, range) in replaced {
+            obj.invalidate(range, InvalidateMode::Full);
+        }
+        for (slot, object_info) in items {
+            if object_info.prefault() && inserted.contains(slot) {
+                self.prefault(*slot);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl UserContext for VirtContext {
+    type MappingInfo = Slot;
+
+    fn switch_to(&self, sctx: ObjID) {
+        let tc = self.target_cache.lock();
+        let target = tc
+            .get(&sctx)
+            .expect("tried to switch to a non-registered sctx");
+        // Safety: we get the target from an ArchContext that we track.
+        unsafe {
+            ArchContext::switch_to_target(target);
+        }
+        if tls_ready() {
+            current_processor().set_loaded_context(&self.active_cpus);
+        }
+    }
+
+    fn insert_object(
+        self: &Arc<Self>,
+        slot: Slot,
+        object_info: &ObjectContextInfo,
+    ) -> Result<(), InsertError> {
+        self.check_insert(slot, object_info)?;
+        let mut new_slot_info = VirtContextSlot::new(slot, object_info, self.null_guard_pages());
+        let contiguous = new_slot_info.alloc_contiguous()?;
+        object_info.object().add_context(self);
+        let mut slots = self.slots.lock();
+        new_slot_info.null_guard = slots.null_guard;
+        if let Some(info) = slots.get_mut(&slot) {
+            let res = if info.same_mapping(&new_slot_info) {
+                info.refs += 1;
+                Ok(())
+            } else {
+                Err(InsertError::Occupied((&*info).into()))
+            };
+            drop(slots);
+            // A slot entry holds a single registration with its object, however many times it was
+            // inserted.
+            object_info.object().remove_context(self.id.value());
+            return res;
+        }
+        let replaced = match contiguous
+            .map(|pages| new_slot_info.populate_contiguous(pages))
+            .transpose()
+        {
+            Ok(replaced) => replaced,
+            Err(err) => {
+                drop(slots);
+                object_info.object().remove_context(self.id.value());
+                return Err(err);
+            }
+        };
+        slots.insert(slot, object_info.object().id(), new_slot_info);
+        drop(slots);
+        if let Some(replaced) = replaced {
+            // Drop any mappings of the holes that were filled by the contiguous run.
+            object_info
+                .object()
+                .invalidate(replaced, InvalidateMode::Full);
+        }
+        if object_info.prefault() {
+            self.prefault(slot);
+        }
+        Ok(())
+    }
+
+    fn lookup_object(&self, info: Self::MappingInfo) -> Option<ObjectContextInfo> {
+        if info.start_vaddr().is_kernel_object_memory() && !self.is_kernel {
+            kernel_context().lookup_object(info)
+        } else {
+            let slots = self.slots.lock();
+            slots.get(&info).map(|info| info.into())
+        }
+    }
+
+    fn invalidate_object(
+        &self,
+        obj: ObjID,
+        range: &core::ops::Range<PageNumber>,
+        mode: obj::InvalidateMode,
+    ) {
+        let mut slots = self.slots.lock();
+        let Some(maps) = slots.obj_to_slots(obj).map(<[Slot]>::to_vec) else {
+            return;
+        };
+        if matches!(mode, obj::InvalidateMode::WriteProtect) {
+            for map in &maps {
+                if let Some(info) = slots.get_mut(map)
+                    && info.slot_range(range).is_some()
+                {
+                    info.write_protected = true;
+                }
+            }
+        }
+        let mut maps: Vec<_> = maps
+            .iter()
+            .map(|map| {
+                slots
+                    .get(map)
+                    .expect("invalid slot info for a mapped object")
+            })
+            .collect();
+        maps.sort_by_key(|info| info.slot);
+        // If the range in a slot starts at the beginning of the slot, and the range in the previous
+        // (adjacent) slot ran to its end, the two can be invalidated with a single cursor, as long
+        // as they have the same settings.
+        let mut runs: Vec<(MappingCursor, MappingSettings)> = Vec::new();
+        for info in maps {
+            let Some((start, len)) = info.slot_range(range) else {
+                continue;
+            };
+            // Each slot is write-protected starting from its own protections, since the object may
+            // be mapped with different protections in different slots. Write access is only ever
+            // removed, so a read-only view of the object never becomes writable.
+            let settings = info.mapping_settings(true, self.is_kernel);
+            if start == 0
+                && let Some((cursor, last_settings)) = runs.last_mut()
+                && (!matches!(mode, obj::InvalidateMode::WriteProtect)
+                    || *last_settings == settings)
+                && cursor.start().offset(cursor.remaining()).ok() == Some(info.slot.start_vaddr())
+            {
+                *cursor = MappingCursor::new(cursor.start(), cursor.remaining() + len);
+                continue;
+            }
+            runs.push((info.mapping_cursor(start, len), settings));
+        }
+        let arches = self.secctx.lock();
+        // Large objects are often only sparsely mapped, so skip the runs that have nothing mapped
+        // in them. All the page tables map the same pages, so checking one of them is enough.
+        if let Some(arch) = arches.get(&KERNEL_SCTX) {
+            runs.retain(|(cursor, _)| arch.readmap(*cursor, |mut r| r.next().is_some()));
+        }
+        if runs.is_empty() {
+            return;
+        }
+        for arch in arches.values() {
+            for (cursor, settings) in &runs {
+                match mode {
+                    obj::InvalidateMode::Full => {
+                        arch.unmap(*cursor);
+                    }
+                    obj::InvalidateMode::WriteProtect => {
+                        arch.change(*cursor, settings);
+                    }
+                    obj::InvalidateMode::Flush => {
+                        arch.flush_unmap(*cursor);
+                    }
+                }
+            }
+        }
+    }
+
+    fn remove_object(&self, info: Self::MappingInfo) {
+        let mut slots = self.slots.lock();
+        let Some(entry) = slots.get(&info) else {
+            return;
+        };
+        // The slots of a stripe are removed together. Any of them that have since been given
+        // another object (e.g. by swapping it in) no longer belong to the stripe, and are left.
+        let targets: Vec<Slot> = match entry.stripe {
+            Some(stripe) => stripe
+                .slots()
+                .filter(|target| {
+                    slots
+                        .get(target)
+                        .is_some_and(|slot| slot.stripe == Some(stripe))
+                })
+                .collect(),
+            None => alloc::vec![info],
+        };
+        // A locked slot must be unlocked before it can be removed.
+        if targets
+            .iter()
+            .any(|target| slots.get(target).is_some_and(|slot| slot.locked))
+        {
+            return;
+        }
+        // The mapping was inserted more than once, so it stays until every holder removes it.
+        if entry.refs > 1 {
+            for target in targets {
+                if let Some(slot) = slots.get_mut(&target) {
+                    slot.refs -= 1;
+                }
+            }
+            return;
+        }
+        let removed = self.remove_slots_locked(&mut slots, targets);
+        drop(slots);
+        for slot in removed {
+            slot.obj.remove_context(self.id.value());
+        }
+    }
+}
+
+/// The number of accessed-bit samples that [VirtContext::working_set] looks back over, as taken by
+/// [VirtContext::sample_working_set]. Each page
+/// keeps one bit per sample, so this must fit in the bits of a u8.
+pub const WORKING_SET_SAMPLES: u32 = u8::BITS;
+
+/// A run of consecutive slots that together map an object that is larger than a single slot, each
+/// mapping the next [MAX_SIZE] bytes of the object.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Stripe {
+    base: Slot,
+    count: usize,
+    // The page of the object after the last one that is mapped.
+    end: PageNumber,
+}
+
+impl Stripe {
+    fn slots(&self) -> impl Iterator<Item = Slot> {
+        let base = self.base.raw();
+        // Unwrap-Ok: the slots were all checked when the stripe was inserted.
+        (base..(base + self.count)).map(|slot| Slot::try_from(slot).unwrap())
+    }
+}
+
+#[derive(Clone)]
+pub struct VirtContextSlot {
+    obj: ObjectRef,
+    slot: Slot,
+    prot: Protections,
+    cache: CacheType,
+    guard: usize,
+    // The page of the object that appears at the start of the slot.
+    offset: PageNumber,
+    // The object pages allocated by faults in this slot that are still mapped.
+    committed: BTreeSet<PageNumber>,
+    // The object pages allocated by breaking copy-on-write sharing in this slot that are still
+    // mapped.
+    cow_committed: BTreeSet<PageNumber>,
+    // Faults on missing pages are sent to userspace instead of allocating pages.
+    user_paged: bool,
+    flags: MappingFlags,
+    // The NUMA node to prefer when allocating faulted in pages, if any.
+    numa_node: Option<u8>,
+    // The group of slots that this slot is a part of, if it maps part of an object that is too
+    // large for a single slot.
+    stripe: Option<Stripe>,
+    // The maximum number of pages faulted in that may be mapped at once, and the object pages
+    // faulted in, oldest first. The list may contain pages that have since been unmapped by other
+    // means. The set holds the same pages, to find them quickly.
+    resident_cap: Option<usize>,
+    faulted: VecDeque<PageNumber>,
+    faulted_set: BTreeSet<PageNumber>,
+    // Number of physically contiguous pages requested for the start of the mapping.
+    contiguous: usize,
+    // The physically contiguous pages backing the start of the mapping, held so that they stay in
+    // place for the lifetime of the mapping.
+    dma_pages: Vec<PageRef>,
+    // Number of times this mapping has been inserted and not yet removed.
+    refs: usize,
+    // Number of callers that have temporarily made the slot writable, see
+    // VirtContext::with_writable.
+    write_grants: usize,
+    // The number of pages at the start of the object that are reserved as a null guard, as set for
+    // the context.
+    null_guard: usize,
+    // Some pages of the slot have been write-protected to share them copy-on-write. Pages that
+    // have not been written since may still be shared, so this is never cleared.
+    write_protected: bool,
+    // For each page accessed within the last WORKING_SET_SAMPLES samples, a bit per sample that
+    // is set if the page was accessed in that sample, newest in the high bit.
+    ws_clock: BTreeMap<PageNumber, u8>,
+    // The number of pages after a faulting page that the fault also maps, if present.
+    fault_ahead: usize,
+    // The length of the object in bytes, past which accesses are out of bounds.
+    len: Option<usize>,
+    // The slot may not be removed, see VirtContext::lock_slot.
+    locked: bool,
+}
+
+impl From<&VirtContextSlot> for ObjectContextInfo {
+    fn from(info: &VirtContextSlot) -> Self {
+        let object_info = ObjectContextInfo::new(info.obj.clone(), info.prot, info.cache)
+            .with_guard_pages(info.guard)
+            .with_offset(info.offset)
+            .with_contiguous(info.contiguous)
+            .with_mapping_flags(info.flags)
+            .with_numa_node(info.numa_node)
+            .with_write_protected(info.write_protected);
+        match info.len {
+            Some(len) => object_info.with_object_len(len),
+            None => object_info,
+        }
+    }
+}
+
+/// A snapshot of the mapping in a slot, including the settings that are changed after the object
+/// is inserted, for copying the mapping into another context.
+struct SlotSnapshot {
+    slot: Slot,
+    info: ObjectContextInfo,
+    stripe: Option<Stripe>,
+    user_paged: bool,
+    fault_ahead: usize,
+    resident_cap: Option<usize>,
+}
+
+impl From<&VirtContextSlot> for SlotSnapshot {
+    fn from(info: &VirtContextSlot) -> Self {
+        Self {
+            slot: info.slot,
+            info: info.into(),
+            stripe: info.stripe,
+            user_paged: info.user_paged,
+            fault_ahead: info.fault_ahead,
+            resident_cap: info.resident_cap,
+        }
+    }
+}
+
+impl SlotSnapshot {
+    /// Copy the window of the object that the slot maps into obj, copy-on-write, and describe a
+    /// mapping of obj with the same settings. This write-protects the source pages in every
+    /// context that maps them, so no slots lock may be held.
+    fn cow_copy(&self, obj: &ObjectRef) -> ObjectContextInfo {
+        let start = self.info.offset().as_byte_offset();
+        copy_ranges(self.info.object(), start, obj, start, MAX_SIZE);
+        // A contiguous run is shared with the copy, so the copy isn't contiguous itself.
+        self.info
+            .clone()
+            .with_object(obj.clone())
+            .with_contiguous(0)
+    }
+
+    /// Apply the settings that are not part of the [ObjectContextInfo] to the slot in ctx.
+    fn apply(&self, ctx: &VirtContext) -> Result<(), InsertError> {
+        ctx.set_user_paged(self.slot, self.user_paged)?;
+        ctx.set_prefault_window(self.slot, self.fault_ahead)?;
+        ctx.set_resident_cap(self.slot, self.resident_cap.unwrap_or(0))
+    }
+}
+
+impl VirtContextSlot {
+    /// Create the entry for a slot mapping an object as described by info, in a context with the
+    /// given number of null guard pages.
+    fn new(slot: Slot, info: &ObjectContextInfo, null_guard: usize) -> Self {
+        Self {
+            obj: info.object().clone(),
+            slot,
+            prot: info.prot(),
+            cache: info.cache(),
+            guard: info.guard_pages(),
+            offset: info.offset(),
+            committed: BTreeSet::new(),
+            cow_committed: BTreeSet::new(),
+            user_paged: false,
+            flags: info.mapping_flags(),
+            numa_node: info.numa_node(),
+            stripe: None,
+            resident_cap: None,
+            faulted: VecDeque::new(),
+            faulted_set: BTreeSet::new(),
+            contiguous: info.contiguous(),
+            dma_pages: Vec::new(),
+            refs: 1,
+            write_grants: 0,
+            null_guard,
+            write_protected: false,
+            ws_clock: BTreeMap::new(),
+            fault_ahead: FAULT_AHEAD_PAGES - 1,
+            len: info.object_len(),
+            locked: false,
+        }
+    }
+
+    /// Do these two slot entries describe the same mapping? Ignores any runtime state.
+    fn same_mapping(&self, other: &Self) -> bool {
+        self.obj == other.obj
+            && self.slot == other.slot
+            && self.prot == other.prot
+            && self.cache == other.cache
+            && self.guard == other.guard
+            && self.offset == other.offset
+            && self.contiguous == other.contiguous
+            && self.flags == other.flags
+            && self.numa_node == other.numa_node
+            && self.stripe == other.stripe
+            && self.len == other.len
+    }
+
+    /// Record a sample of the accessed state of the slot's present pages for the working set clock.
+    /// Pages that are no longer present drop out of the working set.
+    fn sample_accessed(&mut self, pages: &BTreeMap<PageNumber, bool>) {
+        let old = core::mem::take(&mut self.ws_clock);
+        for (page, accessed) in pages {
+            let mut age = old.get(page).copied().unwrap_or(0) >> 1;
+            if *accessed {
+                age |= 1 << (WORKING_SET_SAMPLES - 1);
+            }
+            if age != 0 {
+                self.ws_clock.insert(*page, age);
+            }
+        }
+    }
+
+    /// Allocate a new page for a fault in this slot, from the slot's NUMA node if it has one. If
+    /// that node is out of memory, fall back to allocating from anywhere, preferring the pool of
+    /// pages that are already zeroed. Returns None if there is no memory left at all.
+    fn try_new_page(&self) -> Option<Page> {
+        self.numa_node
+            .and_then(Page::new_on_node)
+            .or_else(Page::try_new_pooled)
+    }
+
+    /// Provide a page to fill the hole at an object page: a new page, filled from the object's
+    /// backing store if it has contents for the page, and otherwise left zeroed. Every path that
+    /// fills holes for accesses through a slot gets its pages from here. Returns None if out of
+    /// memory.
+    fn fill_page(&self, page: PageNumber) -> Option<Page> {
+        let mut new = self.try_new_page()?;
+        self.obj.read_backing_page(page, &mut new);
+        Some(new)
+    }
+
+    /// Record that an object page was faulted in, if this slot has a resident cap, and return the
+    /// pages that must be unmapped to stay within the cap, oldest first.
+    fn note_faulted(&mut self, page: PageNumber) -> Vec<PageNumber> {
+        let Some(cap) = self.resident_cap else {
+            return Vec::new();
+        };
+        if self.faulted_set.insert(page) {
+            self.faulted.push_back(page);
+        }
+        self.over_cap(cap)
+    }
+
+    /// Remove and return the oldest faulted pages beyond the cap.
+    fn over_cap(&mut self, cap: usize) -> Vec<PageNumber> {
+        let excess = self.faulted.len().saturating_sub(cap);
+        let over: Vec<_> = self.faulted.drain(..excess).collect();
+        for page in &over {
+            self.faulted_set.remove(page);
+        }
+        over
+    }
+
+    /// Allocate the physically contiguous run of pages requested for this slot, if any, checking
+    /// that it fits in the slot after the first mappable page.
+    fn alloc_contiguous(&self) -> Result<Option<Vec<Page>>, InsertError> {
+        if self.contiguous == 0 {
+            return Ok(None);
+        }
+        if self.first_mappable_page().offset(self.contiguous) > self.end_page() {
+            return Err(InsertError::NoContiguousMemory);
+        }
+        Page::new_contiguous(self.contiguous)
+            .map(Some)
+            .ok_or(InsertError::NoContiguousMemory)
+    }
+
+    /// Place a run of contiguous pages into the object, starting at the first mappable page, and
+    /// hold on to them for the lifetime of the mapping. Fails if any page in the run is already
+    /// present in the object, since its contents would be lost. Returns the range of object pages
+    /// that was filled.
+    fn populate_contiguous(&mut self, pages: Vec<Page>) -> Result<Range<PageNumber>, InsertError> {
+        let start = self.first_mappable_page();
+        let end = start.offset(pages.len());
+        // The null guard may have grown since the run was allocated.
+        if end > self.end_page() {
+            return Err(InsertError::NoContiguousMemory);
+        }
+        let mut tree = self.obj.lock_page_tree();
+        if tree.present_pages(start..end).next().is_some() {
+            return Err(InsertError::NoContiguousMemory);
+        }
+        for (i, page) in pages.into_iter().enumerate() {
+            tree.add_page(start.offset(i), page);
+        }
+        self.dma_pages = (start.num()..end.num())
+            .map(|pn| tree.get_page(pn.into(), false).unwrap().0)
+            .collect();
+        Ok(start..end)
+    }
+
+    /// The first page of the object that may be mapped, after the null page and guard pages, and
+    /// not before the start of the slot.
+    fn first_mappable_page(&self) -> PageNumber {
+        PageNumber::from(self.null_guard)
+            .offset(self.guard)
+            .max(self.offset)
+    }
+
+    /// The page of the object after the last one that may be mapped, either at the end of the
+    /// slot, or at the end of the object, whichever comes first.
+    fn end_page(&self) -> PageNumber {
+        let slot_end = self.offset.offset(MAX_SIZE / PageNumber::PAGE_SIZE);
+        let obj_end = self.stripe.map_or(
+            PageNumber::from(MAX_SIZE / PageNumber::PAGE_SIZE),
+            |stripe| stripe.end,
+        );
+        let len_end = self.len.map_or(obj_end, |len| {
+            PageNumber::from(len.div_ceil(PageNumber::PAGE_SIZE))
+        });
+        slot_end.min(obj_end).min(len_end)
+    }
+
+    /// Translate a page number within the slot to a page number within the object.
+    fn object_page(&self, page: PageNumber) -> PageNumber {
+        page.offset(self.offset.num())
+    }
+
+    /// The range of bytes within the slot, as a start and a length, that maps the given range of
+    /// object pages. Returns None if no part of the range is visible in the slot.
+    fn slot_range(&self, range: &Range<PageNumber>) -> Option<(usize, usize)> {
+        let max_pages = MAX_SIZE / PageNumber::PAGE_SIZE;
+        let start = range.start.num().max(self.offset.num()) - self.offset.num();
+        let end = (range.end.num().saturating_sub(self.offset.num())).min(max_pages);
+        if start >= end {
+            return None;
+        }
+        Some((
+            start * PageNumber::PAGE_SIZE,
+            (end - start) * PageNumber::PAGE_SIZE,
+        ))
+    }
+
+    fn mapping_cursor(&self, start: usize, len: usize) -> MappingCursor {
+        MappingCursor::new(self.slot.start_vaddr().offset(start).unwrap(), len)
+    }
+
+    /// Like [Self::mapping_cursor], but for a run of pages of the object starting at the given
+    /// object page, which must be visible in the slot.
+    fn object_cursor(&self, start: PageNumber, pages: usize) -> MappingCursor {
+        self.mapping_cursor(
+            (start - self.offset) * PageNumber::PAGE_SIZE,
+            pages * PageNumber::PAGE_SIZE,
+        )
+    }
+
+    /// The protections that accesses to the slot are checked against. These are the slot's own
+    /// protections, plus write access while the slot is temporarily writable.
+    fn allowed_prot(&self) -> Protections {
+        if self.write_grants > 0 {
+            self.prot | Protections::WRITE
+        } else {
+            self.prot
+        }
+    }
+
+    pub fn mapping_settings(&self, wp: bool, is_kern_obj: bool) -> MappingSettings {
+        let mut prot = self.allowed_prot();
+        if wp {
+            prot.remove(Protections::WRITE);
+        }
+        let flags = if is_kern_obj {
+            // Kernel object mappings are always global, and never accessible from userspace.
+            MappingFlags::GLOBAL | (self.flags - MappingFlags::USER)
+        } else {
+            self.flags
+        };
+        MappingSettings::new(prot, self.cache, flags)
+    }
+
+    pub fn object(&self) -> &ObjectRef {
+        &self.obj
+    }
+
+    /// Map a run of object pages into this slot, starting at object page number start. The run is
+    /// split into separate mappings wherever the copy-on-write state of the pages changes. Returns
+    /// false if a page table could not be allocated, in which case the rest of the run is left
+    /// unmapped, to be mapped by a later fault.
+    fn map_pages(
+        &self,
+        arch: &ArchContext,
+        start: PageNumber,
+        pages: &[(PageRef, bool)],
+        is_kern_obj: bool,
+    ) -> bool {
+        let mut idx = 0;
+        while idx < pages.len() {
+            let cow = pages[idx].1;
+            let len = pages[idx..].iter().take_while(|(_, c)| *c == cow).count();
+            let cursor = self.object_cursor(start.offset(idx), len);
+            arch.unmap(cursor);
+            if !arch.try_map(
+                cursor,
+                &mut ObjectPagesProvider {
+                    pages: &pages[idx..(idx + len)],
+                },
+                &self.mapping_settings(cow, is_kern_obj),
+            ) {
+                return false;
+            }
+            idx += len;
+        }
+        true
+    }
+
+    /// Map the shared zero page, read-only, at the given object page. Returns false if a page table
+    /// could not be allocated.
+    fn map_zero_page(&self, arch: &ArchContext, page_number: PageNumber) -> bool {
+        let cursor = self.object_cursor(page_number, 1);
+        arch.unmap(cursor);
+        arch.try_map(
+            cursor,
+            &mut ContiguousProvider::new(zero_frame().start_address(), PageNumber::PAGE_SIZE),
+            &self.mapping_settings(true, false),
+        )
+    }
+
+    /// Collect the pages following page_number that are already present in the object, up to the
+    /// slot's fault-ahead window, stopping at the first hole.
+    fn fault_ahead<'a>(
+        &self,
+        tree: &'a PageRangeTree,
+        page_number: PageNumber,
+    ) -> impl Iterator<Item = (PageRef, bool)> + 'a {
+        let end = self.end_page();
+        (1..=self.fault_ahead)
+            .map(move |i| page_number.offset(i))
+            .take_while(move |pn| *pn < end)
+            .map_while(move |pn| tree.try_get_page(pn))
+    }
+
+    /// Provide a huge page, starting at the given page, which must be the first in a physically
+    /// contiguous run of pages of length [huge_page_size].
+    fn huge_phys_provider<'a>(&self, page: &'a Page) -> ObjectPageProvider<'a> {
+        ObjectPageProvider {
+            page,
+            len: huge_page_size(),
+        }
+    }
+
+    /// Check if the huge-page-sized region of the slot containing object page page_number can be
+    /// mapped with a single huge page. This requires that every page in the region is present and
+    /// private, and that the pages are physically contiguous and aligned. On success, returns the
+    /// first object page number and page of the region.
+    fn huge_page_run(
+        &self,
+        tree: &PageRangeTree,
+        page_number: PageNumber,
+    ) -> Option<(PageNumber, PageRef)> {
+        let nr_pages = huge_page_size() / PageNumber::PAGE_SIZE;
+        // Align within the slot, since the slot's virtual address is what must be aligned.
+        let slot_page = page_number - self.offset;
+        let base = self.object_page(PageNumber::from(slot_page - slot_page % nr_pages));
+        // Never cover the null page or guard pages, and never go past the end of the slot.
+        if base < self.first_mappable_page() || base.offset(nr_pages) > self.end_page() {
+            return None;
+        }
+        let (first, shared) = tree.try_get_page(base)?;
+        let start = first.physical_address();
+        if shared || !start.is_aligned_to(huge_page_size()) {
+            return None;
+        }
+        for i in 1..nr_pages {
+            let (page, shared) = tree.try_get_page(base.offset(i))?;
+            if shared || page.physical_address() != start.offset(i * PageNumber::PAGE_SIZE).ok()? {
+                return None;
+            }
+        }
+        Some((base, first))
+    }
+}
+
+/// The size of a huge page used for mapping object memory. This is the page size of the level
+/// directly above the last level of the page tables.
+fn huge_page_size() -> usize {
+    PageNumber::PAGE_SIZE * Table::PAGE_TABLE_ENTRIES
+}
+
+impl Drop for VirtContext {
+    fn drop(&mut self) {
+        let id = self.id().value();
+        CONTEXTS.lock().remove(&id);
+        // cleanup and object's context info
+        for info in self.slots.get_mut().slots.values() {
+            self.resident
+                .fetch_sub(info.committed.len(), Ordering::SeqCst);
+            info.obj.remove_context(id)
+        }
+    }
+}
+
+pub const HEAP_MAX_LEN: usize = 0x0000001000000000 / 16; //4GB
+
+const INITIAL_HEAP_LEN: usize = 2 * 1024 * 1024;
+
+/// The least the heap grows by at a time, so that a burst of small allocations doesn't map a few
+/// pages at a time. Must be a multiple of the page size.
+pub const MIN_HEAP_GROWTH: usize = 2 * 1024 * 1024;
+
+struct GlobalPageAlloc {
+    alloc: linked_list_allocator::Heap,
+    // The end of the mapped part of the heap. The top of the allocator's region is the high-water
+    // mark of the heap, and the two differ only while a chunk is trimmed.
+    end: VirtAddr,
+    // A freed chunk at the top of the heap that was unmapped (from end to the top of the
+    // allocator's region) instead of being returned to the allocator. The allocator has no way to
+    // shrink, so it still counts the chunk as allocated until the heap needs to grow again.
+    trimmed: Option<(NonNull<u8>, core::alloc::Layout)>,
+}
+
+impl GlobalPageAlloc {
+    fn top(&self) -> VirtAddr {
+        VirtAddr::from_ptr(self.alloc.top())
+    }
+
+    /// The number of bytes of virtual memory currently mapped for the heap.
+    fn size(&self) -> usize {
+        self.end - VirtAddr::HEAP_START
+    }
+
+    /// The number of bytes of the heap currently allocated. A trimmed chunk is not counted, since
+    /// it is free even though the allocator does not know it.
+    fn used(&self) -> usize {
+        self.alloc.used() - self.trimmed.map_or(0, |(_, layout)| layout.size())
+    }
+
+    /// Grow the heap by len bytes. If a chunk was trimmed, its memory is remapped and returned to
+    /// the allocator first, and the caller should retry its allocation before growing further.
+    /// Fails if the heap would grow past [HEAP_MAX_LEN].
+    fn extend(&mut self, len: usize, mapper: &VirtContext) -> Result<(), ()> {
+        if let Some((ptr, layout)) = self.trimmed.take() {
+            let top = self.top();
+            self.map(top - self.end, mapper);
+            // Safety: the chunk came from the allocator, and is backed by memory again.
+            unsafe {
+                self.alloc.deallocate(ptr, layout);
+            }
+            return Ok(());
+        }
+        if self
+            .size()
+            .checked_add(len)
+            .map_or(true, |size| size > HEAP_MAX_LEN)
+        {
+            return Err(());
+        }
+        self.map(len, mapper);
+        // Safety: the extension is backed by memory that is directly after the previous call to
+        // extend.
+        unsafe {
+            self.alloc.extend(len);
+        }
+        Ok(())
+    }
+
+    fn map(&mut self, len: usize, mapper: &VirtContext) {
+        let cursor = MappingCursor::new(self.end, len);
+        let mut phys = ZeroPageProvider::default();
+        let settings = MappingSettings::new(
+            Protections::READ | Protections::WRITE,
+            CacheType::WriteBack,
+            MappingFlags::GLOBAL,
+        );
+        mapper.with_arch(KERNEL_SCTX, |arch| {
+            arch.map(cursor, &mut phys, &settings);
+        });
+        self.end = self.end.offset(len).unwrap();
+    }
+
+    /// Try to release the memory of a chunk being freed back to the system. This is only possible
+    /// if the chunk is at the top of the heap, and never shrinks the heap below its initial size.
+    /// Returns false if the chunk should be returned to the allocator instead.
+    fn trim(
+        &mut self,
+        ptr: NonNull<u8>,
+        layout: core::alloc::Layout,
+        mapper: &VirtContext,
+    ) -> bool {
+        let page_size = Table::level_to_page_size(Table::last_level());
+        let start = VirtAddr::from_ptr(ptr.as_ptr());
+        if self.trimmed.is_some() || start.offset(layout.size()).ok() != Some(self.end) {
+            return false;
+        }
+        let Ok(new_end) = start.align_up(page_size as u64) else {
+            return false;
+        };
+        if new_end >= self.end || new_end < VirtAddr::HEAP_START.offset(INITIAL_HEAP_LEN).unwrap() {
+            return false;
+        }
+        // Go a page at a time, since we cannot allocate memory here to remember the frames, and we
+        // must not free a frame until it is unmapped.
+        mapper.with_arch(KERNEL_SCTX, |arch| {
+            let mut addr = new_end;
+            while addr < self.end {
+                let cursor = MappingCursor::new(addr, page_size);
+                let frame =
+                    arch.readmap(cursor, |mut r| r.next().and_then(|m| get_frame(m.paddr())));
+                arch.unmap(cursor);
+                if let Some(frame) = frame {
+                    free_frame(frame);
+                }
+                addr = addr.offset(page_size).unwrap();
+            }
+        });
+        self.end = new_end;
+        self.trimmed = Some((ptr, layout));
+        true
+    }
+
+    fn init(&mut self, mapper: &VirtContext) {
+        self.map(INITIAL_HEAP_LEN, mapper);
+        // Safety: the initial is backed by memory.
+        unsafe {
+            self.alloc
+                .init(VirtAddr::HEAP_START.as_mut_ptr(), INITIAL_HEAP_LEN);
+        }
+    }
+}
+
+// Safety: the internal heap contains raw pointers, which are not Send. However, the heap is
+// globally mapped and static for the lifetime of the kernel.
+unsafe impl Send for GlobalPageAlloc {}
+
+static GLOBAL_PAGE_ALLOC: Spinlock<GlobalPageAlloc> = Spinlock::new(GlobalPageAlloc {
+    alloc: linked_list_allocator::Heap::empty(),
+    end: VirtAddr::HEAP_START,
+    trimmed: None,
+});
+
+/// The number of bytes currently allocated from the kernel heap.
+pub fn kernel_heap_used() -> usize {
+    GLOBAL_PAGE_ALLOC.lock().used()
+}
+
+/// The number of bytes of memory currently mapped for the kernel heap.
+pub fn kernel_heap_size() -> usize {
+    GLOBAL_PAGE_ALLOC.lock().size()
+}
+
+impl KernelMemoryContext for VirtContext {
+    fn allocate_chunk(&self, layout: core::alloc::Layout) -> NonNull<u8> {
+        self.try_allocate_chunk(layout).unwrap_or_else(|_| {
+            panic!(
+                "kernel heap exhausted: {} bytes mapped, cannot allocate {:?} (max {})",
+                GLOBAL_PAGE_ALLOC.lock().size(),
+                layout,
+                HEAP_MAX_LEN
+            )
+        })
+    }
+
+    fn try_allocate_chunk(&self, layout: core::alloc::Layout) -> Result<NonNull<u8>, AllocError> {
+        let mut glb = GLOBAL_PAGE_ALLOC.lock();
+        if let Ok(x) = glb.alloc.allocate_first_fit(layout) {
+            return Ok(x);
+        }
+        let size = layout
+            .pad_to_align()
+            .size()
+            .next_multiple_of(Table::level_to_page_size(Table::last_level()))
+            .checked_mul(2)
+            .ok_or(AllocError)?;
+        if glb.trimmed.is_some() {
+            let _ = glb.extend(size, self);
+            if let Ok(x) = glb.alloc.allocate_first_fit(layout) {
+                return Ok(x);
+            }
+        }
+        // Close to the maximum size, fall back to growing by only as much as is needed.
+        if glb.extend(size.max(MIN_HEAP_GROWTH), self).is_err() {
+            glb.extend(size, self).map_err(|_| AllocError)?;
+        }
+        glb.alloc.allocate_first_fit(layout).map_err(|_| AllocError)
+    }
+
+    unsafe fn deallocate_chunk(&self, layout: core::alloc::Layout, ptr: NonNull<u8>) {
+        let mut glb = GLOBAL_PAGE_ALLOC.lock();
+        if !glb.trim(ptr, layout, self) {
+            glb.alloc.deallocate(ptr, layout);
+        }
+    }
+
+    fn init_allocator(&self) {
+        let mut glb = GLOBAL_PAGE_ALLOC.lock();
+        glb.init(self);
+    }
+
+    fn prep_smp(&self) {
+        self.with_arch(KERNEL_SCTX, |arch| {
+            arch.unmap(MappingCursor::new(
+                VirtAddr::start_user_memory(),
+                VirtAddr::end_user_memory() - VirtAddr::start_user_memory(),
+            ))
+        });
+    }
+
+    type Handle<T> = KernelObjectVirtHandle<T>;
+
+    fn insert_kernel_object<T>(&self, info: ObjectContextInfo) -> Self::Handle<T> {
+        assert!(
+            ArchContext::supports_cache_type(info.cache()),
+            "unsupported cache type for kernel object: {:?}",
+            info.cache()
+        );
+        let mut slots = self.slots.lock();
+        let mut kernel_slots_counter = KERNEL_SLOT_COUNTER.lock();
+        let slot = kernel_slots_counter
+            .kernel_slots_nums
+            .pop()
+            .unwrap_or_else(|| {
+                let cur = kernel_slots_counter.cur_kernel_slot;
+                kernel_slots_counter.cur_kernel_slot += 1;
+                let max = Slot::try_from(
+                    VirtAddr::end_kernel_object_memory()
+                        .offset(-1isize)
+                        .unwrap(),
+                )
+                .unwrap()
+                .raw();
+                if cur > max {
+                    panic!("out of kernel object slots");
+                }
+                Slot(cur)
+            });
+        let new_slot_info = VirtContextSlot::new(slot, &info, slots.null_guard);
+        slots.insert(slot, info.object().id(), new_slot_info);
+        KernelObjectVirtHandle {
+            info,
+            slot,
+            _pd: PhantomData,
+        }
+    }
+}
+
+pub struct KernelObjectVirtHandle<T> {
+    info: ObjectContextInfo,
+    slot: Slot,
+    _pd: PhantomData<T>,
+}
+
+impl<T> Clone for KernelObjectVirtHandle<T> {
+    fn clone(&self) -> Self {
+        Self {
+            info: self.info.clone(),
+            slot: self.slot,
+            _pd: PhantomData,
+        }
+    }
+}
+
+impl<T> KernelObjectVirtHandle<T> {
+    pub fn start_addr(&self) -> VirtAddr {
+        VirtAddr::new(0)
+            .unwrap()
+            .offset(self.slot.raw() * MAX_SIZE)
+            .unwrap()
+    }
+
+    pub fn id(&self) -> ObjID {
+        self.info.object().id()
+    }
+}
+
+impl<T> Drop for KernelObjectVirtHandle<T> {
+    fn drop(&mut self) {
+        let kctx = kernel_context();
+        {
+            let mut slots = kctx.slots.lock();
+            // We don't need to tell the object that it's no longer mapped in the kernel context,
+            // since object invalidation always informs the kernel context.
+            slots.remove(self.slot);
+        }
+        kctx.with_arch(KERNEL_SCTX, |arch| {
+            arch.unmap(MappingCursor::new(self.start_addr(), MAX_SIZE));
+        });
+        KERNEL_SLOT_COUNTER.lock().kernel_slots_nums.push(self.slot);
+    }
+}
+
+impl<T> KernelObjectHandle<T> for KernelObjectVirtHandle<T> {
+    fn base(&self) -> &T {
+        unsafe {
+            self.start_addr()
+                .offset(NULLPAGE_SIZE)
+                .unwrap()
+                .as_ptr::<T>()
+                .as_ref()
+                .unwrap()
+        }
+    }
+
+    fn base_mut(&mut self) -> &mut T {
+        unsafe {
+            self.start_addr()
+                .offset(NULLPAGE_SIZE)
+                .unwrap()
+                .as_mut_ptr::<T>()
+                .as_mut()
+                .unwrap()
+        }
+    }
+
+    fn lea_raw<R>(&self, iptr: *const R) -> Option<&R> {
+        let offset = iptr as usize;
+        let size = size_of::<R>();
+        if offset >= MAX_SIZE || offset.checked_add(size)? >= MAX_SIZE {
+            return None;
+        }
+        unsafe {
+            Some(
+                self.start_addr()
+                    .offset(offset)
+                    .unwrap()
+                    .as_ptr::<R>()
+                    .as_ref()
+                    .unwrap(),
+            )
+        }
+    }
+
+    fn lea_raw_mut<R>(&self, iptr: *mut R) -> Option<&mut R> {
+        let offset = iptr as usize;
+        let size = size_of::<R>();
+        if offset >= MAX_SIZE || offset.checked_add(size)? >= MAX_SIZE {
+            return None;
+        }
+        unsafe {
+            Some(
+                self.start_addr()
+                    .offset(offset)
+                    .unwrap()
+                    .as_mut_ptr::<R>()
+                    .as_mut()
+                    .unwrap(),
+            )
+        }
+    }
+}
+
+impl StableId for VirtContext {
+    fn id(&self) -> &Id<'_> {
+        &self.id
+    }
+}
+
+bitflags::bitflags! {
+    #[derive(Debug, Clone, Copy)]
+    pub struct PageFaultFlags : u32 {
+        const USER = 1;
+        const INVALID = 2;
+        const PRESENT = 4;
+    }
+}
+
+/// What a page fault calls for, as decided by [VirtContext::resolve_fault], or what came of
+/// handling one. When a fault is handled, the outcome is known once memory has been taken care of,
+/// e.g. pages have been allocated and mapped. Only the upcall to the faulting thread is left to the
+/// caller, so that it can be delivered, or inspected instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultOutcome {
+    /// The fault was resolved, and the access may be retried. If page sampling is enabled and the
+    /// fault allocated a new page, the sample to report is included.
+    Mapped(Option<PageSampleInfo>),
+    /// The address is not mapped in the context, or may not be accessed at all.
+    Violation(MemoryContextViolationInfo),
+    /// The address is in a mapped object, but the access is not allowed or can't be resolved by
+    /// the kernel.
+    ObjectFault(ObjectMemoryFaultInfo),
+    /// The context has reached its limit of pages copied on write.
+    MemoryPressure(MemoryPressureInfo),
+    /// A new page was needed, but no physical memory was available, even after running the
+    /// out-of-memory hooks.
+    OutOfMemory(OutOfMemoryInfo),
+    /// The fault is in userspace memory, but there is no context to handle it in.
+    NeedsContext,
+}
+
+impl FaultOutcome {
+    /// The upcall to send to the faulting thread, if any.
+    pub fn upcall(&self) -> Option<UpcallInfo> {
+        match *self {
+            FaultOutcome::Mapped(sample) => sample.map(UpcallInfo::PageSample),
+            FaultOutcome::Violation(info) => Some(UpcallInfo::MemoryContextViolation(info)),
+            FaultOutcome::ObjectFault(info) => Some(UpcallInfo::ObjectMemoryFault(info)),
+            FaultOutcome::MemoryPressure(info) => Some(UpcallInfo::MemoryPressure(info)),
+            FaultOutcome::OutOfMemory(info) => Some(UpcallInfo::OutOfMemory(info)),
+            FaultOutcome::NeedsContext => None,
+        }
+    }
+
+    /// Whether the outcome is counted as a violation in the fault statistics. Faults left to a
+    /// user pager, or held back by the copy-on-write limit, are not violations.
+    fn is_violation(&self) -> bool {
+        match self {
+            FaultOutcome::Violation(_) => true,
+            FaultOutcome::ObjectFault(info) => info.error != ObjectMemoryError::UserPagedRegion,
+            _ => false,
+        }
+    }
+}
+
+/// Errors for page faults that could not be handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageFaultError {
+    /// The page tables contain invalid bits for the faulting address.
+    InvalidBits,
+    /// The fault is in userspace memory, but the current thread has no memory context.
+    NoContext,
+}
+
+pub fn page_fault(
+    addr: u64,
+    cause: MemoryAccessKind,
+    flags: PageFaultFlags,
+    ip: VirtAddr,
+) -> Result<(), PageFaultError> {
+    let user_ctx = current_memory_context();
+    let outcome = handle_fault_in(user_ctx.as_deref(), addr, cause, flags, ip)?;
+    if outcome == FaultOutcome::NeedsContext {
+        return Err(PageFaultError::NoContext);
+    }
+    if let Some(upcall) = outcome.upcall() {
+        current_thread_ref().unwrap().send_upcall(upcall);
+    }
+    Ok(())
+}
+
+/// Handle a page fault that [page_fault] could not resolve. A fault from userspace kills the
+/// faulting thread, but a fault from the kernel is fatal.
+pub fn page_fault_failed(addr: u64, flags: PageFaultFlags, err: PageFaultError) -> ! {
+    if !flags.contains(PageFaultFlags::USER) {
+        panic!("unhandled kernel page fault at {:#x}: {:?}", addr, err);
+    }
+    logln!(
+        "killing thread {} after unhandled page fault at {:#x}: {:?}",
+        current_thread_ref().unwrap().id(),
+        addr,
+        err
+    );
+    crate::thread::exit(UPCALL_EXIT_CODE);
+}
+
+/// Handle a page fault in user_ctx, the memory context of the faulting thread, if any, mapping
+/// the page if [VirtContext::resolve_fault] would decide the access can be resolved. Faults on
+/// kernel object memory are handled in the kernel context. Pages may be allocated, mapped and
+/// unmapped, and fault statistics updated; only sending the upcall, if any, is left to the caller.
+/// All locks are released before returning.
+fn handle_fault_in(
+    user_ctx: Option<&VirtContext>,
+    addr: u64,
+    cause: MemoryAccessKind,
+    flags: PageFaultFlags,
+    ip: VirtAddr,
+) -> Result<FaultOutcome, PageFaultError> {
+    handle_fault_attempt(user_ctx, addr, cause, flags, ip, true)
+}
+
+/// Make an attempt at handling a page fault, for [handle_fault_in]. If a page or page table can't
+/// be allocated, and oom_retry is set, the out-of-memory hooks are run, and if they freed memory,
+/// the fault is attempted once more without them. Only retrying once keeps a hook that reports
+/// freeing memory, but not enough for this fault, from making the access fault forever.
+fn handle_fault_attempt(
+    user_ctx: Option<&VirtContext>,
+    addr: u64,
+    cause: MemoryAccessKind,
+    flags: PageFaultFlags,
+    ip: VirtAddr,
+    oom_retry: bool,
+) -> Result<FaultOutcome, PageFaultError> {
+    //logln!("page-fault: {:?} {:?} {:?} ip={:?}", addr, cause, flags, ip);
+    if flags.contains(PageFaultFlags::INVALID) {
+        return Err(PageFaultError::InvalidBits);
+    }
+    // The fault address comes straight from the hardware, so make sure it's one we can handle
+    // before going any further.
+    let Ok(addr) = VirtAddr::new(addr) else {
+        if !flags.contains(PageFaultFlags::USER) {
+            panic!(
+                "kernel page-fault at IP {:?} caused by {:?} to/from non-canonical address {:#x} with flags {:?}",
+                ip, cause, addr, flags
+            );
+        }
+        if let Some(ctx) = user_ctx {
+            FaultStats::record(&ctx.fault_stats.violations);
+        }
+        return Ok(FaultOutcome::Violation(
+            MemoryContextViolationInfo::non_canonical(addr, cause),
+        ));
+    };
+    if !flags.contains(PageFaultFlags::USER) && cause == MemoryAccessKind::InstructionFetch {
+        panic!(
+            "kernel page-fault at IP {:?} caused by {:?} to/from {:?} with flags {:?}",
+            ip, cause, addr, flags
+        );
+    }
+    if !flags.contains(PageFaultFlags::USER) && addr.is_kernel() && !addr.is_kernel_object_memory()
+    {
+        panic!(
+            "kernel page-fault at IP {:?} caused by {:?} to/from {:?} with flags {:?}",
+            ip, cause, addr, flags
+        );
+    } else {
+        if flags.contains(PageFaultFlags::USER) && addr.is_kernel() {
+            if let Some(ctx) = user_ctx {
+                FaultStats::record(&ctx.fault_stats.violations);
+            }
+            return Ok(FaultOutcome::Violation(MemoryContextViolationInfo::new(
+                addr.raw(),
+                cause,
+            )));
+        }
+
+        let (ctx, is_kern_obj) = if addr.is_kernel_object_memory() {
+            assert!(!flags.contains(PageFaultFlags::USER));
+            (&**kernel_context(), true)
+        } else {
+            let Some(ctx) = user_ctx else {
+                return Ok(FaultOutcome::NeedsContext);
+            };
+            (ctx, false)
+        };
+        // Converting an address only fails for kernel memory outside of kernel object memory.
+        let Ok(slot) = Slot::try_from(addr) else {
+            FaultStats::record(&ctx.fault_stats.violations);
+            return Ok(FaultOutcome::Violation(MemoryContextViolationInfo::new(
+                addr.raw(),
+                cause,
+            )));
+        };
+
+        let mut slot_mgr = ctx.slots.lock();
+        if let Some(info) = slot_mgr.get_mut(&slot) {
+            // Another thread may have mapped the page since this fault was taken, or the fault may
+            // just be from a stale TLB entry. Either way, there's nothing to do.
+            if ctx
+                .effective_prot(addr)
+                .is_some_and(|prot| prot.contains(access_prot(cause)))
+            {
+                FaultStats::record(&ctx.fault_stats.spurious);
+                return Ok(FaultOutcome::Mapped(None));
+            }
+            let id = info.obj.id();
+            let page_number = info.object_page(PageNumber::from_address(addr));
+            // Called with no locks held when a page or page table can't be allocated.
+            let out_of_memory = || {
+                if oom_retry && run_oom_hooks(id, page_number) {
+                    // Some memory was freed, so try the access again.
+                    return handle_fault_attempt(user_ctx, addr.raw(), cause, flags, ip, false);
+                }
+                Ok(FaultOutcome::OutOfMemory(OutOfMemoryInfo::new(
+                    id,
+                    addr.raw(),
+                )))
+            };
+            let mut obj_page_tree = info.obj.lock_page_tree();
+            if let Some(outcome) = ctx.check_fault(info, &obj_page_tree, addr, cause, ip) {
+                if outcome.is_violation() {
+                    FaultStats::record(&ctx.fault_stats.violations);
+                }
+                // drop these mutexes in case upcall sending generetes a page fault.
+                drop(obj_page_tree);
+                drop(slot_mgr);
+                return Ok(outcome);
+            }
+
+            let mut first_touch = false;
+            // An instruction fetch doesn't need write access, so pages are mapped without it, even
+            // if the slot is writable. A later write faults again to add it.
+            let is_exec = cause == MemoryAccessKind::InstructionFetch;
+            // Huge pages would map more pages at once than a resident cap allows.
+            if let Some((base, page)) = info
+                .resident_cap
+                .is_none()
+                .then(|| info.huge_page_run(&obj_page_tree, page_number))
+                .flatten()
+            {
+                FaultStats::record(&ctx.fault_stats.minor);
+                // TODO: select user context here.
+                let mapped = ctx.with_arch(KERNEL_SCTX, |arch| {
+                    let cursor = info.object_cursor(base, huge_page_size() / PageNumber::PAGE_SIZE);
+                    // Unmap first to clear out any smaller mappings (and their page tables).
+                    arch.unmap(cursor);
+                    arch.try_map(
+                        cursor,
+                        &mut info.huge_phys_provider(&page),
+                        &info.mapping_settings(is_exec, is_kern_obj),
+                    )
+                });
+                if !mapped {
+                    drop(obj_page_tree);
+                    drop(slot_mgr);
+                    return out_of_memory();
+                }
+            } else {
+                let is_write = cause == MemoryAccessKind::Write;
+                // Classify the fault before get_page has a chance to fill holes or break sharing.
+                let shared = obj_page_tree
+                    .try_get_page(page_number)
+                    .map(|(_, shared)| shared);
+                let is_cow = shared == Some(true) && is_write;
+                match shared {
+                    Some(true) if is_write => FaultStats::record(&ctx.fault_stats.cow),
+                    Some(_) => FaultStats::record(&ctx.fault_stats.minor),
+                    None => FaultStats::record(&ctx.fault_stats.zero_fill),
+                }
+                // A hole in an object with a backing store is filled from the store. This must be
+                // done before considering the zero page, so that reads see the stored contents.
+                let mut paged_in = None;
+                if shared.is_none() && !is_write && !is_kern_obj && info.obj.page_in().is_some() {
+                    let Some(mut page) = info.try_new_page() else {
+                        drop(obj_page_tree);
+                        drop(slot_mgr);
+                        return out_of_memory();
+                    };
+                    if info.obj.read_backing_page(page_number, &mut page) {
+                        paged_in = Some(page);
+                    }
+                }
+                if shared.is_none() && !is_write && !is_kern_obj && paged_in.is_none() {
+                    // A read of a hole doesn't need its own page. Map the zero page instead, and
+                    // wait for a write fault to allocate a private page in its place.
+                    info.obj.mark_zero_mapped();
+                    let mapped =
+                        ctx.with_arch(KERNEL_SCTX, |arch| info.map_zero_page(arch, page_number));
+                    drop(obj_page_tree);
+                    drop(slot_mgr);
+                    if !mapped {
+                        return out_of_memory();
+                    }
+                    let observer = *ctx.fault_observer.lock();
+                    if let Some(observer) = observer {
+                        observer(id, page_number, cause);
+                    }
+                    return Ok(FaultOutcome::Mapped(None));
+                }
+                let sample = !is_kern_obj && ctx.page_sampling.load(Ordering::SeqCst);
+                // Holes are filled here whether they lie outside every range of the object or
+                // within one, so that they are always accounted for.
+                let Some((page, cow, filled)) =
+                    obj_page_tree.try_get_or_add_page(page_number, is_write, |pn| {
+                        paged_in.or_else(|| info.fill_page(pn))
+                    })
+                else {
+                    drop(obj_page_tree);
+                    drop(slot_mgr);
+                    return out_of_memory();
+                };
+                if filled {
+                    if info.committed.insert(page_number) {
+                        ctx.resident.fetch_add(1, Ordering::SeqCst);
+                    }
+                    first_touch = sample;
+                    if info.obj.is_zero_mapped() {
+                        // Other contexts, or this one, may map the zero page here. Drop those
+                        // mappings and let the access fault again to map the new page.
+                        let obj = info.obj.clone();
+                        drop(obj_page_tree);
+                        drop(slot_mgr);
+                        obj.invalidate_zero_mapped(page_number..page_number.next());
+                        return Ok(FaultOutcome::Mapped(
+                            first_touch.then(|| page_sample(id, page_number)),
+                        ));
+                    }
+                } else if is_cow && info.cow_committed.insert(page_number) {
+                    ctx.cow_pages.fetch_add(1, Ordering::SeqCst);
+                }
+                let mut pages = alloc::vec![(page, cow)];
+                if info.resident_cap.is_none() {
+                    pages.extend(info.fault_ahead(&obj_page_tree, page_number));
+                }
+                if is_exec {
+                    pages.iter_mut().for_each(|(_, wp)| *wp = true);
+                }
+                // TODO: select user context here.
+                let mapped = ctx.with_arch(KERNEL_SCTX, |arch| {
+                    info.map_pages(arch, page_number, &pages, is_kern_obj)
+                });
+                if !mapped {
+                    drop(obj_page_tree);
+                    drop(slot_mgr);
+                    return out_of_memory();
+                }
+                let over = info.note_faulted(page_number);
+                if !over.is_empty() {
+                    let arches = ctx.secctx.lock();
+                    for page in over {
+                        let start = (page - info.offset) * PageNumber::PAGE_SIZE;
+                        ctx.unmap_locked(info, &arches, start, start + PageNumber::PAGE_SIZE, None);
+                    }
+                }
+            }
+            drop(obj_page_tree);
+            drop(slot_mgr);
+            let observer = *ctx.fault_observer.lock();
+            if let Some(observer) = observer {
+                observer(id, page_number, cause);
+            }
+            Ok(FaultOutcome::Mapped(
+                first_touch.then(|| page_sample(id, page_number)),
+            ))
+        } else {
+            drop(slot_mgr);
+            FaultStats::record(&ctx.fault_stats.violations);
+            Ok(FaultOutcome::Violation(MemoryContextViolationInfo::new(
+                addr.raw(),
+                cause,
+            )))
+        }
+    }
+}
+
+/// The protection a mapping needs to allow an access.
+fn access_prot(kind: MemoryAccessKind) -> Protections {
+    match kind {
+        MemoryAccessKind::Read => Protections::READ,
+        MemoryAccessKind::Write => Protections::WRITE,
+        MemoryAccessKind::InstructionFetch => Protections::EXEC,
+    }
+}
+
+/// The number of pages of an object that are present in memory, whether or not they are mapped in
+/// any context. Returns 0 if the object does not exist. This only visits the pages that are
+/// present.
+pub fn object_resident_pages(obj: ObjID) -> usize {
+    match obj::lookup_object(obj, LookupFlags::ALLOW_DELETED) {
+        LookupResult::Found(obj) => obj.resident_pages(),
+        _ => 0,
+    }
+}
+
+/// Forcibly unmap an object from every context that maps it, e.g. before the object is destroyed.
+/// Every slot mapping the object is removed, no matter how many times it was inserted. Contexts
+/// that are being dropped concurrently are skipped, since dropping them removes their mappings.
+/// The kernel context is not drained, since kernel handles to the object may still be live.
+pub fn drain_object(obj: ObjID) {
+    let LookupResult::Found(object) = obj::lookup_object(obj, LookupFlags::ALLOW_DELETED) else {
+        return;
+    };
+    for ctx in object.mapping_contexts() {
+        ctx.remove_all_mappings(obj);
+    }
+}
+
+/// Find every mapping of the physical page containing phys, in the kernel context and all user
+/// contexts, as the context, the slot, and the page number within the slot. A reclaimer can use
+/// this to unmap a page from every context before reusing its frame. Contexts that are being
+/// dropped are skipped.
+pub fn contexts_mapping_page(phys: PhysAddr) -> Vec<(Arc<VirtContext>, Slot, PageNumber)> {
+    // Upgrade under the lock, but search without it, since dropping a context takes the lock.
+    let mut contexts: Vec<_> = CONTEXTS.lock().values().filter_map(Weak::upgrade).collect();
+    // The kernel context maps the pages of kernel object handles, but isn't registered.
+    contexts.push(kernel_context().clone());
+    let mut found = Vec::new();
+    for ctx in contexts {
+        for (slot, page) in ctx.slots_mapping_page(phys) {
+            found.push((ctx.clone(), slot, page));
+        }
+    }
+    found
+}
+
+fn page_sample(id: ObjID, page_number: PageNumber) -> PageSampleInfo {
+    PageSampleInfo::new(id, page_number.num())
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::sync::Arc;
+
+    use twizzler_abi::{
+        device::CacheType,
+        marker::BaseType,
+        object::{ObjID, Protections, MAX_SIZE},
+        upcall::{MemoryAccessKind, ObjectMemoryError, UpcallInfo},
+    };
+    use twizzler_kernel_macros::kernel_test;
+
+    use super::{
+        context_by_id, contexts_mapping_page, drain_object, handle_fault_in, object_resident_pages,
+        slot_of, EvictResult, FaultOutcome, MemErr, PageFaultError, PageFaultFlags, ProbeResult,
+        Slot, SlotError, VirtContext, MAX_SLOTS, WORKING_SET_SAMPLES,
+    };
+    use crate::{
+        arch::{address::VirtAddr, context::ArchContext},
+        idcounter::StableId,
+        memory::{
+            context::{
+                kernel_context, InsertError, KernelMemoryContext, KernelObjectHandle,
+                ObjectContextInfo, UserContext,
+            },
+            frame::frame_node,
+            pagetables::MappingFlags,
+        },
+        obj::{
+            copy::copy_ranges,
+            pages::{
+                refill_zero_pool, set_zero_pool_size, zero_pool_oom_hook, zero_pool_stats, Page,
+            },
+            InvalidateMode, Object, ObjectRef, PageNumber,
+        },
+        processor::current_processor,
+        security::KERNEL_SCTX,
+    };
+
+    struct Foo {
+        x: u32,
+    }
+
+    impl BaseType for Foo {
+        fn init<T>(_t: T) -> Self {
+            Foo { x: 0 }
+        }
+
+        fn tags() -> &'static [(
+            twizzler_abi::marker::BaseVersion,
+            twizzler_abi::marker::BaseTag,
+        )] {
+            todo!()
+        }
+    }
+
+    #[kernel_test]
+    fn test_kernel_object() {
+        let obj = crate::obj::Object::new();
+        let obj = Arc::new(obj);
+        crate::obj::register_object(obj.clone());
+
+        let ctx = kernel_context();
+        let mut handle = ctx.insert_kernel_object(ObjectContextInfo::new(
+            obj,
+            Protections::READ | Protections::WRITE,
+            twizzler_abi::device::CacheType::WriteBack,
+        ));
+
+        *handle.base_mut() = Foo { x: 42 };
+    }
+
+    fn create_blank_object() -> ObjectRef {
+        let obj = Arc::new(Object::new());
+        crate::obj::register_object(obj.clone());
+        obj
+    }
+
+    fn fault_error(upcalls: &[UpcallInfo]) -> Option<ObjectMemoryError> {
+        match upcalls {
+            [UpcallInfo::ObjectMemoryFault(info)] => Some(info.error),
+            _ => None,
+        }
+    }
+
+    /// Map a new blank object into a free slot of a new context, with the given protections.
+    fn mapped_slot(prot: Protections) -> (Arc<VirtContext>, Slot, ObjectRef) {
+        let ctx = VirtContext::new();
+        let slot = ctx.find_free_slot().unwrap();
+        let obj = create_blank_object();
+        let info = ObjectContextInfo::new(obj.clone(), prot, CacheType::WriteBack);
+        assert!(ctx.insert_object(slot, &info).is_ok());
+        (ctx, slot, obj)
+    }
+
+    /// Create a user context with one blank object mapped in two adjacent slots.
+    fn two_slots_same_object(prot: Protections) -> (Arc<VirtContext>, Slot, Slot, ObjectRef) {
+        let ctx = VirtContext::new();
+        let first = ctx.find_free_slots(2).unwrap();
+        let second = Slot::try_from(first.raw() + 1).unwrap();
+        let obj = create_blank_object();
+        for slot in [first, second] {
+            let info = ObjectContextInfo::new(obj.clone(), prot, CacheType::WriteBack);
+            assert!(ctx.insert_object(slot, &info).is_ok());
+        }
+        (ctx, first, second, obj)
+    }
+
+    /// The address of a page within a slot.
+    fn page_addr(slot: Slot, page: usize) -> VirtAddr {
+        slot.start_vaddr()
+            .offset(page * PageNumber::PAGE_SIZE)
+            .unwrap()
+    }
+
+    /// Inject a user fault, and check that it was resolved without sending any upcalls.
+    fn fault_ok(ctx: &VirtContext, addr: VirtAddr, kind: MemoryAccessKind) {
+        let upcalls = ctx.inject_fault(addr, kind, PageFaultFlags::USER).unwrap();
+        assert!(upcalls.is_empty());
+    }
+
+    #[kernel_test]
+    fn test_fault_stats() {
+        let ps = PageNumber::PAGE_SIZE;
+        let src = create_blank_object();
+        let dest = create_blank_object();
+        let ctx = kernel_context();
+        let prot = Protections::READ | Protections::WRITE;
+
+        let before = ctx.fault_stats();
+        let src_handle = ctx.insert_kernel_object::<u8>(ObjectContextInfo::new(
+            src.clone(),
+            prot,
+            CacheType::WriteBack,
+        ));
+        let sptr = src_handle
+            .start_addr()
+            .offset(ps)
+            .unwrap()
+            .as_mut_ptr::<u8>();
+        unsafe { sptr.write_volatile(42) };
+        let after_fill = ctx.fault_stats();
+        assert!(after_fill.zero_fill > before.zero_fill);
+
+        // Share the page with dest, so that reading it maps the existing page and writing to it
+        // breaks the sharing.
+        copy_ranges(&src, ps, &dest, ps, ps);
+        let dest_handle = ctx.insert_kernel_object::<u8>(ObjectContextInfo::new(
+            dest.clone(),
+            prot,
+            CacheType::WriteBack,
+        ));
+        let dptr = dest_handle
+            .start_addr()
+            .offset(ps)
+            .unwrap()
+            .as_mut_ptr::<u8>();
+        assert_eq!(unsafe { dptr.read_volatile() }, 42);
+        let after_read = ctx.fault_stats();
+        assert!(after_read.minor > after_fill.minor);
+
+        unsafe { dptr.write_volatile(43) };
+        let after_write = ctx.fault_stats();
+        assert!(after_write.cow > after_read.cow);
+        assert_eq!(unsafe { sptr.read_volatile() }, 42);
+    }
+
+    #[kernel_test]
+    fn test_spurious_fault() {
+        let (ctx, slot, _) = mapped_slot(Protections::READ | Protections::WRITE);
+        let addr = page_addr(slot, 1);
+        fault_ok(&ctx, addr, MemoryAccessKind::Write);
+        let before = ctx.fault_stats();
+        let resident = ctx.resident_pages();
+
+        // The page is already mapped writable, so faulting on it again maps nothing new.
+        fault_ok(&ctx, addr, MemoryAccessKind::Write);
+        let after = ctx.fault_stats();
+        assert_eq!(after.spurious, before.spurious + 1);
+        assert_eq!(after.zero_fill, before.zero_fill);
+        assert_eq!(after.minor, before.minor);
+        assert_eq!(ctx.resident_pages(), resident);
+    }
+
+    #[kernel_test]
+    fn test_probe() {
+        let ps = PageNumber::PAGE_SIZE;
+        let ctx = VirtContext::new();
+        let slot = ctx.find_free_slot().unwrap();
+        let read = MemoryAccessKind::Read;
+        let write = MemoryAccessKind::Write;
+        let base = slot.start_vaddr();
+        assert_eq!(
+            ctx.probe(base.offset(ps).unwrap(), read),
+            ProbeResult::Violation
+        );
+
+        let info = ObjectContextInfo::new(
+            create_blank_object(),
+            Protections::READ,
+            CacheType::WriteBack,
+        );
+        assert!(ctx.insert_object(slot, &info).is_ok());
+        let addr = base.offset(ps).unwrap();
+        assert_eq!(ctx.probe(base, read), ProbeResult::Violation);
+        assert_eq!(ctx.probe(addr, read), ProbeResult::WouldFaultMinor);
+        assert_eq!(ctx.probe(addr, write), ProbeResult::Violation);
+        let last = base.offset(MAX_SIZE - ps).unwrap();
+        assert_eq!(ctx.probe(last, read), ProbeResult::WouldFaultMinor);
+
+        // Probing doesn't fault the page in.
+        assert!(!ctx.is_resident(slot, PageNumber::from(1)));
+        fault_ok(&ctx, addr, read);
+        assert_eq!(ctx.probe(addr, read), ProbeResult::Ok);
+        assert_eq!(ctx.probe(addr, write), ProbeResult::Violation);
+
+        // Faults that would send an upcall are violations too: a page a user pager must supply,
+        // and a copy-on-write copy past the context's limit.
+        let (ctx, slot, obj) = mapped_slot(Protections::READ | Protections::WRITE);
+        assert!(ctx.set_user_paged(slot, true).is_ok());
+        assert_eq!(ctx.probe(page_addr(slot, 1), read), ProbeResult::Violation);
+        assert!(ctx.set_user_paged(slot, false).is_ok());
+        let src = create_blank_object();
+        src.add_page(PageNumber::from(1), Page::new());
+        copy_ranges(&src, ps, &obj, ps, ps);
+        ctx.set_cow_limit(Some(0));
+        assert_eq!(
+            ctx.probe(page_addr(slot, 1), read),
+            ProbeResult::WouldFaultMinor
+        );
+        assert_eq!(ctx.probe(page_addr(slot, 1), write), ProbeResult::Violation);
+        assert_eq!(
+            ctx.probe(page_addr(slot, 2), write),
+            ProbeResult::WouldFaultMinor
+        );
+    }
+
+    #[kernel_test]
+    fn test_null_guard_pages() {
+        let (ctx, slot, _) = mapped_slot(Protections::READ | Protections::WRITE);
+        assert_eq!(ctx.null_guard_pages(), 1);
+        let write = MemoryAccessKind::Write;
+        fault_ok(&ctx, page_addr(slot, 2), write);
+
+        // Growing the guard unmaps the page that is now part of it.
+        ctx.set_null_guard_pages(4);
+        assert!(!ctx.is_resident(slot, PageNumber::from(2)));
+        let upcalls = ctx
+            .inject_fault(page_addr(slot, 2), write, PageFaultFlags::USER)
+            .unwrap();
+        assert_eq!(
+            fault_error(&upcalls),
+            Some(ObjectMemoryError::NullPageAccess)
+        );
+        fault_ok(&ctx, page_addr(slot, 4), write);
+        assert_eq!(ctx.probe(page_addr(slot, 3), write), ProbeResult::Violation);
+
+        // New slots start after the guard too: a contiguous run is placed after it, and pages in
+        // the guard aren't prefaulted.
+        let prot = Protections::READ | Protections::WRITE;
+        let obj = create_blank_object();
+        let info =
+            ObjectContextInfo::new(obj.clone(), prot, CacheType::WriteBack).with_contiguous(2);
+        let dma = ctx.find_free_slot().unwrap();
+        assert!(ctx.insert_object(dma, &info).is_ok());
+        let base = ctx.dma_phys_base(dma).unwrap();
+        assert_eq!(
+            obj.lock_page_tree()
+                .try_get_page(PageNumber::from(4))
+                .map(|(page, _)| page.physical_address()),
+            Some(base)
+        );
+        fault_ok(&ctx, page_addr(dma, 4), write);
+        let obj = create_blank_object();
+        obj.add_page(PageNumber::from(2), Page::new());
+        obj.add_page(PageNumber::from(5), Page::new());
+        let info = ObjectContextInfo::new(obj, prot, CacheType::WriteBack).with_prefault(true);
+        assert!(ctx.swap_slot_object(slot, &info).is_ok());
+        assert!(!ctx.is_resident(slot, PageNumber::from(2)));
+        assert!(ctx.is_resident(slot, PageNumber::from(5)));
+
+        // The first page stays reserved.
+        ctx.set_null_guard_pages(0);
+        assert_eq!(ctx.null_guard_pages(), 1);
+    }
+
+    #[kernel_test]
+    fn test_write_protect_views() {
+        let ctx = VirtContext::new();
+        let rw = ctx.find_free_slots(2).unwrap();
+        let rx = Slot::try_from(rw.raw() + 1).unwrap();
+        let obj = create_blank_object();
+        let views = [
+            (rw, Protections::READ | Protections::WRITE),
+            (rx, Protections::READ | Protections::EXEC),
+        ];
+        for (slot, prot) in views {
+            let info = ObjectContextInfo::new(obj.clone(), prot, CacheType::WriteBack);
+            assert!(ctx.insert_object(slot, &info).is_ok());
+        }
+        fault_ok(&ctx, page_addr(rw, 1), MemoryAccessKind::Write);
+        fault_ok(&ctx, page_addr(rx, 1), MemoryAccessKind::InstructionFetch);
+
+        obj.invalidate(
+            PageNumber::from(1)..PageNumber::from(2),
+            InvalidateMode::WriteProtect,
+        );
+        assert_eq!(
+            ctx.effective_prot(page_addr(rw, 1)),
+            Some(Protections::READ)
+        );
+        assert_eq!(
+            ctx.effective_prot(page_addr(rx, 1)),
+            Some(Protections::READ | Protections::EXEC)
+        );
+
+        // Writing through the writable view restores its write access, and only its.
+        fault_ok(&ctx, page_addr(rw, 1), MemoryAccessKind::Write);
+        assert_eq!(
+            ctx.effective_prot(page_addr(rw, 1)),
+            Some(Protections::READ | Protections::WRITE)
+        );
+        assert_eq!(
+            ctx.effective_prot(page_addr(rx, 1)),
+            Some(Protections::READ | Protections::EXEC)
+        );
+    }
+
+    #[kernel_test]
+    fn test_drain_object() {
+        let prot = Protections::READ | Protections::WRITE;
+        let obj = create_blank_object();
+        let info = ObjectContextInfo::new(obj.clone(), prot, CacheType::WriteBack);
+        let first = VirtContext::new();
+        let second = VirtContext::new();
+        let slots = first.find_free_slots(2).unwrap();
+        let other = Slot::try_from(slots.raw() + 1).unwrap();
+        assert!(first.insert_object(slots, &info).is_ok());
+        assert!(first.insert_object(other, &info).is_ok());
+        // Even a mapping with several references is removed.
+        assert!(second.insert_object(slots, &info).is_ok());
+        assert!(second.insert_object(slots, &info).is_ok());
+        assert_eq!(obj.mapping_contexts().len(), 2);
+
+        // A context that is dropped before the drain is skipped.
+        let dropped = VirtContext::new();
+        assert!(dropped.insert_object(slots, &info).is_ok());
+        drop(dropped);
+
+        drain_object(obj.id());
+        assert!(first.lookup_object(slots).is_none());
+        assert!(first.lookup_object(other).is_none());
+        assert!(second.lookup_object(slots).is_none());
+        assert!(obj.mapping_contexts().is_empty());
+    }
+
+    #[kernel_test]
+    fn test_with_writable() {
+        let (ctx, slot, _) = mapped_slot(Protections::READ);
+        let addr = page_addr(slot, 1);
+        let write = |ctx: &VirtContext| {
+            ctx.inject_fault(addr, MemoryAccessKind::Write, PageFaultFlags::USER)
+                .unwrap()
+        };
+        assert!(matches!(
+            write(&ctx)[..],
+            [UpcallInfo::MemoryContextViolation(_)]
+        ));
+
+        let res = ctx.with_writable(slot, || {
+            assert!(write(&ctx).is_empty());
+            assert!(ctx
+                .with_writable(slot, || assert!(write(&ctx).is_empty()))
+                .is_ok());
+            // The outer call still holds the slot writable.
+            assert!(write(&ctx).is_empty());
+            ctx.effective_prot(addr)
+        });
+        assert!(res.is_ok_and(|prot| prot.is_some_and(|prot| prot.contains(Protections::WRITE))));
+        assert_eq!(ctx.effective_prot(addr), Some(Protections::READ));
+        assert!(matches!(
+            write(&ctx)[..],
+            [UpcallInfo::MemoryContextViolation(_)]
+        ));
+        assert_eq!(ctx.lookup_object(slot).unwrap().prot(), Protections::READ);
+    }
+
+    #[kernel_test]
+    fn test_with_writable_wx() {
+        let ctx = VirtContext::new_with_wx_enforce(true);
+        let slot = ctx.find_free_slot().unwrap();
+        let info = ObjectContextInfo::new(
+            create_blank_object(),
+            Protections::READ | Protections::EXEC,
+            CacheType::WriteBack,
+        );
+        assert!(ctx.insert_object(slot, &info).is_ok());
+
+        // Granting write access to an executable slot would make it W+X, so f is never run.
+        let res = ctx.with_writable(slot, || unreachable!());
+        assert!(matches!(res, Err(InsertError::WxViolation)));
+        let upcalls = ctx
+            .inject_fault(
+                page_addr(slot, 1),
+                MemoryAccessKind::Write,
+                PageFaultFlags::USER,
+            )
+            .unwrap();
+        assert!(matches!(
+            upcalls[..],
+            [UpcallInfo::MemoryContextViolation(_)]
+        ));
+    }
+
+    #[kernel_test]
+    fn test_fork() {
+        let ps = PageNumber::PAGE_SIZE;
+        let obj = create_blank_object();
+        let prot = Protections::READ | Protections::WRITE;
+        let kctx = kernel_context();
+
+        let parent = VirtContext::new();
+        let slot = parent.find_free_slot().unwrap();
+        let info = ObjectContextInfo::new(obj.clone(), prot, CacheType::WriteBack);
+        assert!(parent.insert_object(slot, &info).is_ok());
+
+        let handle = kctx.insert_kernel_object::<u8>(info);
+        let ptr = handle.start_addr().offset(ps).unwrap().as_mut_ptr::<u8>();
+        unsafe { ptr.write_volatile(42) };
+
+        let child = parent.fork().unwrap();
+        let child_obj = child.lookup_object(slot).unwrap().object().clone();
+        assert_ne!(child_obj.id(), obj.id());
+        let child_handle = kctx.insert_kernel_object::<u8>(ObjectContextInfo::new(
+            child_obj,
+            prot,
+            CacheType::WriteBack,
+        ));
+        let child_ptr = child_handle
+            .start_addr()
+            .offset(ps)
+            .unwrap()
+            .as_mut_ptr::<u8>();
+        assert_eq!(unsafe { child_ptr.read_volatile() }, 42);
+        unsafe { child_ptr.write_volatile(43) };
+        assert_eq!(unsafe { ptr.read_volatile() }, 42);
+
+        // The slots of a large object map the same copy at the same offsets, and settings made
+        // after inserting are kept.
+        let window = MAX_SIZE / ps;
+        let base = parent.find_free_slots(2).unwrap();
+        let second = Slot::try_from(base.raw() + 1).unwrap();
+        let large = ObjectContextInfo::new(create_blank_object(), prot, CacheType::WriteBack);
+        assert!(parent
+            .insert_large_object(base, &large, MAX_SIZE + 4 * ps)
+            .is_ok());
+        let addr = page_addr(second, 3);
+        assert_eq!(parent.write_bytes(addr, &[7]), Ok(1));
+        assert!(parent.set_user_paged(slot, true).is_ok());
+        let child = parent.fork().unwrap();
+        let first_copy = child.lookup_object(base).unwrap();
+        let second_copy = child.lookup_object(second).unwrap();
+        assert_eq!(first_copy.object().id(), second_copy.object().id());
+        assert_eq!(second_copy.offset(), PageNumber::from(window));
+        let mut buf = [0u8; 1];
+        assert_eq!(child.read_bytes(addr, &mut buf), Ok(1));
+        assert_eq!(buf, [7]);
+        assert_eq!(
+            child.probe(page_addr(slot, 2), MemoryAccessKind::Read),
+            ProbeResult::Violation
+        );
+    }
+
+    #[kernel_test]
+    fn test_insert_objects_rollback() {
+        let prot = Protections::READ | Protections::WRITE;
+        let ctx = VirtContext::new();
+        let first = ctx.find_free_slots(2).unwrap();
+        let second = Slot::try_from(first.raw() + 1).unwrap();
+        let existing = ObjectContextInfo::new(create_blank_object(), prot, CacheType::WriteBack);
+        assert!(ctx.insert_object(second, &existing).is_ok());
+
+        let items = [
+            (
+                first,
+                ObjectContextInfo::new(create_blank_object(), prot, CacheType::WriteBack),
+            ),
+            (
+                second,
+                ObjectContextInfo::new(create_blank_object(), prot, CacheType::WriteBack),
+            ),
+        ];
+        assert!(matches!(
+            ctx.insert_objects(&items),
+            Err((1, InsertError::Occupied(_)))
+        ));
+        assert!(ctx.lookup_object(first).is_none());
+        assert_eq!(
+            ctx.lookup_object(second).unwrap().object().id(),
+            existing.object().id()
+        );
+
+        ctx.remove_object(second);
+        assert!(ctx.insert_objects(&items).is_ok());
+        assert!(ctx.lookup_object(first).is_some());
+        assert!(ctx.lookup_object(second).is_some());
+    }
+
+    #[kernel_test]
+    fn test_insert_invalid_range() {
+        let ctx = VirtContext::new();
+        let slot = Slot::try_from(VirtAddr::start_kernel_object_memory()).unwrap();
+        let info = ObjectContextInfo::new(
+            create_blank_object(),
+            Protections::READ,
+            CacheType::WriteBack,
+        );
+        assert!(matches!(
+            ctx.insert_object(slot, &info),
+            Err(InsertError::InvalidRange)
+        ));
+    }
+
+    #[kernel_test]
+    fn test_wx_enforce() {
+        let ctx = VirtContext::new_with_wx_enforce(true);
+        let slot = ctx.find_free_slot().unwrap();
+        let obj = create_blank_object();
+        let wx = ObjectContextInfo::new(
+            obj.clone(),
+            Protections::READ | Protections::WRITE | Protections::EXEC,
+            CacheType::WriteBack,
+        );
+        assert!(matches!(
+            ctx.insert_object(slot, &wx),
+            Err(InsertError::WxViolation)
+        ));
+        let rw = ObjectContextInfo::new(
+            obj,
+            Protections::READ | Protections::WRITE,
+            CacheType::WriteBack,
+        );
+        assert!(ctx.insert_object(slot, &rw).is_ok());
+        assert!(matches!(
+            ctx.protect_slot(slot, wx.prot(), None),
+            Err(InsertError::WxViolation)
+        ));
+        assert!(ctx
+            .protect_slot(slot, Protections::READ | Protections::EXEC, None)
+            .is_ok());
+    }
+
+    #[kernel_test]
+    fn test_clear_user_mappings() {
+        let (ctx, first, second, _) = two_slots_same_object(Protections::READ | Protections::WRITE);
+        ctx.clear_user_mappings();
+        assert!(ctx.lookup_object(first).is_none());
+        assert!(ctx.lookup_object(second).is_none());
+        assert!(!ctx.is_resident(first, PageNumber::from(1)));
+    }
+
+    #[cfg(debug_assertions)]
+    #[kernel_test]
+    fn test_validate() {
+        let (ctx, first, second, _) = two_slots_same_object(Protections::READ | Protections::WRITE);
+        ctx.validate();
+        ctx.remove_object(first);
+        ctx.validate();
+        assert!(ctx.lookup_object(second).is_some());
+    }
+
+    #[kernel_test]
+    fn test_remove_one_of_two_slots() {
+        let (ctx, first, second, obj) =
+            two_slots_same_object(Protections::READ | Protections::WRITE);
+        fault_ok(&ctx, page_addr(second, 1), MemoryAccessKind::Write);
+        assert!(ctx.is_resident(second, PageNumber::from(1)));
+
+        // The remaining slot must still be found when the object is invalidated.
+        ctx.remove_object(first);
+        obj.invalidate(
+            PageNumber::from(1)..PageNumber::from(2),
+            InvalidateMode::Full,
+        );
+        assert!(!ctx.is_resident(second, PageNumber::from(1)));
+    }
+
+    #[kernel_test]
+    fn test_refcounted_insert() {
+        let prot = Protections::READ | Protections::WRITE;
+        let (ctx, slot, obj) = mapped_slot(prot);
+        let info = ObjectContextInfo::new(obj, prot, CacheType::WriteBack);
+        assert!(ctx.insert_object(slot, &info).is_ok());
+
+        // The first remove only drops a reference, leaving the mapping in place.
+        ctx.remove_object(slot);
+        assert!(ctx.lookup_object(slot).is_some());
+        fault_ok(&ctx, page_addr(slot, 1), MemoryAccessKind::Write);
+        assert!(ctx.is_resident(slot, PageNumber::from(1)));
+
+        ctx.remove_object(slot);
+        assert!(ctx.lookup_object(slot).is_none());
+        assert!(!ctx.is_resident(slot, PageNumber::from(1)));
+    }
+
+    #[kernel_test]
+    fn test_lookup_write_protected() {
+        let (ctx, slot, obj) = mapped_slot(Protections::READ | Protections::WRITE);
+        fault_ok(&ctx, page_addr(slot, 1), MemoryAccessKind::Write);
+        assert!(!ctx.lookup_object(slot).unwrap().write_protected());
+
+        // Invalidating a range that the slot doesn't map leaves it alone.
+        let end = PageNumber::from(MAX_SIZE / PageNumber::PAGE_SIZE);
+        obj.invalidate(end..end.offset(1), InvalidateMode::WriteProtect);
+        assert!(!ctx.lookup_object(slot).unwrap().write_protected());
+
+        obj.invalidate(
+            PageNumber::from(1)..PageNumber::from(2),
+            InvalidateMode::WriteProtect,
+        );
+        assert!(ctx.lookup_object(slot).unwrap().write_protected());
+    }
+
+    #[kernel_test]
+    fn test_flush_slot() {
+        let (ctx, slot, _) = mapped_slot(Protections::READ | Protections::WRITE);
+        // Flushing a slot with nothing in it does nothing.
+        let empty = ctx.find_free_slot().unwrap();
+        ctx.flush_slot(empty, PageNumber::from(0)..PageNumber::from(4));
+
+        let addr = page_addr(slot, 1);
+        fault_ok(&ctx, addr, MemoryAccessKind::Write);
+        let before = ctx.effective_prot(addr);
+
+        ctx.flush_slot(slot, PageNumber::from(0)..PageNumber::from(4));
+        assert!(ctx.is_resident(slot, PageNumber::from(1)));
+        assert_eq!(ctx.effective_prot(addr), before);
+    }
+
+    #[kernel_test]
+    fn test_invalidate_sparse() {
+        let (ctx, first, second, obj) =
+            two_slots_same_object(Protections::READ | Protections::WRITE);
+        fault_ok(&ctx, page_addr(second, 1), MemoryAccessKind::Write);
+
+        // The first slot has nothing mapped, but the page must still be unmapped from the second.
+        obj.invalidate(
+            PageNumber::from(0)..PageNumber::from(4),
+            InvalidateMode::Full,
+        );
+        assert!(!ctx.is_resident(second, PageNumber::from(1)));
+        assert!(!ctx.is_resident(first, PageNumber::from(1)));
+    }
+
+    #[kernel_test]
+    fn test_unsupported_cache() {
+        let prot = Protections::READ | Protections::WRITE;
+        let ctx = VirtContext::new();
+        let slot = ctx.find_free_slot().unwrap();
+        let obj = create_blank_object();
+        // Write-combining isn't set up on any architecture.
+        let info = ObjectContextInfo::new(obj.clone(), prot, CacheType::WriteCombining);
+        assert!(matches!(
+            ctx.insert_object(slot, &info),
+            Err(InsertError::UnsupportedCache)
+        ));
+        assert!(ctx.lookup_object(slot).is_none());
+
+        let info = ObjectContextInfo::new(obj, prot, CacheType::WriteBack);
+        assert!(ctx.insert_object(slot, &info).is_ok());
+        assert!(matches!(
+            ctx.set_cache_type(slot, CacheType::WriteCombining),
+            Err(InsertError::UnsupportedCache)
+        ));
+        assert_eq!(
+            ctx.lookup_object(slot).unwrap().cache(),
+            CacheType::WriteBack
+        );
+    }
+
+    #[kernel_test]
+    fn test_working_set() {
+        let ctx = kernel_context();
+        let (user, _, _) = mapped_slot(Protections::READ | Protections::WRITE);
+        assert_eq!(user.working_set(user.find_free_slot().unwrap()), 0);
+        assert_eq!(user.sample_working_set(user.find_free_slot().unwrap()), 0);
+
+        // Kernel object memory is mapped in every context, so the accesses really happen.
+        let handle = ctx.insert_kernel_object::<u8>(ObjectContextInfo::new(
+            create_blank_object(),
+            Protections::READ | Protections::WRITE,
+            CacheType::WriteBack,
+        ));
+        let slot = Slot::try_from(handle.start_addr()).unwrap();
+        for page in 1..3 {
+            let ptr = handle
+                .start_addr()
+                .offset(page * PageNumber::PAGE_SIZE)
+                .unwrap()
+                .as_mut_ptr::<u8>();
+            unsafe { ptr.write_volatile(1) };
+        }
+        // Reading the estimate doesn't take a sample, or clear the accessed state.
+        assert_eq!(ctx.working_set(slot), 0);
+        assert_eq!(ctx.working_set(slot), 0);
+        assert_eq!(ctx.sample_working_set(slot), 2);
+        assert_eq!(ctx.working_set(slot), 2);
+
+        // Nothing touches the pages again, so they age out once enough samples have passed.
+        for _ in 1..WORKING_SET_SAMPLES {
+            assert_eq!(ctx.sample_working_set(slot), 2);
+        }
+        assert_eq!(ctx.sample_working_set(slot), 0);
+        assert_eq!(ctx.working_set(slot), 0);
+        assert!(ctx.is_resident(slot, PageNumber::from(1)));
+    }
+
+    #[kernel_test]
+    fn test_resident_unmap() {
+        let prot = Protections::READ | Protections::WRITE;
+        let (ctx, slot, obj) = mapped_slot(prot);
+        let other = VirtContext::new();
+        let info = ObjectContextInfo::new(obj, prot, CacheType::WriteBack);
+        assert!(other.insert_object(slot, &info).is_ok());
+        fault_ok(&ctx, page_addr(slot, 1), MemoryAccessKind::Write);
+        fault_ok(&other, page_addr(slot, 1), MemoryAccessKind::Read);
+        fault_ok(&other, page_addr(slot, 2), MemoryAccessKind::Write);
+        fault_ok(&other, page_addr(slot, 3), MemoryAccessKind::Read);
+        assert_eq!(ctx.resident_pages(), 1);
+        assert_eq!(other.resident_pages(), 1);
+
+        // Unmapping a page that another context allocated, or the zero page, releases nothing.
+        other.unmap_range(slot, PageNumber::from(1)..PageNumber::from(2), None);
+        other.unmap_range(slot, PageNumber::from(3)..PageNumber::from(4), None);
+        assert_eq!(other.resident_pages(), 1);
+        other.unmap_range(slot, PageNumber::from(0)..PageNumber::from(4), None);
+        assert_eq!(other.resident_pages(), 0);
+        assert_eq!(ctx.resident_pages(), 1);
+    }
+
+    #[kernel_test]
+    fn test_page_table_bytes() {
+        let (ctx, slot, _) = mapped_slot(Protections::READ | Protections::WRITE);
+        // Nothing is mapped in the slot yet, so no tables were allocated for it.
+        let empty = ctx.page_table_bytes();
+        assert!(empty >= PageNumber::PAGE_SIZE);
+        fault_ok(&ctx, page_addr(slot, 1), MemoryAccessKind::Write);
+        assert!(ctx.page_table_bytes() > empty);
+
+        // Removing the only mapping frees the tables that were allocated for it.
+        ctx.remove_object(slot);
+        assert_eq!(ctx.page_table_bytes(), empty);
+    }
+
+    #[kernel_test]
+    fn test_prefault_window() {
+        let ctx = VirtContext::new();
+        let obj = create_blank_object();
+        for page in 1..6 {
+            obj.add_page(PageNumber::from(page), Page::new());
+        }
+        let prot = Protections::READ | Protections::WRITE;
+        let info = ObjectContextInfo::new(obj, prot, CacheType::WriteBack);
+        let sequential = ctx.find_free_slot().unwrap();
+        assert!(ctx.insert_object(sequential, &info).is_ok());
+        let random = ctx.find_free_slot().unwrap();
+        assert!(ctx.insert_object(random, &info).is_ok());
+        assert!(ctx.set_prefault_window(sequential, 2).is_ok());
+        assert!(ctx.set_prefault_window(random, 0).is_ok());
+
+        for slot in [sequential, random] {
+            fault_ok(&ctx, page_addr(slot, 1), MemoryAccessKind::Read);
+            assert!(ctx.is_resident(slot, PageNumber::from(1)));
+        }
+        assert!(ctx.is_resident(sequential, PageNumber::from(3)));
+        assert!(!ctx.is_resident(sequential, PageNumber::from(4)));
+        assert!(!ctx.is_resident(random, PageNumber::from(2)));
+    }
+
+    #[kernel_test]
+    fn test_exec_fault_read_only() {
+        let prot = Protections::READ | Protections::WRITE | Protections::EXEC;
+        let (ctx, slot, obj) = mapped_slot(prot);
+        obj.add_page(PageNumber::from(1), Page::new());
+        let addr = page_addr(slot, 1);
+
+        // An instruction fetch maps the page executable, but not writable.
+        fault_ok(&ctx, addr, MemoryAccessKind::InstructionFetch);
+        assert_eq!(
+            ctx.effective_prot(addr),
+            Some(Protections::READ | Protections::EXEC)
+        );
+        // A write then upgrades the mapping.
+        fault_ok(&ctx, addr, MemoryAccessKind::Write);
+        assert_eq!(ctx.effective_prot(addr), Some(prot));
+    }
+
+    #[kernel_test]
+    fn test_contexts_mapping_page() {
+        let obj = create_blank_object();
+        obj.add_page(PageNumber::from(1), Page::new());
+        let info = ObjectContextInfo::new(obj, Protections::READ, CacheType::WriteBack);
+        let first = VirtContext::new();
+        let second = VirtContext::new();
+        let slot = first.find_free_slot().unwrap();
+        for ctx in [&first, &second] {
+            assert!(ctx.insert_object(slot, &info).is_ok());
+        }
+        let addr = page_addr(slot, 1);
+        fault_ok(&first, addr, MemoryAccessKind::Read);
+        let phys = first.virt_to_phys(addr).unwrap();
+
+        // Only the context that has faulted the page in maps it.
+        let found = contexts_mapping_page(phys.offset(8usize).unwrap());
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].0.id().value(), first.id().value());
+        assert_eq!((found[0].1, found[0].2), (slot, PageNumber::from(1)));
+
+        fault_ok(&second, addr, MemoryAccessKind::Read);
+        assert_eq!(contexts_mapping_page(phys).len(), 2);
+        drop(second);
+        assert_eq!(contexts_mapping_page(phys).len(), 1);
+
+        // Pages mapped by kernel object handles are found in the kernel context.
+        let kctx = kernel_context();
+        let handle = kctx.insert_kernel_object::<u8>(ObjectContextInfo::new(
+            create_blank_object(),
+            Protections::READ | Protections::WRITE,
+            CacheType::WriteBack,
+        ));
+        let addr = handle.start_addr().offset(PageNumber::PAGE_SIZE).unwrap();
+        unsafe { addr.as_mut_ptr::<u8>().write_volatile(1) };
+        let found = contexts_mapping_page(kctx.virt_to_phys(addr).unwrap());
+        assert_eq!(found.len(), 1);
+        assert!(Arc::ptr_eq(&found[0].0, kctx));
+        assert_eq!(
+            (found[0].1, found[0].2),
+            (Slot::try_from(addr).unwrap(), PageNumber::from(1))
+        );
+    }
+
+    #[kernel_test]
+    fn test_slot_error() {
+        assert_eq!(
+            Slot::try_from(VirtAddr::end_kernel_object_memory()),
+            Err(SlotError::KernelAddress)
+        );
+        assert_eq!(Slot::try_from(usize::MAX), Err(SlotError::OutOfRange));
+        let slot = Slot::try_from(VirtAddr::start_user_memory()).unwrap();
+        assert_eq!(Slot::try_from(slot.raw()), Ok(slot));
+    }
+
+    #[kernel_test]
+    fn test_used_slots() {
+        let ctx = VirtContext::new();
+        assert_eq!(ctx.used_slots(), 0);
+        let last = Slot::try_from(MAX_SLOTS - 1).unwrap();
+        assert!(last
+            .start_vaddr()
+            .offset(MAX_SIZE - 1)
+            .is_ok_and(|end| !end.is_kernel()));
+        let info = ObjectContextInfo::new(
+            create_blank_object(),
+            Protections::READ,
+            CacheType::WriteBack,
+        );
+        for slot in [ctx.find_free_slot().unwrap(), last] {
+            assert!(ctx.insert_object(slot, &info).is_ok());
+        }
+        assert_eq!(ctx.used_slots(), 2);
+    }
+
+    #[kernel_test]
+    fn test_zero_pool() {
+        // The pool is global, so put its size back however the test ends.
+        struct RestorePool(usize);
+        impl Drop for RestorePool {
+            fn drop(&mut self) {
+                set_zero_pool_size(self.0);
+            }
+        }
+        let _restore = RestorePool(zero_pool_stats().capacity);
+        let (ctx, slot, obj) = mapped_slot(Protections::READ | Protections::WRITE);
+        let fault = |page: usize| fault_ok(&ctx, page_addr(slot, page), MemoryAccessKind::Write);
+
+        // A zero-fill fault takes its page from the pool when it has one.
+        set_zero_pool_size(2);
+        refill_zero_pool();
+        let before = zero_pool_stats();
+        assert!(before.pages > 0);
+        assert_eq!(before.capacity, 2);
+        fault(1);
+        assert!(zero_pool_stats().hits > before.hits);
+        let page = obj
+            .lock_page_tree()
+            .try_get_page(PageNumber::from(1))
+            .unwrap()
+            .0;
+        assert!(page.as_slice().iter().all(|b| *b == 0));
+
+        // With the pool disabled, pages are zeroed on the spot.
+        set_zero_pool_size(0);
+        let before = zero_pool_stats();
+        assert_eq!(before.pages, 0);
+        fault(2);
+        assert!(zero_pool_stats().misses > before.misses);
+
+        // Running out of memory gives the pooled pages back, but keeps the pool's size.
+        set_zero_pool_size(2);
+        refill_zero_pool();
+        assert!(zero_pool_stats().pages > 0);
+        assert!(zero_pool_oom_hook(obj.id(), PageNumber::from(1)));
+        assert_eq!(zero_pool_stats().pages, 0);
+        assert_eq!(zero_pool_stats().capacity, 2);
+        assert!(!zero_pool_oom_hook(obj.id(), PageNumber::from(1)));
+    }
+
+    #[kernel_test]
+    fn test_execute_only() {
+        let ctx = VirtContext::new();
+        let slot = ctx.find_free_slot().unwrap();
+        let info = ObjectContextInfo::new(
+            create_blank_object(),
+            Protections::EXEC,
+            CacheType::WriteBack,
+        );
+        if !ArchContext::supports_execute_only() {
+            assert!(matches!(
+                ctx.insert_object(slot, &info),
+                Err(InsertError::UnsupportedProt)
+            ));
+            return;
+        }
+        assert!(ctx.insert_object(slot, &info).is_ok());
+        let addr = page_addr(slot, 1);
+        let upcalls = ctx
+            .inject_fault(addr, MemoryAccessKind::Read, PageFaultFlags::USER)
+            .unwrap();
+        assert!(matches!(
+            upcalls[..],
+            [UpcallInfo::MemoryContextViolation(_)]
+        ));
+        fault_ok(&ctx, addr, MemoryAccessKind::InstructionFetch);
+        assert_eq!(ctx.effective_prot(addr), Some(Protections::EXEC));
+    }
+
+    #[kernel_test]
+    fn test_page_in() {
+        // A backing store that only has contents for odd pages.
+        fn page_in(_id: ObjID, page: PageNumber, new: &mut Page) -> bool {
+            if page.num() % 2 == 0 {
+                return false;
+            }
+            new.as_mut_slice()[0] = 42;
+            true
+        }
+
+        let (ctx, slot, obj) = mapped_slot(Protections::READ | Protections::WRITE);
+        obj.set_page_in(Some(page_in));
+        let read = MemoryAccessKind::Read;
+
+        fault_ok(&ctx, page_addr(slot, 1), read);
+        let mut buf = [0u8; 1];
+        assert_eq!(ctx.read_bytes(page_addr(slot, 1), &mut buf), Ok(1));
+        assert_eq!(buf[0], 42);
+
+        // The store has nothing for this page, so the read maps the zero page, leaving a hole.
+        fault_ok(&ctx, page_addr(slot, 2), read);
+        assert!(ctx.read_bytes(page_addr(slot, 2), &mut buf).is_err());
+
+        // A hole within a range is filled from the store too, even by a write.
+        obj.add_page(PageNumber::from(4), Page::new());
+        assert!(obj.lock_page_tree().get(PageNumber::from(3)).is_some());
+        fault_ok(&ctx, page_addr(slot, 3), MemoryAccessKind::Write);
+        assert_eq!(ctx.read_bytes(page_addr(slot, 3), &mut buf), Ok(1));
+        assert_eq!(buf[0], 42);
+    }
+
+    #[kernel_test]
+    fn test_fill_hole_in_range() {
+        let prot = Protections::READ | Protections::WRITE;
+        let (ctx, first, obj) = mapped_slot(prot);
+        let second = ctx.find_free_slot().unwrap();
+        let info = ObjectContextInfo::new(obj.clone(), prot, CacheType::WriteBack);
+        assert!(ctx.insert_object(second, &info).is_ok());
+        // Page 2 is a hole within the range holding pages 1 and 3.
+        obj.add_page(PageNumber::from(1), Page::new());
+        obj.add_page(PageNumber::from(3), Page::new());
+        let hole = PageNumber::from(2);
+        assert!(obj.lock_page_tree().get(hole).is_some());
+        assert!(obj.lock_page_tree().try_get_page(hole).is_none());
+
+        // Reads map the zero page in both slots.
+        for slot in [first, second] {
+            fault_ok(&ctx, page_addr(slot, 2), MemoryAccessKind::Read);
+        }
+        let resident = ctx.resident_pages();
+
+        // A write fills the hole, and drops the zero page mappings, including the other slot's.
+        fault_ok(&ctx, page_addr(first, 2), MemoryAccessKind::Write);
+        assert_eq!(ctx.resident_pages(), resident + 1);
+        assert!(obj.lock_page_tree().try_get_page(hole).is_some());
+        assert!(!ctx.is_resident(second, hole));
+        fault_ok(&ctx, page_addr(first, 2), MemoryAccessKind::Write);
+        fault_ok(&ctx, page_addr(second, 2), MemoryAccessKind::Read);
+        assert_eq!(
+            ctx.virt_to_phys(page_addr(first, 2)),
+            ctx.virt_to_phys(page_addr(second, 2))
+        );
+        assert_eq!(ctx.resident_pages(), resident + 1);
+    }
+
+    #[kernel_test]
+    fn test_kernel_write_to_hole() {
+        let (ctx, slot, obj) = mapped_slot(Protections::READ | Protections::WRITE);
+        let addr = page_addr(slot, 1).offset(8).unwrap();
+        fault_ok(&ctx, addr, MemoryAccessKind::Read);
+        assert!(ctx.is_resident(slot, PageNumber::from(1)));
+
+        // The kernel fills the hole, so the zero page mapping has to go, or the value would never
+        // be seen through the slot.
+        unsafe { obj.write_val_and_signal(PageNumber::PAGE_SIZE + 8, 42u64, 0) };
+        assert!(!ctx.is_resident(slot, PageNumber::from(1)));
+        fault_ok(&ctx, addr, MemoryAccessKind::Read);
+        let phys = ctx.virt_to_phys(addr).unwrap();
+        assert_eq!(
+            unsafe { phys.kernel_vaddr().as_ptr::<u64>().read_volatile() },
+            42
+        );
+    }
+
+    #[kernel_test]
+    fn test_lookup_mapping_flags() {
+        let ctx = VirtContext::new();
+        let slot = ctx.find_free_slot().unwrap();
+        let flags = MappingFlags::USER | MappingFlags::GLOBAL;
+        let info = ObjectContextInfo::new(
+            create_blank_object(),
+            Protections::READ,
+            CacheType::WriteBack,
+        )
+        .with_mapping_flags(flags)
+        .with_allow_global(true);
+        assert!(ctx.insert_object(slot, &info).is_ok());
+        assert_eq!(ctx.lookup_object(slot).unwrap().mapping_flags(), flags);
+    }
+
+    #[kernel_test]
+    fn test_object_resident_pages() {
+        let obj = create_blank_object();
+        assert_eq!(object_resident_pages(obj.id()), 0);
+        obj.add_page(PageNumber::from(1), Page::new());
+        obj.add_page(PageNumber::from(100), Page::new());
+        assert_eq!(object_resident_pages(obj.id()), 2);
+    }
+
+    #[kernel_test]
+    fn test_read_bytes() {
+        let ps = PageNumber::PAGE_SIZE;
+        let ctx = VirtContext::new();
+        let slot = ctx.find_free_slot().unwrap();
+        let obj = create_blank_object();
+        obj.add_page(PageNumber::from(1), Page::new());
+        obj.add_page(PageNumber::from(2), Page::new());
+        obj.lock_page_tree()
+            .get_page(PageNumber::from(2), false)
+            .unwrap()
+            .0
+            .as_mut_slice()[0] = 42;
+        let info = ObjectContextInfo::new(obj, Protections::READ, CacheType::WriteBack);
+        assert!(ctx.insert_object(slot, &info).is_ok());
+        let base = slot.start_vaddr();
+
+        // A read spanning two present pages.
+        let mut buf = [0xffu8; 16];
+        let addr = base.offset(2 * ps - 8).unwrap();
+        assert_eq!(ctx.read_bytes(addr, &mut buf), Ok(16));
+        assert_eq!(buf[8], 42);
+
+        // A read that runs into a page that isn't present stops there.
+        let addr = base.offset(3 * ps - 8).unwrap();
+        assert_eq!(ctx.read_bytes(addr, &mut buf), Ok(8));
+        let addr = base.offset(3 * ps).unwrap();
+        assert_eq!(ctx.read_bytes(addr, &mut buf), Err(MemErr::NotMapped));
+        // The null page is never readable.
+        assert_eq!(ctx.read_bytes(base, &mut buf), Err(MemErr::NotMapped));
+    }
+
+    #[kernel_test]
+    fn test_inject_fault() {
+        let ps = PageNumber::PAGE_SIZE;
+        let ctx = VirtContext::new();
+        let slots = ctx.find_free_slots(2).unwrap();
+        let slot = slots;
+        let empty = Slot::try_from(slots.raw() + 1).unwrap();
+        let obj = create_blank_object();
+        let info = ObjectContextInfo::new(
+            obj,
+            Protections::READ | Protections::WRITE,
+            CacheType::WriteBack,
+        )
+        .with_guard_pages(1);
+        assert!(ctx.insert_object(slot, &info).is_ok());
+        let base = slot.start_vaddr();
+        let read = MemoryAccessKind::Read;
+        let user = PageFaultFlags::USER;
+
+        let upcalls = ctx.inject_fault(base, read, user).unwrap();
+        assert_eq!(
+            fault_error(&upcalls),
+            Some(ObjectMemoryError::NullPageAccess)
+        );
+        let upcalls = ctx
+            .inject_fault(base.offset(ps).unwrap(), read, user)
+            .unwrap();
+        assert_eq!(
+            fault_error(&upcalls),
+            Some(ObjectMemoryError::GuardPageAccess)
+        );
+        let upcalls = ctx.inject_fault(empty.start_vaddr(), read, user).unwrap();
+        assert!(matches!(
+            upcalls[..],
+            [UpcallInfo::MemoryContextViolation(_)]
+        ));
+        assert_eq!(
+            ctx.inject_fault(base, read, PageFaultFlags::INVALID),
+            Err(PageFaultError::InvalidBits)
+        );
+
+        // A fault that maps a page sends no upcalls.
+        let addr = page_addr(slot, 2);
+        fault_ok(&ctx, addr, MemoryAccessKind::Write);
+        assert!(ctx.is_resident(slot, PageNumber::from(2)));
+
+        // Without a context, there's nothing to handle a user fault in.
+        let fault = handle_fault_in(None, addr.raw(), read, user, VirtAddr::new(0).unwrap());
+        assert_eq!(fault, Ok(FaultOutcome::NeedsContext));
+    }
+
+    #[kernel_test]
+    fn test_resolve_fault() {
+        let (ctx, slot, _obj) = mapped_slot(Protections::READ);
+        let read = MemoryAccessKind::Read;
+        let user = PageFaultFlags::USER;
+        let ip = VirtAddr::new(0).unwrap();
+        let before = ctx.fault_stats();
+
+        // A hole can be resolved by mapping, but deciding so maps nothing.
+        let addr = page_addr(slot, 2);
+        assert_eq!(
+            ctx.resolve_fault(addr, read, user, ip),
+            FaultOutcome::Mapped(None)
+        );
+        assert!(!ctx.is_resident(slot, PageNumber::from(2)));
+        assert_eq!(ctx.probe(addr, read), ProbeResult::WouldFaultMinor);
+
+        // Faults that can't be resolved give the outcome the handler would report.
+        let outcome = ctx.resolve_fault(slot.start_vaddr(), read, user, ip);
+        assert!(matches!(
+            outcome,
+            FaultOutcome::ObjectFault(info) if info.error == ObjectMemoryError::NullPageAccess
+        ));
+        assert!(matches!(
+            ctx.resolve_fault(addr, MemoryAccessKind::Write, user, ip),
+            FaultOutcome::Violation(_)
+        ));
+        let empty = ctx.find_free_slot().unwrap();
+        assert!(matches!(
+            ctx.resolve_fault(empty.start_vaddr(), read, user, ip),
+            FaultOutcome::Violation(_)
+        ));
+        assert_eq!(ctx.fault_stats(), before);
+
+        // Once the page is mapped, a fault on it is spurious.
+        fault_ok(&ctx, addr, read);
+        let after = ctx.fault_stats();
+        assert_eq!(
+            ctx.resolve_fault(addr, read, user, ip),
+            FaultOutcome::Mapped(None)
+        );
+        assert_eq!(ctx.fault_stats(), after);
+    }
+
+    #[kernel_test]
+    fn test_insert_large_object() {
+        let ps = PageNumber::PAGE_SIZE;
+        let window = MAX_SIZE / ps;
+        let ctx = VirtContext::new();
+        let base = ctx.find_free_slots(2).unwrap();
+        let second = Slot::try_from(base.raw() + 1).unwrap();
+        let obj = create_blank_object();
+        let info = ObjectContextInfo::new(
+            obj.clone(),
+            Protections::READ | Protections::WRITE,
+            CacheType::WriteBack,
+        );
+        assert!(ctx
+            .insert_large_object(base, &info, MAX_SIZE + 4 * ps)
+            .is_ok());
+        assert_eq!(
+            ctx.lookup_object(second).unwrap().offset(),
+            PageNumber::from(window)
+        );
+
+        // A fault in the second slot fills in the page at the corresponding offset in the object.
+        fault_ok(&ctx, page_addr(second, 3), MemoryAccessKind::Write);
+        assert!(obj
+            .lock_page_tree()
+            .try_get_page(PageNumber::from(window + 3))
+            .is_some());
+        // Past the end of the region is out of bounds.
+        let upcalls = ctx
+            .inject_fault(
+                page_addr(second, 4),
+                MemoryAccessKind::Write,
+                PageFaultFlags::USER,
+            )
+            .unwrap();
+        assert!(matches!(
+            fault_error(&upcalls),
+            Some(ObjectMemoryError::OutOfBounds(_))
+        ));
+
+        // Removing either slot removes both.
+        ctx.remove_object(second);
+        assert!(ctx.lookup_object(base).is_none());
+        assert!(ctx.lookup_object(second).is_none());
+
+        // A slot that has been given another object is no longer part of the stripe, so removing
+        // the stripe leaves it mapped.
+        assert!(ctx
+            .insert_large_object(base, &info, MAX_SIZE + 4 * ps)
+            .is_ok());
+        let other = create_blank_object();
+        let other_info = ObjectContextInfo::new(
+            other.clone(),
+            Protections::READ | Protections::WRITE,
+            CacheType::WriteBack,
+        );
+        assert_eq!(
+            ctx.swap_slot_object(second, &other_info).ok(),
+            Some(obj.id())
+        );
+        ctx.remove_object(base);
+        assert!(ctx.lookup_object(base).is_none());
+        assert_eq!(ctx.lookup_object(second).unwrap().object().id(), other.id());
+    }
+
+    #[kernel_test]
+    fn test_insert_anonymous() {
+        let ps = PageNumber::PAGE_SIZE;
+        let ctx = VirtContext::new();
+        let slot = ctx.find_free_slot().unwrap();
+        assert!(matches!(
+            ctx.insert_anonymous(slot, MAX_SIZE + ps, Protections::READ | Protections::WRITE),
+            Err(InsertError::InvalidRange)
+        ));
+        assert!(ctx
+            .insert_anonymous(slot, 4 * ps, Protections::READ | Protections::WRITE)
+            .is_ok());
+        let info = ctx.lookup_object(slot).unwrap();
+        let obj = Arc::downgrade(info.object());
+        drop(info);
+
+        fault_ok(&ctx, page_addr(slot, 1), MemoryAccessKind::Write);
+        assert!(ctx.is_resident(slot, PageNumber::from(1)));
+        let upcalls = ctx
+            .inject_fault(
+                page_addr(slot, 4),
+                MemoryAccessKind::Write,
+                PageFaultFlags::USER,
+            )
+            .unwrap();
+        assert!(matches!(
+            fault_error(&upcalls),
+            Some(ObjectMemoryError::OutOfBounds(_))
+        ));
+
+        // Only the mapping refers to the anonymous object, so removing it frees the object and its
+        // pages.
+        ctx.remove_object(slot);
+        assert!(obj.upgrade().is_none());
+    }
+
+    #[kernel_test]
+    fn test_object_len() {
+        let ps = PageNumber::PAGE_SIZE;
+        let ctx = VirtContext::new();
+        let slot = ctx.find_free_slot().unwrap();
+        let info = ObjectContextInfo::new(
+            create_blank_object(),
+            Protections::READ | Protections::WRITE,
+            CacheType::WriteBack,
+        )
+        .with_object_len(2 * ps + 1);
+        assert!(ctx.insert_object(slot, &info).is_ok());
+        assert_eq!(
+            ctx.lookup_object(slot).unwrap().object_len(),
+            Some(2 * ps + 1)
+        );
+
+        // The page holding the last byte of the object is accessible, but the next is not.
+        fault_ok(&ctx, page_addr(slot, 2), MemoryAccessKind::Write);
+        let upcalls = ctx
+            .inject_fault(
+                page_addr(slot, 3),
+                MemoryAccessKind::Read,
+                PageFaultFlags::USER,
+            )
+            .unwrap();
+        assert!(matches!(
+            fault_error(&upcalls),
+            Some(ObjectMemoryError::OutOfBounds(_))
+        ));
+    }
+
+    #[kernel_test]
+    fn test_lock_slot() {
+        let prot = Protections::READ | Protections::WRITE;
+        let (ctx, locked, _) = mapped_slot(prot);
+        let other = ctx.find_free_slot().unwrap();
+        let other_info = ObjectContextInfo::new(create_blank_object(), prot, CacheType::WriteBack);
+        assert!(ctx.insert_object(other, &other_info).is_ok());
+        assert!(ctx.lock_slot(locked).is_ok());
+
+        // A locked slot can still be faulted in.
+        fault_ok(&ctx, page_addr(locked, 1), MemoryAccessKind::Write);
+
+        ctx.remove_object(locked);
+        assert!(ctx.lookup_object(locked).is_some());
+        let to = ctx.find_free_slot().unwrap();
+        assert!(matches!(
+            ctx.remap_slot(locked, to),
+            Err(InsertError::Locked)
+        ));
+        ctx.clear_user_mappings();
+        assert!(ctx.lookup_object(other).is_none());
+        assert!(ctx.lookup_object(locked).is_some());
+        assert!(ctx.is_resident(locked, PageNumber::from(1)));
+
+        assert!(ctx.unlock_slot(locked).is_ok());
+        ctx.remove_object(locked);
+        assert!(ctx.lookup_object(locked).is_none());
+    }
+
+    #[kernel_test]
+    fn test_remap_slot() {
+        let prot = Protections::READ | Protections::WRITE;
+        let (ctx, slot, _) = mapped_slot(prot);
+        fault_ok(&ctx, page_addr(slot, 1), MemoryAccessKind::Write);
+        let kernel = Slot::try_from(VirtAddr::start_kernel_object_memory()).unwrap();
+        assert!(matches!(
+            ctx.remap_slot(slot, kernel),
+            Err(InsertError::InvalidRange)
+        ));
+
+        // A slot of a large object can't be moved on its own.
+        let base = ctx.find_free_slots(2).unwrap();
+        let second = Slot::try_from(base.raw() + 1).unwrap();
+        let info = ObjectContextInfo::new(create_blank_object(), prot, CacheType::WriteBack);
+        assert!(ctx.insert_large_object(base, &info, 2 * MAX_SIZE).is_ok());
+        let to = ctx.find_free_slot().unwrap();
+        assert!(matches!(
+            ctx.remap_slot(second, to),
+            Err(InsertError::InvalidRange)
+        ));
+        assert!(ctx.lookup_object(second).is_some());
+
+        assert!(ctx.remap_slot(slot, to).is_ok());
+        assert!(ctx.lookup_object(slot).is_none());
+        assert!(ctx.is_resident(to, PageNumber::from(1)));
+        assert!(!ctx.is_resident(slot, PageNumber::from(1)));
+    }
+
+    #[kernel_test]
+    fn test_compact_slots() {
+        let ctx = VirtContext::new();
+        let first = ctx.find_free_slot().unwrap();
+        let prot = Protections::READ | Protections::WRITE;
+        let slot = |n: usize| Slot::try_from(first.raw() + n).unwrap();
+        let mut ids = alloc::vec::Vec::new();
+        for n in [1, 3, 5] {
+            let obj = create_blank_object();
+            ids.push(obj.id());
+            let info = ObjectContextInfo::new(obj, prot, CacheType::WriteBack);
+            assert!(ctx.insert_object(slot(n), &info).is_ok());
+        }
+        assert!(ctx.lock_slot(slot(3)).is_ok());
+        fault_ok(&ctx, page_addr(slot(5), 1), MemoryAccessKind::Write);
 
-    fn insert_kernel_object<T>(&self, info: ObjectContextInfo) -> Self::Handle<T> {
-        let mut slots = self.slots.lock();
-        let mut kernel_slots_counter = KERNEL_SLOT_COUNTER.lock();
-        let slot = kernel_slots_counter
-            .kernel_slots_nums
-            .pop()
-            .unwrap_or_else(|| {
-                let cur = kernel_slots_counter.cur_kernel_slot;
-                kernel_slots_counter.cur_kernel_slot += 1;
-                let max = Slot::try_from(
-                    VirtAddr::end_kernel_object_memory()
-                        .offset(-1isize)
-                        .unwrap(),
-                )
-                .unwrap()
-                .raw();
-                if cur > max {
-                    panic!("out of kernel object slots");
-                }
-                Slot(cur)
-            });
-        let new_slot_info = VirtContextSlot {
-            obj: info.object().clone(),
-            slot,
-            prot: info.prot(),
-            cache: info.cache(),
-        };
-        slots.insert(slot, info.object().id(), new_slot_info);
-        KernelObjectVirtHandle {
-            info,
-            slot,
-            _pd: PhantomData,
+        // The locked slot stays put, and the others fill the free slots around it.
+        let moved = ctx.compact_slots();
+        assert_eq!(moved.len(), 2);
+        assert_eq!(moved.get(&slot(1)), Some(&slot(0)));
+        assert_eq!(moved.get(&slot(5)), Some(&slot(1)));
+        for (n, id) in [0, 3, 1].into_iter().zip(ids) {
+            assert_eq!(ctx.lookup_object(slot(n)).unwrap().object().id(), id);
         }
+        assert!(ctx.lookup_object(slot(5)).is_none());
+        assert!(ctx.is_resident(slot(1), PageNumber::from(1)));
+        assert!(ctx.compact_slots().is_empty());
     }
-}
 
-pub struct KernelObjectVirtHandle<T> {
-    info: ObjectContextInfo,
-    slot: Slot,
-    _pd: PhantomData<T>,
-}
+    #[kernel_test]
+    fn test_swap_slot_object() {
+        let ctx = VirtContext::new();
+        let slot = ctx.find_free_slot().unwrap();
+        let first = create_blank_object();
+        let second = create_blank_object();
+        second.add_page(PageNumber::from(1), Page::new());
+        let prot = Protections::READ | Protections::WRITE;
+        let info = ObjectContextInfo::new(first.clone(), prot, CacheType::WriteBack);
+        let new = ObjectContextInfo::new(second.clone(), prot, CacheType::WriteBack);
+        assert!(matches!(
+            ctx.swap_slot_object(slot, &new),
+            Err(InsertError::NotMapped)
+        ));
 
-impl<T> Clone for KernelObjectVirtHandle<T> {
-    fn clone(&self) -> Self {
-        Self {
-            info: self.info.clone(),
-            slot: self.slot,
-            _pd: PhantomData,
-        }
+        assert!(ctx.insert_object(slot, &info).is_ok());
+        assert_eq!(ctx.swap_slot_object(slot, &new).ok(), Some(first.id()));
+        assert_eq!(ctx.lookup_object(slot).unwrap().object().id(), second.id());
+        // The new object's present pages are mapped right away.
+        assert!(ctx.is_resident(slot, PageNumber::from(1)));
     }
-}
 
-impl<T> KernelObjectVirtHandle<T> {
-    pub fn start_addr(&self) -> VirtAddr {
-        VirtAddr::new(0)
-            .unwrap()
-            .offset(self.slot.raw() * MAX_SIZE)
+    #[kernel_test]
+    fn test_contiguous() {
+        let ctx = VirtContext::new();
+        let obj = create_blank_object();
+        let prot = Protections::READ | Protections::WRITE;
+        let info =
+            ObjectContextInfo::new(obj.clone(), prot, CacheType::WriteBack).with_contiguous(2);
+        let slot = ctx.find_free_slot().unwrap();
+        assert!(ctx.insert_object(slot, &info).is_ok());
+        let base = ctx.dma_phys_base(slot).unwrap();
+        let pages = obj
+            .lock_page_tree()
+            .present_pages(PageNumber::from(0)..PageNumber::from(MAX_SIZE / PageNumber::PAGE_SIZE))
+            .map(|(_, page, _)| page.physical_address())
+            .collect::<alloc::vec::Vec<_>>();
+        assert_eq!(pages, [base, base.offset(PageNumber::PAGE_SIZE).unwrap()]);
+
+        // The run is now present in the object, so another contiguous mapping of it would replace
+        // those pages. Both inserting and swapping it in fail, leaving everything as it was.
+        let other = ctx.find_free_slot().unwrap();
+        assert!(matches!(
+            ctx.insert_object(other, &info),
+            Err(InsertError::NoContiguousMemory)
+        ));
+        assert!(ctx.lookup_object(other).is_none());
+        let blank = create_blank_object();
+        let blank_info = ObjectContextInfo::new(blank.clone(), prot, CacheType::WriteBack);
+        assert!(ctx.insert_object(other, &blank_info).is_ok());
+        assert!(matches!(
+            ctx.swap_slot_object(other, &info),
+            Err(InsertError::NoContiguousMemory)
+        ));
+        assert_eq!(ctx.lookup_object(other).unwrap().object().id(), blank.id());
+        assert_eq!(ctx.dma_phys_base(slot), Some(base));
+        // The run starts after the null page.
+        assert_eq!(
+            obj.lock_page_tree()
+                .try_get_page(PageNumber::from(1))
+                .map(|(page, _)| page.physical_address()),
+            Some(base)
+        );
+    }
+
+    #[kernel_test]
+    fn test_user_paged() {
+        let (ctx, slot, obj) = mapped_slot(Protections::READ | Protections::WRITE);
+        assert!(ctx.set_user_paged(slot, true).is_ok());
+        let addr = page_addr(slot, 1);
+        let upcalls = ctx
+            .inject_fault(addr, MemoryAccessKind::Read, PageFaultFlags::USER)
+            .unwrap();
+        assert!(matches!(
+            fault_error(&upcalls),
+            Some(ObjectMemoryError::UserPagedRegion)
+        ));
+        assert!(!ctx.is_resident(slot, PageNumber::from(1)));
+
+        // The handler supplies the page, which is copied into the object and mapped.
+        let src = Page::new();
+        src.as_mut_slice()[0] = 7;
+        let resident = ctx.resident_pages();
+        assert!(ctx
+            .supply_page(slot, PageNumber::from(1), src.physical_address())
+            .is_ok());
+        assert_eq!(ctx.resident_pages(), resident + 1);
+        let page = obj
+            .lock_page_tree()
+            .try_get_page(PageNumber::from(1))
             .unwrap()
+            .0;
+        assert_eq!(page.as_slice()[0], 7);
+        assert_eq!(ctx.virt_to_phys(addr), Some(page.physical_address()));
+        fault_ok(&ctx, addr, MemoryAccessKind::Read);
+
+        // Supplying a page that is already present leaves it alone.
+        assert!(ctx
+            .supply_page(slot, PageNumber::from(1), src.physical_address())
+            .is_ok());
+        assert_eq!(ctx.resident_pages(), resident + 1);
+        // Pages past the end of the slot can't be supplied.
+        assert!(matches!(
+            ctx.supply_page(
+                slot,
+                PageNumber::from(MAX_SIZE / PageNumber::PAGE_SIZE),
+                src.physical_address()
+            ),
+            Err(InsertError::NotMapped)
+        ));
     }
 
-    pub fn id(&self) -> ObjID {
-        self.info.object().id()
+    #[kernel_test]
+    fn test_slot_of() {
+        let ctx = VirtContext::new();
+        let slot = ctx.find_free_slot().unwrap();
+        let addr = slot.start_vaddr().offset(0x1234).unwrap();
+        assert_eq!(slot_of(addr), Some((slot, 0x1234)));
+        assert_eq!(slot_of(VirtAddr::start_kernel_memory()), None);
     }
-}
 
-impl<T> Drop for KernelObjectVirtHandle<T> {
-    fn drop(&mut self) {
-        let kctx = kernel_context();
-        {
-            let mut slots = kctx.slots.lock();
-            // We don't need to tell the object that it's no longer mapped in the kernel context,
-            // since object invalidation always informs the kernel context.
-            slots.remove(self.slot);
-        }
-        kctx.with_arch(KERNEL_SCTX, |arch| {
-            arch.unmap(MappingCursor::new(self.start_addr(), MAX_SIZE));
+    #[kernel_test]
+    fn test_active_cpus() {
+        let first = VirtContext::new();
+        let second = VirtContext::new();
+        // Stay on this CPU while switching.
+        crate::interrupt::with_disabled(|| {
+            let cpu = current_processor().id;
+            first.switch_to(KERNEL_SCTX);
+            assert!(first.active_cpus().contains(cpu));
+            second.switch_to(KERNEL_SCTX);
+            assert!(!first.active_cpus().contains(cpu));
+            assert!(second.active_cpus().contains(cpu));
+            // Go back to the kernel context before the page tables of these contexts are freed.
+            kernel_context().switch_to(KERNEL_SCTX);
+            assert!(!second.active_cpus().contains(cpu));
         });
-        KERNEL_SLOT_COUNTER.lock().kernel_slots_nums.push(self.slot);
     }
-}
 
-impl<T> KernelObjectHandle<T> for KernelObjectVirtHandle<T> {
-    fn base(&self) -> &T {
-        unsafe {
-            self.start_addr()
-                .offset(NULLPAGE_SIZE)
-                .unwrap()
-                .as_ptr::<T>()
-                .as_ref()
-                .unwrap()
-        }
+    #[kernel_test]
+    fn test_context_by_id() {
+        let ctx = VirtContext::new();
+        let id = ctx.id().value();
+        assert!(Arc::ptr_eq(&context_by_id(id).unwrap(), &ctx));
+        drop(ctx);
+        assert!(context_by_id(id).is_none());
     }
 
-    fn base_mut(&mut self) -> &mut T {
-        unsafe {
-            self.start_addr()
-                .offset(NULLPAGE_SIZE)
-                .unwrap()
-                .as_mut_ptr::<T>()
-                .as_mut()
-                .unwrap()
+    #[kernel_test]
+    fn test_numa_node() {
+        let ctx = VirtContext::new();
+        let prot = Protections::READ | Protections::WRITE;
+        let node_of = |obj: &ObjectRef, page: usize| {
+            let (page, _) = obj
+                .lock_page_tree()
+                .try_get_page(PageNumber::from(page))
+                .unwrap();
+            frame_node(page.physical_address())
+        };
+        // All memory is attached to node 0, so a mapping that asks for it gets its pages from
+        // there, and one that asks for a node with no memory falls back instead of failing.
+        for node in [0, 1] {
+            let slot = ctx.find_free_slot().unwrap();
+            let obj = create_blank_object();
+            let info = ObjectContextInfo::new(obj.clone(), prot, CacheType::WriteBack)
+                .with_numa_node(Some(node));
+            assert!(ctx.insert_object(slot, &info).is_ok());
+            assert_eq!(ctx.lookup_object(slot).unwrap().numa_node(), Some(node));
+            fault_ok(&ctx, page_addr(slot, 1), MemoryAccessKind::Write);
+            assert_eq!(node_of(&obj, 1), Some(0));
         }
+        assert!(Page::new_on_node(0).is_some());
+        assert!(Page::new_on_node(1).is_none());
     }
 
-    fn lea_raw<R>(&self, iptr: *const R) -> Option<&R> {
-        let offset = iptr as usize;
-        let size = size_of::<R>();
-        if offset >= MAX_SIZE || offset.checked_add(size)? >= MAX_SIZE {
-            return None;
-        }
-        unsafe {
-            Some(
-                self.start_addr()
-                    .offset(offset)
-                    .unwrap()
-                    .as_ptr::<R>()
-                    .as_ref()
-                    .unwrap(),
-            )
-        }
-    }
+    #[kernel_test]
+    fn test_resident_cap() {
+        let ctx = VirtContext::new();
+        let slot = ctx.find_free_slot().unwrap();
+        assert!(matches!(
+            ctx.set_resident_cap(slot, 4),
+            Err(InsertError::NotMapped)
+        ));
+        let obj = create_blank_object();
+        let prot = Protections::READ | Protections::WRITE;
+        let info = ObjectContextInfo::new(obj.clone(), prot, CacheType::WriteBack);
+        assert!(ctx.insert_object(slot, &info).is_ok());
+        assert!(ctx.set_resident_cap(slot, 2).is_ok());
 
-    fn lea_raw_mut<R>(&self, iptr: *mut R) -> Option<&mut R> {
-        let offset = iptr as usize;
-        let size = size_of::<R>();
-        if offset >= MAX_SIZE || offset.checked_add(size)? >= MAX_SIZE {
-            return None;
-        }
-        unsafe {
-            Some(
-                self.start_addr()
-                    .offset(offset)
-                    .unwrap()
-                    .as_mut_ptr::<R>()
-                    .as_mut()
-                    .unwrap(),
-            )
+        // Faulting in a third page unmaps the oldest, which stays present in the object.
+        for page in 1..4 {
+            fault_ok(&ctx, page_addr(slot, page), MemoryAccessKind::Write);
         }
-    }
-}
-
-impl StableId for VirtContext {
-    fn id(&self) -> &Id<'_> {
-        &self.id
-    }
-}
+        assert!(!ctx.is_resident(slot, PageNumber::from(1)));
+        assert!(ctx.is_resident(slot, PageNumber::from(2)));
+        assert!(ctx.is_resident(slot, PageNumber::from(3)));
+        assert!(obj
+            .lock_page_tree()
+            .try_get_page(PageNumber::from(1))
+            .is_some());
 
-bitflags::bitflags! {
-    #[derive(Debug)]
-    pub struct PageFaultFlags : u32 {
-        const USER = 1;
-        const INVALID = 2;
-        const PRESENT = 4;
+        // Lowering the cap unmaps the oldest right away.
+        assert!(ctx.set_resident_cap(slot, 1).is_ok());
+        assert!(!ctx.is_resident(slot, PageNumber::from(2)));
+        assert!(ctx.is_resident(slot, PageNumber::from(3)));
+        assert!(ctx.set_resident_cap(slot, 0).is_ok());
     }
-}
 
-pub fn page_fault(addr: VirtAddr, cause: MemoryAccessKind, flags: PageFaultFlags, ip: VirtAddr) {
-    //logln!("page-fault: {:?} {:?} {:?} ip={:?}", addr, cause, flags, ip);
-    if flags.contains(PageFaultFlags::INVALID) {
-        panic!("page table contains invalid bits for address {:?}", addr);
-    }
-    if !flags.contains(PageFaultFlags::USER) && cause == MemoryAccessKind::InstructionFetch {
-        panic!(
-            "kernel page-fault at IP {:?} caused by {:?} to/from {:?} with flags {:?}",
-            ip, cause, addr, flags
+    #[kernel_test]
+    fn test_write_bytes() {
+        let ps = PageNumber::PAGE_SIZE;
+        let ctx = VirtContext::new();
+        let slots = ctx.find_free_slots(2).unwrap();
+        let src_slot = slots;
+        let dest_slot = Slot::try_from(slots.raw() + 1).unwrap();
+        let src = create_blank_object();
+        let dest = create_blank_object();
+        src.add_page(PageNumber::from(1), Page::new());
+        copy_ranges(&src, ps, &dest, ps, ps);
+        let src_info = ObjectContextInfo::new(src, Protections::READ, CacheType::WriteBack);
+        let dest_info = ObjectContextInfo::new(
+            dest,
+            Protections::READ | Protections::WRITE,
+            CacheType::WriteBack,
         );
+        assert!(ctx.insert_object(src_slot, &src_info).is_ok());
+        assert!(ctx.insert_object(dest_slot, &dest_info).is_ok());
+
+        let src_addr = src_slot.start_vaddr().offset(ps).unwrap();
+        let dest_addr = dest_slot.start_vaddr().offset(ps).unwrap();
+        assert_eq!(ctx.write_bytes(src_addr, &[1]), Err(MemErr::ReadOnly));
+        assert_eq!(ctx.write_bytes(dest_addr, &[1, 2, 3]), Ok(3));
+
+        // The write broke the sharing, so the source object is unchanged.
+        let mut buf = [0u8; 3];
+        assert_eq!(ctx.read_bytes(dest_addr, &mut buf), Ok(3));
+        assert_eq!(buf, [1, 2, 3]);
+        assert_eq!(ctx.read_bytes(src_addr, &mut buf), Ok(3));
+        assert_eq!(buf, [0, 0, 0]);
     }
-    if !flags.contains(PageFaultFlags::USER) && addr.is_kernel() && !addr.is_kernel_object_memory()
-    {
-        panic!(
-            "kernel page-fault at IP {:?} caused by {:?} to/from {:?} with flags {:?}",
-            ip, cause, addr, flags
-        );
-    } else {
-        if flags.contains(PageFaultFlags::USER) && addr.is_kernel() {
-            current_thread_ref()
-                .unwrap()
-                .send_upcall(UpcallInfo::MemoryContextViolation(
-                    MemoryContextViolationInfo::new(addr.raw(), cause),
-                ));
-            return;
-        }
 
-        let user_ctx = current_memory_context();
-        let (ctx, is_kern_obj) = if addr.is_kernel_object_memory() {
-            assert!(!flags.contains(PageFaultFlags::USER));
-            (kernel_context(), true)
-        } else {
-            (user_ctx.as_ref().unwrap_or_else(||
-            panic!("page fault in userland with no memory context at IP {:?} caused by {:?} to/from {:?} with flags {:?}, thread {}", ip, cause, addr, flags, current_thread_ref().map_or(0, |t| t.id()))), false)
-        };
-        let slot = match addr.try_into() {
-            Ok(s) => s,
-            Err(_) => {
-                current_thread_ref()
-                    .unwrap()
-                    .send_upcall(UpcallInfo::MemoryContextViolation(
-                        MemoryContextViolationInfo::new(addr.raw(), cause),
-                    ));
-                return;
-            }
-        };
+    #[kernel_test]
+    fn test_share_cow() {
+        let ps = PageNumber::PAGE_SIZE;
+        let ctx = VirtContext::new();
+        let other = VirtContext::new();
+        let slot = ctx.find_free_slot().unwrap();
+        let obj = create_blank_object();
+        obj.add_page(PageNumber::from(1), Page::new());
+        let info = ObjectContextInfo::new(
+            obj.clone(),
+            Protections::READ | Protections::WRITE,
+            CacheType::WriteBack,
+        );
+        assert!(matches!(
+            ctx.share_cow(&other, slot),
+            Err(InsertError::NotMapped)
+        ));
+        assert!(ctx.insert_object(slot, &info).is_ok());
+        let addr = slot.start_vaddr().offset(ps).unwrap();
+        assert_eq!(ctx.write_bytes(addr, &[7]), Ok(1));
 
-        let page_number = PageNumber::from_address(addr);
-        let slot_mgr = ctx.slots.lock();
-        if let Some(info) = slot_mgr.get(&slot) {
-            let id = info.obj.id();
-            let null_upcall = UpcallInfo::ObjectMemoryFault(ObjectMemoryFaultInfo::new(
-                id,
-                ObjectMemoryError::NullPageAccess,
-                cause,
-                addr.into(),
-            ));
+        assert!(ctx.share_cow(&other, slot).is_ok());
+        let shared = other.lookup_object(slot).unwrap();
+        assert_ne!(shared.object().id(), obj.id());
+        let mut buf = [0u8; 1];
+        assert_eq!(other.read_bytes(addr, &mut buf), Ok(1));
+        assert_eq!(buf, [7]);
 
-            let oob_upcall = UpcallInfo::ObjectMemoryFault(ObjectMemoryFaultInfo::new(
-                id,
-                ObjectMemoryError::OutOfBounds(page_number.as_byte_offset()),
-                cause,
-                addr.into(),
-            ));
+        // A write in either context diverges from the other.
+        assert_eq!(other.write_bytes(addr, &[8]), Ok(1));
+        assert_eq!(ctx.read_bytes(addr, &mut buf), Ok(1));
+        assert_eq!(buf, [7]);
+        assert!(matches!(
+            ctx.share_cow(&other, slot),
+            Err(InsertError::Occupied(_))
+        ));
 
-            let mut obj_page_tree = info.obj.lock_page_tree();
-            if page_number.is_zero() {
-                // drop these mutexes in case upcall sending generetes a page fault.
-                drop(obj_page_tree);
-                drop(slot_mgr);
-                current_thread_ref().unwrap().send_upcall(null_upcall);
-                return;
-            }
-            if page_number.as_byte_offset() >= MAX_SIZE {
-                // drop these mutexes in case upcall sending generetes a page fault.
-                drop(obj_page_tree);
-                drop(slot_mgr);
-                current_thread_ref().unwrap().send_upcall(oob_upcall);
-                return;
-            }
+        // A mapping at an offset shares the window of the object that it maps, and keeps its
+        // settings.
+        let window = ctx.find_free_slot().unwrap();
+        let info = info
+            .with_offset(PageNumber::from(4))
+            .with_object_len(16 * ps);
+        assert!(ctx.insert_object(window, &info).is_ok());
+        assert!(ctx.set_user_paged(window, true).is_ok());
+        obj.add_page(PageNumber::from(5), Page::new());
+        let addr = window.start_vaddr().offset(ps).unwrap();
+        assert_eq!(ctx.write_bytes(addr, &[9]), Ok(1));
+        assert!(ctx.share_cow(&other, window).is_ok());
+        let shared = other.lookup_object(window).unwrap();
+        assert_eq!(shared.offset(), PageNumber::from(4));
+        assert_eq!(shared.object_len(), Some(16 * ps));
+        assert_eq!(other.read_bytes(addr, &mut buf), Ok(1));
+        assert_eq!(buf, [9]);
+        let hole = window.start_vaddr().offset(2 * ps).unwrap();
+        assert_eq!(
+            other.probe(hole, MemoryAccessKind::Read),
+            ProbeResult::Violation
+        );
+    }
 
-            if let Some((page, cow)) =
-                obj_page_tree.get_page(page_number, cause == MemoryAccessKind::Write)
-            {
-                // TODO: select user context here.
-                ctx.with_arch(KERNEL_SCTX, |arch| {
-                    // TODO: don't need all three every time.
-                    arch.unmap(
-                        info.mapping_cursor(page_number.as_byte_offset(), PageNumber::PAGE_SIZE),
-                    );
-                    arch.map(
-                        info.mapping_cursor(page_number.as_byte_offset(), PageNumber::PAGE_SIZE),
-                        &mut info.phys_provider(&page),
-                        &info.mapping_settings(cow, is_kern_obj),
-                    );
-                    arch.change(
-                        info.mapping_cursor(page_number.as_byte_offset(), PageNumber::PAGE_SIZE),
-                        &info.mapping_settings(cow, is_kern_obj),
-                    );
-                });
-            } else {
-                let page = Page::new();
-                obj_page_tree.add_page(page_number, page);
-                let (page, cow) = obj_page_tree
-                    .get_page(page_number, cause == MemoryAccessKind::Write)
-                    .unwrap();
-                // TODO: select user context here.
-                ctx.with_arch(KERNEL_SCTX, |arch| {
-                    // TODO: don't need all three every time.
-                    arch.unmap(
-                        info.mapping_cursor(page_number.as_byte_offset(), PageNumber::PAGE_SIZE),
-                    );
-                    arch.map(
-                        info.mapping_cursor(page_number.as_byte_offset(), PageNumber::PAGE_SIZE),
-                        &mut info.phys_provider(&page),
-                        &info.mapping_settings(cow, is_kern_obj),
-                    );
-                    arch.change(
-                        info.mapping_cursor(page_number.as_byte_offset(), PageNumber::PAGE_SIZE),
-                        &info.mapping_settings(cow, is_kern_obj),
-                    );
-                });
-            }
-        } else {
-            drop(slot_mgr);
-            current_thread_ref()
-                .unwrap()
-                .send_upcall(UpcallInfo::MemoryContextViolation(
-                    MemoryContextViolationInfo::new(addr.raw(), cause),
-                ));
+    #[kernel_test]
+    fn test_pin_range() {
+        let (ctx, slot, obj) = mapped_slot(Protections::READ | Protections::WRITE);
+        let page = PageNumber::from(1);
+        let range = page..page.next();
+        let end = PageNumber::from(MAX_SIZE / PageNumber::PAGE_SIZE);
+        for bad in [PageNumber::from(0)..page, page..end.next()] {
+            assert!(matches!(
+                ctx.pin_range(slot, bad),
+                Err(InsertError::InvalidRange)
+            ));
         }
-    }
-}
 
-#[cfg(test)]
-mod test {
-    use alloc::sync::Arc;
+        // Pinning a hole that maps the zero page fills it, and drops the zero page mapping.
+        fault_ok(&ctx, page_addr(slot, 1), MemoryAccessKind::Read);
+        assert!(ctx.pin_range(slot, range.clone()).is_ok());
+        assert!(obj.lock_page_tree().try_get_page(page).is_some());
+        assert!(!ctx.is_resident(slot, page));
 
-    use twizzler_abi::{marker::BaseType, object::Protections};
-    use twizzler_kernel_macros::kernel_test;
+        // Pins nest, so the page stays pinned until every pin is released.
+        assert!(ctx.pin_range(slot, range.clone()).is_ok());
+        assert_eq!(ctx.evict_page(slot, page), EvictResult::Pinned);
+        assert!(ctx.unpin_range(slot, range.clone()).is_ok());
+        assert_eq!(ctx.evict_page(slot, page), EvictResult::Pinned);
+        assert!(ctx.unpin_range(slot, range).is_ok());
+        assert_ne!(ctx.evict_page(slot, page), EvictResult::Pinned);
+    }
 
-    use crate::memory::context::{
-        kernel_context, KernelMemoryContext, KernelObjectHandle, ObjectContextInfo,
-    };
+    #[kernel_test]
+    fn test_pin_range_cow() {
+        let ps = PageNumber::PAGE_SIZE;
+        let (ctx, slot, obj) = mapped_slot(Protections::READ | Protections::WRITE);
+        let src = create_blank_object();
+        src.add_page(PageNumber::from(1), Page::new());
+        copy_ranges(&src, ps, &obj, ps, ps);
+        let addr = page_addr(slot, 1);
+        fault_ok(&ctx, addr, MemoryAccessKind::Read);
+        let shared = ctx.virt_to_phys(addr).unwrap();
 
-    struct Foo {
-        x: u32,
+        // Pinning copies the shared page, and drops the mapping of the shared one.
+        let page = PageNumber::from(1);
+        assert!(ctx.pin_range(slot, page..page.next()).is_ok());
+        assert!(!ctx.is_resident(slot, page));
+        fault_ok(&ctx, addr, MemoryAccessKind::Read);
+        assert_ne!(ctx.virt_to_phys(addr), Some(shared));
+        assert!(ctx.unpin_range(slot, page..page.next()).is_ok());
     }
 
-    impl BaseType for Foo {
-        fn init<T>(_t: T) -> Self {
-            Foo { x: 0 }
+    #[kernel_test]
+    fn test_cow_pages_released() {
+        let ps = PageNumber::PAGE_SIZE;
+        let (ctx, slot, obj) = mapped_slot(Protections::READ | Protections::WRITE);
+        let src = create_blank_object();
+        src.add_page(PageNumber::from(1), Page::new());
+        copy_ranges(&src, ps, &obj, ps, ps);
+        for page in [2, 3, 1] {
+            fault_ok(&ctx, page_addr(slot, page), MemoryAccessKind::Write);
         }
+        assert_eq!(ctx.cow_pages(), 1);
+        let resident = ctx.resident_pages();
 
-        fn tags() -> &'static [(
-            twizzler_abi::marker::BaseVersion,
-            twizzler_abi::marker::BaseTag,
-        )] {
-            todo!()
-        }
+        // Unmapping just the copied page releases it, and not the pages that were zero-filled.
+        ctx.unmap_range(slot, PageNumber::from(1)..PageNumber::from(2), None);
+        assert_eq!(ctx.cow_pages(), 0);
+        assert_eq!(ctx.resident_pages(), resident);
+        ctx.unmap_range(slot, PageNumber::from(0)..PageNumber::from(4), None);
+        assert_eq!(ctx.resident_pages(), 0);
     }
 
     #[kernel_test]
-    fn test_kernel_object() {
-        let obj = crate::obj::Object::new();
-        let obj = Arc::new(obj);
-        crate::obj::register_object(obj.clone());
-
-        let ctx = kernel_context();
-        let mut handle = ctx.insert_kernel_object(ObjectContextInfo::new(
-            obj,
+    fn test_invalidate_flush() {
+        let ps = PageNumber::PAGE_SIZE;
+        let kctx = kernel_context();
+        let obj = create_blank_object();
+        let handle = kctx.insert_kernel_object::<u8>(ObjectContextInfo::new(
+            obj.clone(),
             Protections::READ | Protections::WRITE,
-            twizzler_abi::device::CacheType::WriteBack,
+            CacheType::Uncacheable,
         ));
-
-        *handle.base_mut() = Foo { x: 42 };
+        let slot = Slot::try_from(handle.start_addr()).unwrap();
+        let ptr = handle.start_addr().offset(ps).unwrap().as_mut_ptr::<u8>();
+        unsafe { ptr.write_volatile(42) };
+        obj.invalidate(
+            PageNumber::from(1)..PageNumber::from(2),
+            crate::obj::InvalidateMode::Flush,
+        );
+        // Flushing unmaps the page, like a full invalidation, and the write is in the page itself,
+        // as seen through another mapping of it, without faulting the page back in.
+        assert!(!kctx.is_resident(slot, PageNumber::from(1)));
+        let (page, _) = obj
+            .lock_page_tree()
+            .try_get_page(PageNumber::from(1))
+            .unwrap();
+        let phys = page.physical_address().kernel_vaddr();
+        assert_eq!(unsafe { phys.as_ptr::<u8>().read_volatile() }, 42);
+        assert!(!kctx.is_resident(slot, PageNumber::from(1)));
     }
 }
@@ -56,6 +56,8 @@ struct AllocationRegion {
     indexer: FrameIndexer,
     next_for_init: PhysAddr,
     pages: usize,
+    // The NUMA node that this region's memory is attached to.
+    node: u8,
     zeroed: LinkedList<FrameAdapter>,
     non_zeroed: LinkedList<FrameAdapter>,
 }
@@ -100,6 +102,75 @@ impl AllocationRegion {
         true
     }
 
+    /// Allocate a run of count physically contiguous frames, returning the first frame. The lowest
+    /// run of free frames that have already been admitted is used if there is one, and otherwise
+    /// the run is admitted from the part of the region that has not been admitted yet.
+    fn allocate_contiguous(&mut self, count: usize) -> Option<FrameRef> {
+        if count == 0 {
+            return None;
+        }
+        if let Some(first) = self.take_free_run(count) {
+            return Some(first);
+        }
+        let start = self.next_for_init;
+        let last = start.offset((count - 1).checked_mul(FRAME_SIZE)?).ok()?;
+        if !self.contains(start) || !self.contains(last) {
+            return None;
+        }
+        self.next_for_init = last.offset(FRAME_SIZE).unwrap();
+        for i in 0..count {
+            let pa = start.offset(i * FRAME_SIZE).unwrap();
+            // Unwrap-Ok: we checked that the whole run is in this region
+            // Safety: we are allocating new, untouched frames here
+            let frame = unsafe { self.get_frame_mut(pa) }.unwrap();
+            // Safety: the frames have not been admitted, so we are the only ones with access.
+            unsafe { frame.reset(pa) };
+            frame.set_admitted();
+            frame.set_allocated();
+        }
+        self.get_frame(start)
+    }
+
+    /// Find the lowest run of count free, admitted frames, and take each of them off the free list
+    /// it is on, marking it allocated. Returns the first frame of the run.
+    fn take_free_run(&mut self, count: usize) -> Option<FrameRef> {
+        let admitted = (self.next_for_init - self.indexer.start) / FRAME_SIZE;
+        let mut run = 0;
+        for i in 0..admitted {
+            // Unwrap-Ok: every frame below next_for_init is in this region
+            let frame = self
+                .get_frame(self.indexer.start.offset(i * FRAME_SIZE).unwrap())
+                .unwrap();
+            let flags = frame.get_flags();
+            if !flags.contains(PhysicalFrameFlags::ADMITTED)
+                || flags.contains(PhysicalFrameFlags::ALLOCATED)
+            {
+                run = 0;
+                continue;
+            }
+            run += 1;
+            if run < count {
+                continue;
+            }
+            let first = i + 1 - count;
+            for j in first..=i {
+                let frame = self
+                    .get_frame(self.indexer.start.offset(j * FRAME_SIZE).unwrap())
+                    .unwrap();
+                let list = if frame.is_zeroed() {
+                    &mut self.zeroed
+                } else {
+                    &mut self.non_zeroed
+                };
+                // Safety: free frames are always on the list matching their zeroed flag.
+                unsafe { list.cursor_mut_from_ptr(frame) }.remove();
+                frame.set_allocated();
+            }
+            return self.get_frame(self.indexer.start.offset(first * FRAME_SIZE).unwrap());
+        }
+        None
+    }
+
     fn free(&mut self, frame: FrameRef) {
         if !self.contains(frame.start_address()) {
             return;
@@ -172,6 +243,9 @@ impl AllocationRegion {
             },
             next_for_init: start.offset(array_pages * FRAME_SIZE).unwrap(),
             pages: nr_pages - array_pages,
+            // The boot info doesn't report memory affinity yet, so all memory is treated as local
+            // to node 0.
+            node: 0,
             zeroed: LinkedList::new(FrameAdapter::NEW),
             non_zeroed: LinkedList::new(FrameAdapter::NEW),
         };
@@ -212,8 +286,8 @@ impl core::fmt::Debug for Frame {
 }
 
 impl Frame {
-    // Safety: must only be called once, during admit_one, when the frame has not been initialized
-    // yet.
+    // Safety: must only be called once, during admit_one or allocate_contiguous, when the frame has
+    // not been initialized yet.
     unsafe fn reset(&mut self, pa: PhysAddr) {
         self.lock.store(0, Ordering::SeqCst);
         self.flags.store(0, Ordering::SeqCst);
@@ -398,7 +472,7 @@ impl PhysicalFrameAllocator {
 
     fn alloc(&mut self, flags: PhysicalFrameFlags, fallback: bool) -> Option<FrameRef> {
         let frame = if fallback {
-            Some(self.__do_alloc_fallback())
+            self.__do_alloc_fallback()
         } else {
             self.__do_alloc(flags)
         }?;
@@ -408,15 +482,15 @@ impl PhysicalFrameAllocator {
         Some(frame)
     }
 
-    fn __do_alloc_fallback(&mut self) -> FrameRef {
+    fn __do_alloc_fallback(&mut self) -> Option<FrameRef> {
         // fallback
         for reg in &mut self.regions {
             let frame = reg.allocate(true, false);
-            if let Some(frame) = frame {
+            if frame.is_some() {
                 return frame;
             }
         }
-        panic!("out of memory");
+        None
     }
 
     fn __do_alloc(&mut self, flags: PhysicalFrameFlags) -> Option<FrameRef> {
@@ -431,6 +505,24 @@ impl PhysicalFrameAllocator {
         None
     }
 
+    fn alloc_on_node(&mut self, node: u8, flags: PhysicalFrameFlags) -> Option<FrameRef> {
+        let frame = self
+            .regions
+            .iter_mut()
+            .filter(|reg| reg.node == node)
+            .find_map(|reg| reg.allocate(true, false))?;
+        if flags.contains(PhysicalFrameFlags::ZEROED) && !frame.is_zeroed() {
+            frame.zero();
+        }
+        Some(frame)
+    }
+
+    fn alloc_contiguous(&mut self, count: usize) -> Option<FrameRef> {
+        self.regions
+            .iter_mut()
+            .find_map(|reg| reg.allocate_contiguous(count))
+    }
+
     fn free(&mut self, frame: FrameRef) {
         for reg in &mut self.regions {
             if reg.contains(frame.start_address()) {
@@ -439,6 +531,13 @@ impl PhysicalFrameAllocator {
             }
         }
     }
+
+    fn node_of(&self, pa: PhysAddr) -> Option<u8> {
+        self.regions
+            .iter()
+            .find(|reg| reg.contains(pa))
+            .map(|reg| reg.node)
+    }
 }
 
 #[doc(hidden)]
@@ -543,11 +642,17 @@ pub fn init(regions: &[MemoryRegion]) {
 /// let zeroed_frame = alloc_frame(PhysicalFrameFlags::ZEROED);
 /// ```
 pub fn alloc_frame(flags: PhysicalFrameFlags) -> FrameRef {
+    try_alloc_frame(flags).expect("out of memory")
+}
+
+/// Try to allocate a physical frame. The flags argument is the same as in [alloc_frame]. Returns
+/// None if no physical frame is available.
+pub fn try_alloc_frame(flags: PhysicalFrameFlags) -> Option<FrameRef> {
     let mut frame = { PFA.wait().lock().alloc(flags, false) };
     if frame.is_none() {
         frame = PFA.wait().lock().alloc(flags, true);
     }
-    let frame = frame.expect("out of memory");
+    let frame = frame?;
     if flags.contains(PhysicalFrameFlags::ZEROED) {
         assert!(frame.is_zeroed());
     }
@@ -555,13 +660,37 @@ pub fn alloc_frame(flags: PhysicalFrameFlags) -> FrameRef {
     frame.set_not_zero();
     assert!(frame.get_flags().contains(PhysicalFrameFlags::ADMITTED));
     assert!(frame.get_flags().contains(PhysicalFrameFlags::ALLOCATED));
-    frame
+    Some(frame)
 }
 
-/// Try to allocate a physical frame. The flags argument is the same as in [alloc_frame]. Returns
-/// None if no physical frame is available.
-pub fn try_alloc_frame(flags: PhysicalFrameFlags) -> Option<FrameRef> {
-    Some(alloc_frame(flags))
+/// Try to allocate a physical frame from the memory attached to the given NUMA node. The flags
+/// argument is the same as in [alloc_frame]. Returns None if the node has no free memory (or does
+/// not exist); it is up to the caller whether to fall back to another node.
+pub fn try_alloc_frame_on_node(node: u8, flags: PhysicalFrameFlags) -> Option<FrameRef> {
+    let frame = PFA.wait().lock().alloc_on_node(node, flags)?;
+    frame.set_not_zero();
+    Some(frame)
+}
+
+/// Try to allocate a run of count physically contiguous frames, returning the first one. The rest
+/// of the run can be found with [get_frame]. The flags argument is the same as in [alloc_frame].
+/// Each frame in the run is allocated individually, and must be freed with [free_frame]. Returns
+/// None if no such run is available.
+///
+/// Runs of frames that have been freed are used before memory that has not been handed out by the
+/// allocator yet. Finding a free run means scanning the frames of a region, so this is meant for
+/// occasional allocations, such as DMA buffers.
+pub fn try_alloc_contiguous_frames(count: usize, flags: PhysicalFrameFlags) -> Option<FrameRef> {
+    let first = PFA.wait().lock().alloc_contiguous(count)?;
+    for i in 0..count {
+        // Unwrap-Ok: the run was allocated from a single region
+        let frame = get_frame(first.start_address().offset(i * FRAME_SIZE).unwrap()).unwrap();
+        if flags.contains(PhysicalFrameFlags::ZEROED) {
+            frame.zero();
+        }
+        frame.set_not_zero();
+    }
+    Some(first)
 }
 
 /// Free a physical frame.
@@ -574,6 +703,12 @@ pub fn free_frame(frame: FrameRef) {
     PFA.wait().lock().free(frame);
 }
 
+/// The NUMA node that the memory at a physical address is attached to, or None if the address is
+/// not managed by the frame allocator.
+pub fn frame_node(pa: PhysAddr) -> Option<u8> {
+    PFA.wait().lock().node_of(pa)
+}
+
 /// Get a FrameRef from a physical address.
 pub fn get_frame(pa: PhysAddr) -> Option<FrameRef> {
     let fi = FI.wait();
@@ -592,8 +727,10 @@ mod tests {
 
     use twizzler_kernel_macros::kernel_test;
 
-    use super::{alloc_frame, free_frame, get_frame, PhysicalFrameFlags};
-    use crate::utils::quick_random;
+    use super::{
+        alloc_frame, free_frame, get_frame, try_alloc_contiguous_frames, PhysicalFrameFlags,
+    };
+    use crate::{arch::memory::frame::FRAME_SIZE, utils::quick_random};
 
     #[kernel_test]
     fn test_get_frame() {
@@ -603,6 +740,35 @@ mod tests {
         assert!(core::ptr::eq(frame as *const _, test_frame as *const _));
     }
 
+    #[kernel_test]
+    fn test_contiguous_reuse() {
+        let run = |first: super::FrameRef| {
+            (0..4).map(move |i| {
+                get_frame(first.start_address().offset(i * FRAME_SIZE).unwrap()).unwrap()
+            })
+        };
+        let first = try_alloc_contiguous_frames(4, PhysicalFrameFlags::ZEROED).unwrap();
+        let addr = first.start_address();
+        for frame in run(first) {
+            assert!(frame.get_flags().contains(PhysicalFrameFlags::ALLOCATED));
+            free_frame(frame);
+        }
+        // The freed run is already admitted, so it (or a lower free run) is found on the free lists
+        // rather than carved out of memory that has not been admitted yet.
+        let again = try_alloc_contiguous_frames(4, PhysicalFrameFlags::ZEROED).unwrap();
+        assert!(again.start_address() <= addr);
+        for frame in run(again) {
+            assert!(frame.get_flags().contains(PhysicalFrameFlags::ALLOCATED));
+            // A frame in the run must not also be handed out by the regular allocator.
+            let other = alloc_frame(PhysicalFrameFlags::empty());
+            assert!(!core::ptr::eq(frame, other));
+            free_frame(other);
+        }
+        for frame in run(again) {
+            free_frame(frame);
+        }
+    }
+
     #[kernel_test]
     fn stress_test_pmm() {
         let mut stack = Vec::new();
@@ -30,6 +30,7 @@ pub fn init<B: BootInfo>(boot_info: &B) {
     kc.switch_to(KERNEL_SCTX);
     kc.init_allocator();
     allocator::init(kc);
+    context::virtmem::register_oom_hook(crate::obj::pages::zero_pool_oom_hook);
     // set flag to indicate that mm system is initalized
     MEM_INIT.store(true, Ordering::SeqCst);
 }
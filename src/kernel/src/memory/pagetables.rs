@@ -9,7 +9,7 @@ mod reader;
 mod settings;
 mod table;
 
-pub use consistency::DeferredUnmappingOps;
+pub use consistency::{DeferredUnmappingOps, InvalidationBatch};
 pub use cursor::MappingCursor;
 pub use mapper::Mapper;
 pub use phys_provider::{ContiguousProvider, PhysAddrProvider, ZeroPageProvider};
@@ -1,3 +1,5 @@
+use alloc::vec::Vec;
+
 use intrusive_collections::LinkedList;
 
 use crate::{
@@ -11,6 +13,7 @@ use crate::{
 /// Management for consistency, wrapping any cache-line flushing and TLB coherence into a single
 /// object.
 pub(super) struct Consistency {
+    target: PhysAddr,
     cl: ArchCacheLineMgr,
     tlb: ArchTlbMgr,
     pages: LinkedList<FrameAdapter>,
@@ -19,6 +22,7 @@ pub(super) struct Consistency {
 impl Consistency {
     pub(super) fn new(target: PhysAddr) -> Self {
         Self {
+            target,
             cl: ArchCacheLineMgr::default(),
             tlb: ArchTlbMgr::new(target),
             pages: LinkedList::new(FrameAdapter::NEW),
@@ -56,6 +60,56 @@ impl Consistency {
     }
 }
 
+/// A set of consistency operations accumulated over many page table updates, possibly to several
+/// sets of page tables, that are performed together when the batch is flushed. This allows a bulk
+/// operation to do a single, coalesced TLB shootdown per set of page tables instead of one per
+/// update. The batch is flushed when dropped, so no stale TLB entries can be left behind, but it
+/// must not be flushed (or dropped) while holding any page table locks.
+#[derive(Default)]
+pub struct InvalidationBatch {
+    pending: Vec<Consistency>,
+}
+
+impl InvalidationBatch {
+    /// Construct a new, empty [InvalidationBatch].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the consistency operations pending for the page tables rooted at target.
+    pub(super) fn consistency(&mut self, target: PhysAddr) -> &mut Consistency {
+        let idx = match self.pending.iter().position(|c| c.target == target) {
+            Some(idx) => idx,
+            None => {
+                self.pending.push(Consistency::new(target));
+                self.pending.len() - 1
+            }
+        };
+        &mut self.pending[idx]
+    }
+
+    /// Check if any page tables have been updated through this batch since it was last flushed.
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Perform all the queued TLB invalidations, and then free any page tables that were
+    /// reclaimed.
+    pub fn flush(&mut self) {
+        for consist in self.pending.drain(..) {
+            // Converting into the deferred ops drops the TLB manager, which performs the
+            // invalidations before the frames are freed.
+            consist.into_deferred().run_all();
+        }
+    }
+}
+
+impl Drop for InvalidationBatch {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
 pub struct DeferredUnmappingOps {
     pages: LinkedList<FrameAdapter>,
 }
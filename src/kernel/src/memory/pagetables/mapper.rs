@@ -1,9 +1,9 @@
 use super::{
-    consistency::{Consistency, DeferredUnmappingOps},
+    consistency::{Consistency, DeferredUnmappingOps, InvalidationBatch},
     MapInfo, MappingCursor, MappingSettings, PhysAddrProvider,
 };
 use crate::arch::{
-    address::PhysAddr,
+    address::{PhysAddr, VirtAddr},
     memory::pagetables::{Entry, Table},
 };
 
@@ -12,6 +12,8 @@ use crate::arch::{
 pub struct Mapper {
     root: PhysAddr,
     start_level: usize,
+    // Number of table frames below the root allocated through this mapper and not yet freed.
+    tables: usize,
 }
 
 impl Mapper {
@@ -20,6 +22,7 @@ impl Mapper {
         Self {
             root,
             start_level: Table::top_level(),
+            tables: 0,
         }
     }
 
@@ -68,17 +71,46 @@ impl Mapper {
         self.root
     }
 
+    /// Get the number of frames occupied by the page tables managed by this mapper, including the
+    /// root. Tables installed with [Self::set_top_level_table] are not counted, since they are
+    /// owned elsewhere.
+    pub fn table_frames(&self) -> usize {
+        self.tables + 1
+    }
+
     /// Map a set of physical pages into the tables with the provided settings.
+    ///
+    /// # Panic
+    /// Will panic if a page table can't be allocated. Use [Self::try_map] to handle that instead.
     pub fn map(
         &mut self,
         cursor: MappingCursor,
         phys: &mut impl PhysAddrProvider,
         settings: &MappingSettings,
     ) {
+        assert!(
+            self.try_map(cursor, phys, settings),
+            "out of memory for page tables"
+        );
+    }
+
+    /// Map a set of physical pages into the tables with the provided settings, like [Self::map].
+    /// Returns false if a page table could not be allocated, in which case the region may be only
+    /// partly mapped.
+    #[must_use]
+    pub fn try_map(
+        &mut self,
+        cursor: MappingCursor,
+        phys: &mut impl PhysAddrProvider,
+        settings: &MappingSettings,
+    ) -> bool {
         let mut consist = Consistency::new(self.root);
         let level = self.start_level;
         let root = self.root_mut();
-        root.map(&mut consist, cursor, level, phys, settings);
+        let mut allocated = 0;
+        let mapped = root.map(&mut consist, cursor, level, phys, settings, &mut allocated);
+        self.tables += allocated;
+        mapped
     }
 
     #[must_use]
@@ -88,10 +120,36 @@ impl Mapper {
         let mut consist = Consistency::new(self.root);
         let level = self.start_level;
         let root = self.root_mut();
-        root.unmap(&mut consist, cursor, level);
+        let freed = root.unmap(&mut consist, cursor, level);
+        // Tables that existed before this mapper was created (e.g. from boot) were never counted.
+        self.tables = self.tables.saturating_sub(freed);
         consist.into_deferred()
     }
 
+    /// Unmap a region from the page tables, queuing the consistency operations in batch instead of
+    /// performing them. The batch must be flushed AFTER unlocking any page table locks.
+    pub fn unmap_batched(&mut self, cursor: MappingCursor, batch: &mut InvalidationBatch) {
+        let consist = batch.consistency(self.root);
+        let level = self.start_level;
+        let root = self.root_mut();
+        let freed = root.unmap(consist, cursor, level);
+        // Tables that existed before this mapper was created (e.g. from boot) were never counted.
+        self.tables = self.tables.saturating_sub(freed);
+    }
+
+    /// Allocate all the page tables needed to map a region, without mapping any pages. Tables that
+    /// end up empty are still freed by a later unmap of the region. Returns false if a page table
+    /// could not be allocated, in which case only some of the tables may have been allocated.
+    #[must_use]
+    pub fn reserve(&mut self, cursor: MappingCursor) -> bool {
+        let level = self.start_level;
+        let root = self.root_mut();
+        let mut allocated = 0;
+        let reserved = root.reserve(cursor, level, &mut allocated);
+        self.tables += allocated;
+        reserved
+    }
+
     /// Change a region to use new mapping settings.
     pub fn change(&mut self, cursor: MappingCursor, settings: &MappingSettings) {
         let mut consist = Consistency::new(self.root);
@@ -100,6 +158,96 @@ impl Mapper {
         root.change(&mut consist, cursor, level, settings);
     }
 
+    /// Change a region to use new mapping settings, queuing the consistency operations in batch
+    /// instead of performing them.
+    pub fn change_batched(
+        &mut self,
+        cursor: MappingCursor,
+        settings: &MappingSettings,
+        batch: &mut InvalidationBatch,
+    ) {
+        let consist = batch.consistency(self.root);
+        let level = self.start_level;
+        let root = self.root_mut();
+        root.change(consist, cursor, level, settings);
+    }
+
+    /// Invalidate the TLB entries for the present mappings within a region, on all CPUs, without
+    /// changing the mappings. Used after the memory behind a mapping is modified through another
+    /// mapping.
+    pub fn flush(&self, cursor: MappingCursor) {
+        let mut consist = Consistency::new(self.root);
+        let level = self.start_level;
+        self.root().flush_tlb(&mut consist, cursor, level);
+    }
+
+    /// Find the dirty mappings within a region, calling `f` with the virtual address and length of
+    /// each. If `clear` is set, the dirty state of those mappings is also cleared.
+    pub fn collect_dirty(
+        &mut self,
+        cursor: MappingCursor,
+        clear: bool,
+        f: &mut impl FnMut(VirtAddr, usize),
+    ) {
+        let mut consist = Consistency::new(self.root);
+        let level = self.start_level;
+        let root = self.root_mut();
+        let reset = if clear {
+            Some(Entry::clear_dirty as fn(&mut Entry))
+        } else {
+            None
+        };
+        root.harvest(
+            &mut consist,
+            cursor,
+            level,
+            Entry::is_dirty,
+            reset,
+            &mut |vaddr, len, is_dirty| {
+                if is_dirty {
+                    f(vaddr, len)
+                }
+            },
+        );
+    }
+
+    /// Read the accessed state of the mappings within a region, calling `f` with the virtual
+    /// address and length of each mapping, and whether it has been accessed. If `clear` is set,
+    /// the accessed state of those mappings is also cleared.
+    pub fn harvest_accessed(
+        &mut self,
+        cursor: MappingCursor,
+        clear: bool,
+        f: &mut impl FnMut(VirtAddr, usize, bool),
+    ) {
+        let mut consist = Consistency::new(self.root);
+        let level = self.start_level;
+        let root = self.root_mut();
+        let reset = if clear {
+            Some(Entry::clear_accessed as fn(&mut Entry))
+        } else {
+            None
+        };
+        root.harvest(&mut consist, cursor, level, Entry::is_accessed, reset, f);
+    }
+
+    /// Mark the mappings within a region as accessed. The access flag is managed by software on
+    /// aarch64, so this is how an access flag fault is resolved.
+    #[cfg(target_arch = "aarch64")]
+    pub fn set_accessed(&mut self, cursor: MappingCursor) {
+        let mut consist = Consistency::new(self.root);
+        let level = self.start_level;
+        let root = self.root_mut();
+        root.harvest(
+            &mut consist,
+            cursor,
+            level,
+            |entry| !entry.is_accessed(),
+            Some(Entry::set_accessed),
+            &mut |_, _, _| {},
+        );
+    }
+
     /// Read the map of a single address (the start of the cursor). If there is a mapping at the
     /// specified location, return the mapping information. Otherwise, return Err with a length
     /// that specifies how much the cursor may advance before calling this function again to
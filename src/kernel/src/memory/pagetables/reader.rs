@@ -8,6 +8,9 @@ pub struct MapReader<'a> {
 }
 
 impl<'a> MapReader<'a> {
+    /// Merge runs of mappings that are contiguous in both virtual and physical memory. Mappings
+    /// are only merged if all their settings match, including the cache type, so that e.g. device
+    /// memory is never merged into a neighboring cacheable mapping.
     pub fn coalesce(self) -> MapCoalescer<'a> {
         MapCoalescer {
             reader: self,
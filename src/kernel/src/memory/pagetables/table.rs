@@ -5,7 +5,7 @@ use crate::{
         memory::pagetables::{Entry, EntryFlags, Table},
     },
     memory::{
-        frame::{alloc_frame, get_frame, FrameRef, PhysicalFrameFlags},
+        frame::{get_frame, try_alloc_frame, FrameRef, PhysicalFrameFlags},
         pagetables::MappingFlags,
     },
 };
@@ -53,13 +53,18 @@ impl Table {
             && phys_len >= page_size
     }
 
-    fn populate(&mut self, index: usize, flags: EntryFlags) {
+    /// Ensure the entry at index points to a table, allocating one if needed. Returns true if a
+    /// new table frame was allocated, or None if no frame was available for it.
+    fn populate(&mut self, index: usize, flags: EntryFlags) -> Option<bool> {
         let count = self.read_count();
         let entry = &mut self[index];
         if !entry.is_present() {
-            let frame = alloc_frame(PhysicalFrameFlags::ZEROED);
+            let frame = try_alloc_frame(PhysicalFrameFlags::ZEROED)?;
             *entry = Entry::new(frame.start_address(), flags);
             self.set_count(count + 1);
+            Some(true)
+        } else {
+            Some(false)
         }
     }
 
@@ -101,6 +106,9 @@ impl Table {
         }
     }
 
+    /// Map the region covered by the cursor, adding the number of table frames allocated to
+    /// allocated. Returns false if a table frame could not be allocated, in which case the rest of
+    /// the region is left unmapped.
     pub(super) fn map(
         &mut self,
         consist: &mut Consistency,
@@ -108,7 +116,8 @@ impl Table {
         level: usize,
         phys: &mut impl PhysAddrProvider,
         settings: &MappingSettings,
-    ) {
+        allocated: &mut usize,
+    ) -> bool {
         let start_index = Self::get_index(cursor.start(), level);
         for idx in start_index..Table::PAGE_TABLE_ENTRIES {
             let entry = &mut self[idx];
@@ -144,9 +153,21 @@ impl Table {
                 phys.consume(Self::level_to_page_size(level));
             } else {
                 assert_ne!(level, Self::last_level());
-                self.populate(idx, EntryFlags::intermediate());
+                let Some(new) = self.populate(idx, EntryFlags::intermediate()) else {
+                    return false;
+                };
+                *allocated += new as usize;
                 let next_table = self.next_table_mut(idx).unwrap();
-                next_table.map(consist, cursor, Self::next_level(level), phys, settings);
+                if !next_table.map(
+                    consist,
+                    cursor,
+                    Self::next_level(level),
+                    phys,
+                    settings,
+                    allocated,
+                ) {
+                    return false;
+                }
             }
 
             if let Some(next) = cursor.align_advance(Self::level_to_page_size(level)) {
@@ -155,14 +176,18 @@ impl Table {
                 break;
             }
         }
+        true
     }
 
+    /// Unmap the region covered by the cursor. Returns the number of table frames queued to be
+    /// freed.
     pub(super) fn unmap(
         &mut self,
         consist: &mut Consistency,
         mut cursor: MappingCursor,
         level: usize,
-    ) {
+    ) -> usize {
+        let mut freed = 0;
         let start_index = Self::get_index(cursor.start(), level);
         for idx in start_index..Table::PAGE_TABLE_ENTRIES {
             let entry = &mut self[idx];
@@ -178,10 +203,15 @@ impl Table {
                 );
             } else if entry.is_present() && level != Self::last_level() {
                 let next_table = self.next_table_mut(idx).unwrap();
-                next_table.unmap(consist, cursor, Self::next_level(level));
-                if next_table.read_count() == 0 && level != Table::top_level() {
+                freed += next_table.unmap(consist, cursor, Self::next_level(level));
+                // The top-level entries for kernel memory are shared by every context, so they must
+                // never be reclaimed, even if empty.
+                if next_table.read_count() == 0
+                    && (level != Table::top_level() || !cursor.start().is_kernel())
+                {
                     // Unwrap-Ok: The entry is present, and not a leaf, so it must be a table.
                     consist.free_frame(self.next_table_frame(idx).unwrap());
+                    freed += 1;
                     self.update_entry(
                         consist,
                         idx,
@@ -199,6 +229,45 @@ impl Table {
                 break;
             }
         }
+        freed
+    }
+
+    /// Allocate any missing page tables below this one that are needed to map the region covered
+    /// by the cursor, without mapping any pages. Regions covered by huge pages are skipped. Adds
+    /// the number of table frames allocated to allocated, and returns false if a table frame could
+    /// not be allocated.
+    pub(super) fn reserve(
+        &mut self,
+        mut cursor: MappingCursor,
+        level: usize,
+        allocated: &mut usize,
+    ) -> bool {
+        if level == Self::last_level() {
+            return true;
+        }
+        let start_index = Self::get_index(cursor.start(), level);
+        for idx in start_index..Table::PAGE_TABLE_ENTRIES {
+            let entry = self[idx];
+            let is_huge = entry.is_huge() && Self::can_map_at_level(level);
+            if !(entry.is_present() && is_huge) {
+                let Some(new) = self.populate(idx, EntryFlags::intermediate()) else {
+                    return false;
+                };
+                *allocated += new as usize;
+                // Unwrap-Ok: we just populated this entry with a table.
+                let next_table = self.next_table_mut(idx).unwrap();
+                if !next_table.reserve(cursor, Self::next_level(level), allocated) {
+                    return false;
+                }
+            }
+
+            if let Some(next) = cursor.align_advance(Self::level_to_page_size(level)) {
+                cursor = next;
+            } else {
+                break;
+            }
+        }
+        true
     }
 
     pub(super) fn change(
@@ -245,6 +314,78 @@ impl Table {
         }
     }
 
+    /// Walk the present leaf entries within the cursor, calling `f` with the virtual address and
+    /// size of each one, along with the result of `test` on the entry. If `reset` is provided, it
+    /// is applied to each entry for which `test` returned true, and the TLB is invalidated for
+    /// them.
+    pub(super) fn harvest(
+        &mut self,
+        consist: &mut Consistency,
+        mut cursor: MappingCursor,
+        level: usize,
+        test: fn(&Entry) -> bool,
+        reset: Option<fn(&mut Entry)>,
+        f: &mut impl FnMut(VirtAddr, usize, bool),
+    ) {
+        let start_index = Self::get_index(cursor.start(), level);
+        for idx in start_index..Table::PAGE_TABLE_ENTRIES {
+            let entry = self[idx];
+            let is_huge = entry.is_huge() && Self::can_map_at_level(level);
+
+            if entry.is_present() && (is_huge || level == Self::last_level()) {
+                let is_set = test(&entry);
+                f(cursor.start(), Self::level_to_page_size(level), is_set);
+                if let Some(reset) = reset.filter(|_| is_set) {
+                    let mut new_entry = entry;
+                    reset(&mut new_entry);
+                    self.update_entry(consist, idx, new_entry, cursor.start(), true, level);
+                }
+            } else if entry.is_present() && level != Self::last_level() {
+                let next_table = self.next_table_mut(idx).unwrap();
+                next_table.harvest(consist, cursor, Self::next_level(level), test, reset, f);
+            }
+
+            if let Some(next) = cursor.align_advance(Self::level_to_page_size(level)) {
+                cursor = next;
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Invalidate the TLB entries for the present leaf entries within the cursor, without changing
+    /// any entries.
+    pub(super) fn flush_tlb(
+        &self,
+        consist: &mut Consistency,
+        mut cursor: MappingCursor,
+        level: usize,
+    ) {
+        let start_index = Self::get_index(cursor.start(), level);
+        for idx in start_index..Table::PAGE_TABLE_ENTRIES {
+            let entry = self[idx];
+            let is_huge = entry.is_huge() && Self::can_map_at_level(level);
+
+            if entry.is_present() && (is_huge || level == Self::last_level()) {
+                let is_global = entry
+                    .flags()
+                    .settings()
+                    .flags()
+                    .contains(MappingFlags::GLOBAL);
+                consist.enqueue(cursor.start(), is_global, true, level);
+            } else if entry.is_present() && level != Self::last_level() {
+                let next_table = self.next_table(idx).unwrap();
+                next_table.flush_tlb(consist, cursor, Self::next_level(level));
+            }
+
+            if let Some(next) = cursor.align_advance(Self::level_to_page_size(level)) {
+                cursor = next;
+            } else {
+                break;
+            }
+        }
+    }
+
     pub(super) fn readmap(&self, cursor: &MappingCursor, level: usize) -> Result<MapInfo, usize> {
         let index = Self::get_index(cursor.start(), level);
         let entry = &self[index];
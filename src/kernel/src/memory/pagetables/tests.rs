@@ -5,10 +5,16 @@ mod test {
     use twizzler_kernel_macros::kernel_test;
 
     use crate::{
-        arch::{address::VirtAddr, memory::pagetables::Table},
+        arch::{
+            address::{PhysAddr, VirtAddr},
+            memory::pagetables::Table,
+        },
         memory::{
             frame::{alloc_frame, PhysicalFrameFlags},
-            pagetables::{phys_provider, Mapper, MappingCursor, MappingFlags, MappingSettings},
+            pagetables::{
+                phys_provider, ContiguousProvider, InvalidationBatch, Mapper, MappingCursor,
+                MappingFlags, MappingSettings,
+            },
         },
     };
 
@@ -102,4 +108,106 @@ mod test {
             test_mapper_at_level(i);
         }
     }
+
+    #[kernel_test]
+    fn test_unmap_reclaims_tables() {
+        let mut m = Mapper::new(alloc_frame(PhysicalFrameFlags::ZEROED).start_address());
+        let page_size = Table::level_to_page_size(Table::last_level());
+        let stride = Table::level_to_page_size(Table::top_level());
+        let settings = MappingSettings::new(
+            Protections::WRITE | Protections::READ,
+            CacheType::WriteBack,
+            MappingFlags::empty(),
+        );
+        // Map a single page under each of a number of top-level entries, so that each needs its
+        // own set of tables.
+        let cursors = (0..8).map(|i| {
+            MappingCursor::new(
+                VirtAddr::start_user_memory().offset(i * stride).unwrap(),
+                page_size,
+            )
+        });
+        for cur in cursors.clone() {
+            m.map(cur, &mut StaticProvider {}, &settings);
+        }
+        assert_eq!(m.root().read_count(), 8);
+
+        for cur in cursors {
+            m.unmap(cur).run_all();
+        }
+        assert_eq!(m.root().read_count(), 0);
+    }
+
+    #[kernel_test]
+    fn test_unmap_batched() {
+        let mut m = Mapper::new(alloc_frame(PhysicalFrameFlags::ZEROED).start_address());
+        let page_size = Table::level_to_page_size(Table::last_level());
+        let settings = MappingSettings::new(
+            Protections::WRITE | Protections::READ,
+            CacheType::WriteBack,
+            MappingFlags::empty(),
+        );
+        let cur = MappingCursor::new(VirtAddr::start_user_memory(), page_size * 4);
+        m.map(cur, &mut StaticProvider {}, &settings);
+
+        let mut batch = InvalidationBatch::new();
+        for i in 0..4 {
+            let page = MappingCursor::new(
+                VirtAddr::start_user_memory().offset(i * page_size).unwrap(),
+                page_size,
+            );
+            m.unmap_batched(page, &mut batch);
+        }
+        // The mappings and tables are gone right away, even though the flush is still pending.
+        assert!(m.readmap(cur).next().is_none());
+        assert_eq!(m.root().read_count(), 0);
+        assert!(!batch.is_empty());
+        batch.flush();
+        assert!(batch.is_empty());
+    }
+
+    #[kernel_test]
+    fn test_coalesce_cache_types() {
+        let mut m = Mapper::new(alloc_frame(PhysicalFrameFlags::ZEROED).start_address());
+        let page_size = Table::level_to_page_size(Table::last_level());
+        let prot = Protections::WRITE | Protections::READ;
+        let wb = MappingSettings::new(prot, CacheType::WriteBack, MappingFlags::empty());
+        let uc = MappingSettings::new(prot, CacheType::Uncacheable, MappingFlags::empty());
+        // Four pages, contiguous in both virtual and physical memory, where the cache type changes
+        // in the middle.
+        let vbase = VirtAddr::start_user_memory();
+        let pbase = PhysAddr::new(0x100000).unwrap();
+        let runs = [(0, &wb), (1, &wb), (2, &uc), (3, &uc)];
+        for (i, settings) in runs {
+            let cur = MappingCursor::new(vbase.offset(i * page_size).unwrap(), page_size);
+            let mut phys = ContiguousProvider::new(pbase.offset(i * page_size).unwrap(), page_size);
+            m.map(cur, &mut phys, settings);
+        }
+
+        let cur = MappingCursor::new(vbase, page_size * 4);
+        let maps: alloc::vec::Vec<_> = m.readmap(cur).coalesce().collect();
+        assert_eq!(maps.len(), 2);
+        assert_eq!(maps[0].vaddr(), vbase);
+        assert_eq!(maps[0].len(), page_size * 2);
+        assert_eq!(maps[0].settings().cache(), CacheType::WriteBack);
+        assert_eq!(maps[1].vaddr(), vbase.offset(page_size * 2).unwrap());
+        assert_eq!(maps[1].len(), page_size * 2);
+        assert_eq!(maps[1].settings().cache(), CacheType::Uncacheable);
+        m.unmap(cur).run_all();
+    }
+
+    #[kernel_test]
+    fn test_reserve_tables() {
+        let mut m = Mapper::new(alloc_frame(PhysicalFrameFlags::ZEROED).start_address());
+        let page_size = Table::level_to_page_size(Table::last_level());
+        let cur = MappingCursor::new(VirtAddr::start_user_memory(), page_size * 4);
+        assert!(m.reserve(cur));
+        assert_eq!(m.root().read_count(), 1);
+        // Reserving tables doesn't map anything.
+        assert!(m.readmap(cur).next().is_none());
+
+        // Unmapping the region frees the reserved tables, even though nothing was mapped.
+        m.unmap(cur).run_all();
+        assert_eq!(m.root().read_count(), 0);
+    }
 }
@@ -29,6 +29,20 @@ pub mod range;
 pub mod thread_sync;
 
 const OBJ_DELETED: u32 = 1;
+const OBJ_ZERO_MAPPED: u32 = 2;
+
+/// A callback that reads a page of an object from its backing store, called before a hole in the
+/// object is filled. It is given a new, zeroed page that the kernel allocated for the hole, and
+/// copies the stored contents into it, returning true. If the store has no contents for that page,
+/// it returns false and leaves the page untouched, in which case the hole is zero-filled as usual.
+///
+/// The callback is called with the object's page tree, and the faulting context's slots, locked. It
+/// must not access the object's pages itself, and it must not block. A store that has to wait for
+/// its contents should not be attached with [Object::set_page_in]. Instead, it should map the
+/// object in user-paged slots (see [crate::memory::context::virtmem::VirtContext::set_user_paged]):
+/// a fault on a hole there drops every lock before sending an upcall to the store's handler, which
+/// may block, and then supplies the page, after which the faulting access is retried.
+pub type PageInFn = fn(ObjID, PageNumber, &mut Page) -> bool;
 pub struct Object {
     id: ObjID,
     flags: AtomicU32,
@@ -36,6 +50,7 @@ pub struct Object {
     sleep_info: Mutex<SleepInfo>,
     pin_info: Mutex<PinInfo>,
     contexts: Mutex<ContextInfo>,
+    page_in: Mutex<Option<PageInFn>>,
 }
 
 #[derive(Default)]
@@ -147,6 +162,44 @@ impl Object {
         self.flags.fetch_or(OBJ_DELETED, Ordering::SeqCst);
     }
 
+    /// Set the callback used to fill holes in this object from a backing store when they are
+    /// faulted in, replacing any existing one. With None, holes are zero-filled.
+    pub fn set_page_in(&self, page_in: Option<PageInFn>) {
+        *self.page_in.lock() = page_in;
+    }
+
+    /// The callback used to fill holes in this object, if it has a backing store.
+    pub fn page_in(&self) -> Option<PageInFn> {
+        *self.page_in.lock()
+    }
+
+    /// Read a page of this object from its backing store into page, a new page for the hole at
+    /// page pn. Returns false if the object has no backing store, or the store has no contents for
+    /// the page.
+    pub fn read_backing_page(&self, pn: PageNumber, page: &mut Page) -> bool {
+        self.page_in()
+            .is_some_and(|page_in| page_in(self.id, pn, page))
+    }
+
+    /// Record that some context has mapped the shared zero page in place of a hole in this object.
+    pub fn mark_zero_mapped(&self) {
+        self.flags.fetch_or(OBJ_ZERO_MAPPED, Ordering::SeqCst);
+    }
+
+    /// Has any context ever mapped the shared zero page for this object?
+    pub fn is_zero_mapped(&self) -> bool {
+        self.flags.load(Ordering::SeqCst) & OBJ_ZERO_MAPPED != 0
+    }
+
+    /// Called after holes in a range of this object have been filled. Contexts may still map the
+    /// shared zero page there, so have them drop their mappings of the range. Must not be called
+    /// with the page tree locked.
+    pub fn invalidate_zero_mapped(&self, range: core::ops::Range<PageNumber>) {
+        if self.is_zero_mapped() {
+            self.invalidate(range, InvalidateMode::Full);
+        }
+    }
+
     pub fn lock_page_tree(&self) -> LockGuard<'_, range::PageRangeTree> {
         self.range_tree.lock()
     }
@@ -154,12 +207,22 @@ impl Object {
     pub fn add_page(&self, pn: PageNumber, page: pages::Page) {
         let mut range_tree = self.range_tree.lock();
         range_tree.add_page(pn, page);
+        drop(range_tree);
+        self.invalidate_zero_mapped(pn..pn.next());
     }
 
     pub fn id(&self) -> ObjID {
         self.id
     }
 
+    /// The number of pages of the object that are present in memory. Pages that are shared
+    /// copy-on-write with another object are counted for both.
+    pub fn resident_pages(&self) -> usize {
+        self.lock_page_tree()
+            .present_pages(0.into()..usize::MAX.into())
+            .count()
+    }
+
     pub fn release_pin(&self, _pin: u32) {
         // TODO: Currently we don't track pins. This will be changed in-future when we fully
         // implement eviction.
@@ -171,16 +234,23 @@ impl Object {
         let mut pin_info = self.pin_info.lock();
 
         let mut v = Vec::new();
+        let mut filled = false;
         for i in 0..len {
             // TODO: we'll need to handle failures here when we expand the paging system.
-            let p = tree.get_page(start.offset(i), true);
-            if let Some(p) = p {
-                v.push(p.0.physical_address());
-            } else {
-                let page = Page::new();
-                v.push(page.physical_address());
-                tree.add_page(start.offset(i), page);
-            }
+            let (page, _, hole) = tree
+                .try_get_or_add_page(start.offset(i), true, |pn| {
+                    let mut page = Page::new();
+                    self.read_backing_page(pn, &mut page);
+                    Some(page)
+                })
+                .unwrap();
+            v.push(page.physical_address());
+            filled |= hole;
+        }
+
+        drop(tree);
+        if filled {
+            self.invalidate_zero_mapped(start..start.offset(len));
         }
 
         let id = pin_info.id_counter.next_simple();
@@ -198,6 +268,7 @@ impl Object {
             sleep_info: Mutex::new(SleepInfo::new()),
             pin_info: Mutex::new(PinInfo::default()),
             contexts: Mutex::new(ContextInfo::default()),
+            page_in: Mutex::new(None),
         }
     }
 
@@ -209,6 +280,16 @@ impl Object {
         self.contexts.lock().remove(id)
     }
 
+    /// The contexts that this object is mapped in, skipping any that are being dropped.
+    pub fn mapping_contexts(&self) -> Vec<ContextRef> {
+        self.contexts
+            .lock()
+            .contexts
+            .values()
+            .filter_map(|ctx| ctx.0.upgrade())
+            .collect()
+    }
+
     pub fn invalidate(&self, range: core::ops::Range<PageNumber>, mode: InvalidateMode) {
         let contexts = self.contexts.lock();
         for ctx in contexts.contexts.values() {
@@ -229,6 +310,9 @@ impl Object {
 pub enum InvalidateMode {
     Full,
     WriteProtect,
+    /// Like Full, but first flush any pending writes to the memory (e.g. for write-combining
+    /// device memory).
+    Flush,
 }
 
 impl Default for Object {
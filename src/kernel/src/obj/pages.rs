@@ -1,15 +1,20 @@
-use alloc::sync::Arc;
+use alloc::{sync::Arc, vec::Vec};
 use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 
-use twizzler_abi::device::{CacheType, MMIO_OFFSET};
+use intrusive_collections::LinkedList;
+use twizzler_abi::{
+    device::{CacheType, MMIO_OFFSET},
+    object::ObjID,
+};
 
 use super::{Object, PageNumber};
 use crate::{
     arch::memory::{frame::FRAME_SIZE, phys_to_virt},
     memory::{
-        frame::{self, free_frame, FrameRef, PhysicalFrameFlags},
+        frame::{self, free_frame, FrameAdapter, FrameRef, PhysicalFrameFlags},
         PhysAddr, VirtAddr,
     },
+    spinlock::Spinlock,
 };
 
 /// An object page can be either a physical frame (allocatable memory) or a static physical address
@@ -24,6 +29,7 @@ enum FrameOrWired {
 pub struct Page {
     frame: FrameOrWired,
     cache_type: CacheType,
+    pins: AtomicU32,
 }
 
 pub type PageRef = Arc<Page>;
@@ -52,13 +58,91 @@ impl Page {
         Self {
             frame: FrameOrWired::Frame(frame::alloc_frame(PhysicalFrameFlags::ZEROED)),
             cache_type: CacheType::WriteBack,
+            pins: AtomicU32::new(0),
         }
     }
 
+    /// Allocate a new, zeroed page. Returns None if no physical memory is available.
+    pub fn try_new() -> Option<Self> {
+        Some(Self {
+            frame: FrameOrWired::Frame(frame::try_alloc_frame(PhysicalFrameFlags::ZEROED)?),
+            cache_type: CacheType::WriteBack,
+            pins: AtomicU32::new(0),
+        })
+    }
+
+    /// Allocate a new, zeroed page, taking it from the zero pool if the pool has one, so that it
+    /// need not be zeroed now. Otherwise, this is the same as [Self::try_new].
+    pub fn try_new_pooled() -> Option<Self> {
+        let frame = {
+            let mut pool = ZERO_POOL.lock();
+            let frame = pool.frames.pop_front();
+            if frame.is_some() {
+                pool.len -= 1;
+            }
+            frame
+        };
+        let Some(frame) = frame else {
+            ZERO_POOL_MISSES.fetch_add(1, Ordering::Relaxed);
+            return Self::try_new();
+        };
+        ZERO_POOL_HITS.fetch_add(1, Ordering::Relaxed);
+        Some(Self {
+            frame: FrameOrWired::Frame(frame),
+            cache_type: CacheType::WriteBack,
+            pins: AtomicU32::new(0),
+        })
+    }
+
+    /// Allocate a new, zeroed page from the memory attached to the given NUMA node. Returns None if
+    /// that node has no free memory.
+    pub fn new_on_node(node: u8) -> Option<Self> {
+        Some(Self {
+            frame: FrameOrWired::Frame(frame::try_alloc_frame_on_node(
+                node,
+                PhysicalFrameFlags::ZEROED,
+            )?),
+            cache_type: CacheType::WriteBack,
+            pins: AtomicU32::new(0),
+        })
+    }
+
+    /// Allocate a new page, and copy the contents of the page at the given physical address into
+    /// it. Returns None if no physical memory is available.
+    pub fn new_copy_of(pa: PhysAddr) -> Option<Self> {
+        let frame = frame::try_alloc_frame(PhysicalFrameFlags::empty())?;
+        frame.copy_contents_from_physaddr(pa);
+        Some(Self {
+            frame: FrameOrWired::Frame(frame),
+            cache_type: CacheType::WriteBack,
+            pins: AtomicU32::new(0),
+        })
+    }
+
+    /// Allocate count new, zeroed pages that are physically contiguous, in order of physical
+    /// address. Returns None if no contiguous run of that size is available.
+    pub fn new_contiguous(count: usize) -> Option<Vec<Self>> {
+        let first = frame::try_alloc_contiguous_frames(count, PhysicalFrameFlags::ZEROED)?;
+        Some(
+            (0..count)
+                .map(|i| {
+                    let pa = first.start_address().offset(i * FRAME_SIZE).unwrap();
+                    Self {
+                        // Unwrap-Ok: the run was just allocated, so each of its frames exists.
+                        frame: FrameOrWired::Frame(frame::get_frame(pa).unwrap()),
+                        cache_type: CacheType::WriteBack,
+                        pins: AtomicU32::new(0),
+                    }
+                })
+                .collect(),
+        )
+    }
+
     pub fn new_wired(pa: PhysAddr, cache_type: CacheType) -> Self {
         Self {
             frame: FrameOrWired::Wired(pa),
             cache_type,
+            pins: AtomicU32::new(0),
         }
     }
 
@@ -99,21 +183,48 @@ impl Page {
     }
 
     pub fn copy_page(&self) -> Self {
-        let new_frame = frame::alloc_frame(PhysicalFrameFlags::empty());
+        self.try_copy_page().expect("out of memory")
+    }
+
+    /// Copy the page into a newly allocated one, like [Self::copy_page]. Returns None if no
+    /// physical memory is available.
+    pub fn try_copy_page(&self) -> Option<Self> {
+        let new_frame = frame::try_alloc_frame(PhysicalFrameFlags::empty())?;
         match self.frame {
             FrameOrWired::Frame(f) => new_frame.copy_contents_from(f),
             FrameOrWired::Wired(p) => new_frame.copy_contents_from_physaddr(p),
         }
-        Self {
+        Some(Self {
             frame: FrameOrWired::Frame(new_frame),
             // TODO: maybe this should default to write-back instead?
             cache_type: self.cache_type,
-        }
+            pins: AtomicU32::new(0),
+        })
     }
 
     pub fn cache_type(&self) -> CacheType {
         self.cache_type
     }
+
+    /// Pin the page, preventing it from being evicted. Pins nest, and the page stays pinned until
+    /// each pin has been released with [Self::unpin].
+    pub fn pin(&self) {
+        self.pins.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Release a pin on the page. Does nothing if the page is not pinned.
+    pub fn unpin(&self) {
+        let _ = self
+            .pins
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |pins| {
+                pins.checked_sub(1)
+            });
+    }
+
+    /// Is the page pinned?
+    pub fn is_pinned(&self) -> bool {
+        self.pins.load(Ordering::SeqCst) > 0
+    }
 }
 
 impl Object {
@@ -130,6 +241,7 @@ impl Object {
                 let page = Page::new();
                 obj_page_tree.add_page(page_number, page);
                 drop(obj_page_tree);
+                self.invalidate_zero_mapped(page_number..page_number.next());
                 self.write_val_and_signal(offset, val, wakeup_count);
                 return;
             }
@@ -151,6 +263,7 @@ impl Object {
             let page = Page::new();
             obj_page_tree.add_page(page_number, page);
             drop(obj_page_tree);
+            self.invalidate_zero_mapped(page_number..page_number.next());
             self.read_atomic_u64(offset)
         }
     }
@@ -167,12 +280,14 @@ impl Object {
             let page = Page::new();
             obj_page_tree.add_page(page_number, page);
             drop(obj_page_tree);
+            self.invalidate_zero_mapped(page_number..page_number.next());
             self.read_atomic_u32(offset)
         }
     }
 
     pub fn write_base<T>(&self, info: &T) {
         let mut offset = FRAME_SIZE;
+        let mut filled = Vec::new();
         unsafe {
             let mut obj_page_tree = self.lock_page_tree();
             let bytes = info as *const T as *const u8;
@@ -197,12 +312,16 @@ impl Object {
                     //let t = page.get_mut_to_val::<T>(page_offset);
                     //(t as *mut T).copy_from(info as *const T, 1);
                     obj_page_tree.add_page(page_number, page);
+                    filled.push(page_number);
                 }
 
                 offset += thislen;
                 count += thislen;
             }
         }
+        for page_number in filled {
+            self.invalidate_zero_mapped(page_number..page_number.next());
+        }
     }
 
     pub fn map_phys(&self, start: PhysAddr, end: PhysAddr, ct: CacheType) {
@@ -216,3 +335,116 @@ impl Object {
         }
     }
 }
+
+/// The number of zeroed pages kept in the zero pool by default.
+pub const DEFAULT_ZERO_POOL_PAGES: usize = 64;
+
+// The most pages that one call to refill_zero_pool zeroes, so that the idle loop doesn't put off
+// scheduling for long.
+const ZERO_POOL_REFILL_BATCH: usize = 8;
+
+/// A pool of frames that were zeroed ahead of time, so that allocating a zeroed page for a
+/// zero-fill fault need not wait for the frame to be zeroed. The pool is refilled while the
+/// processors are idle.
+struct ZeroPool {
+    frames: LinkedList<FrameAdapter>,
+    len: usize,
+    capacity: usize,
+}
+
+static ZERO_POOL: Spinlock<ZeroPool> = Spinlock::new(ZeroPool {
+    frames: LinkedList::new(FrameAdapter::NEW),
+    len: 0,
+    capacity: DEFAULT_ZERO_POOL_PAGES,
+});
+static ZERO_POOL_HITS: AtomicU64 = AtomicU64::new(0);
+static ZERO_POOL_MISSES: AtomicU64 = AtomicU64::new(0);
+
+/// A point-in-time view of the zero pool.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ZeroPoolStats {
+    /// The number of zeroed pages in the pool.
+    pub pages: usize,
+    /// The number of pages the pool is refilled up to.
+    pub capacity: usize,
+    /// Pages allocated with [Page::try_new_pooled] that were taken from the pool.
+    pub hits: u64,
+    /// Pages allocated with [Page::try_new_pooled] that had to be zeroed on the spot, because the
+    /// pool was empty.
+    pub misses: u64,
+}
+
+/// Get the current state of the zero pool.
+pub fn zero_pool_stats() -> ZeroPoolStats {
+    let pool = ZERO_POOL.lock();
+    ZeroPoolStats {
+        pages: pool.len,
+        capacity: pool.capacity,
+        hits: ZERO_POOL_HITS.load(Ordering::Relaxed),
+        misses: ZERO_POOL_MISSES.load(Ordering::Relaxed),
+    }
+}
+
+/// Set the number of zeroed pages that the zero pool is refilled up to. A size of 0 disables the
+/// pool. If the pool holds more pages than the new size, the extra pages are freed.
+pub fn set_zero_pool_size(pages: usize) {
+    let mut extra = LinkedList::new(FrameAdapter::NEW);
+    {
+        let mut pool = ZERO_POOL.lock();
+        pool.capacity = pages;
+        while pool.len > pages {
+            // Unwrap-Ok: the list holds len frames.
+            extra.push_back(pool.frames.pop_front().unwrap());
+            pool.len -= 1;
+        }
+    }
+    while let Some(frame) = extra.pop_front() {
+        free_frame(frame);
+    }
+}
+
+/// Free every page in the zero pool, leaving its size as it is, so that the pool is refilled once
+/// memory is available again. Returns the number of pages freed.
+pub fn drain_zero_pool() -> usize {
+    let mut frames = {
+        let mut pool = ZERO_POOL.lock();
+        pool.len = 0;
+        pool.frames.take()
+    };
+    let mut freed = 0;
+    while let Some(frame) = frames.pop_front() {
+        free_frame(frame);
+        freed += 1;
+    }
+    freed
+}
+
+/// An out-of-memory hook that gives the pages held in the zero pool back, since they are better
+/// used for the allocation that failed. Registered when memory management is initialized.
+pub fn zero_pool_oom_hook(_id: ObjID, _page: PageNumber) -> bool {
+    drain_zero_pool() > 0
+}
+
+/// Zero some frames and add them to the zero pool, if it is not full. Called from the idle loop.
+pub fn refill_zero_pool() {
+    for _ in 0..ZERO_POOL_REFILL_BATCH {
+        {
+            let pool = ZERO_POOL.lock();
+            if pool.len >= pool.capacity {
+                return;
+            }
+        }
+        // Zero the frame without holding the pool lock.
+        let Some(frame) = frame::try_alloc_frame(PhysicalFrameFlags::ZEROED) else {
+            return;
+        };
+        let mut pool = ZERO_POOL.lock();
+        if pool.len >= pool.capacity {
+            drop(pool);
+            free_frame(frame);
+            return;
+        }
+        pool.frames.push_back(frame);
+        pool.len += 1;
+    }
+}
@@ -70,15 +70,17 @@ impl PageVec {
         pv
     }
 
-    pub fn clone_pages_limited(&self, start: usize, len: usize) -> Self {
+    /// Copy the pages in a range of this vector into a new one. Returns None if a page could not
+    /// be allocated for a copy.
+    pub fn clone_pages_limited(&self, start: usize, len: usize) -> Option<Self> {
         let mut pv = Self::new();
         for (di, si) in (start..(start + len)).enumerate() {
             if let Some(page) = &self.pages[si] {
                 pv.pages.resize(di + 1, None);
-                pv.pages[di] = Some(Arc::new(page.copy_page()));
+                pv.pages[di] = Some(Arc::new(page.try_copy_page()?));
             }
         }
-        pv
+        Some(pv)
     }
 
     pub fn get_page(&mut self, offset: usize) -> PageRef {
@@ -93,6 +95,11 @@ impl PageVec {
         }
     }
 
+    /// Get a page if it is present, without allocating one to fill the hole.
+    pub fn try_get_page(&self, offset: usize) -> Option<PageRef> {
+        self.pages.get(offset)?.clone()
+    }
+
     pub fn add_page(&mut self, offset: usize, page: Page) {
         if offset >= self.pages.len() {
             self.pages.reserve((offset + 1) * 2);
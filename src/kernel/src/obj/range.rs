@@ -42,6 +42,12 @@ impl PageRange {
         self.pv.lock().get_page(self.offset + off)
     }
 
+    fn try_get_page(&self, pn: PageNumber) -> Option<PageRef> {
+        assert!(pn >= self.start);
+        let off = pn - self.start;
+        self.pv.lock().try_get_page(self.offset + off)
+    }
+
     fn add_page(&self, pn: PageNumber, page: Page) {
         assert!(pn >= self.start);
         assert!(pn < self.start.offset(self.length));
@@ -147,17 +153,18 @@ impl PageRangeTree {
         self.tree.remove(pn)
     }
 
-    fn split_into_three(&mut self, pn: PageNumber, discard: bool) {
-        let Some(range) = self.tree.remove(&pn) else {
-            return;
-        };
-        let (r1, mut r2, r3) = range.split_at(pn);
+    /// Split the page at pn out of its range, into a range of its own with a private page vector,
+    /// which is empty if discard is set, and otherwise holds a copy of the page. Returns None if
+    /// the copy could not be allocated, in which case the tree is left unchanged.
+    fn split_into_three(&mut self, pn: PageNumber, discard: bool) -> Option<()> {
+        let (r1, mut r2, r3) = self.tree.get(&pn)?.split_at(pn);
         /* r2 is always the one we want */
         let pv = if discard {
             PageVec::new()
         } else {
-            r2.pv.lock().clone_pages_limited(r2.offset, r2.length)
+            r2.pv.lock().clone_pages_limited(r2.offset, r2.length)?
         };
+        self.tree.remove(&pn);
 
         r2.pv = Arc::new(Mutex::new(pv));
         r2.offset = 0;
@@ -174,6 +181,7 @@ impl PageRangeTree {
             let res = self.insert_replace(r3.range(), r3);
             assert_eq!(res.len(), 0);
         }
+        Some(())
     }
 
     fn do_get_page(&self, pn: PageNumber) -> Option<(PageRef, bool)> {
@@ -188,23 +196,78 @@ impl PageRangeTree {
         if !shared || !is_write {
             return Some((page, shared));
         }
-        self.split_into_three(pn, false);
+        Some(self.break_sharing(pn).expect("out of memory"))
+    }
+
+    /// Copy the page at pn out of the range it shares with other objects, returning the copy.
+    /// Returns None if the copy could not be allocated, in which case the page stays shared.
+    fn break_sharing(&mut self, pn: PageNumber) -> Option<(PageRef, bool)> {
+        self.split_into_three(pn, false)?;
         let (page, shared) = self.do_get_page(pn)?;
         assert!(!shared);
         Some((page, false))
     }
 
+    /// Get a page if it is present in the tree. Unlike [Self::get_page], this never allocates a
+    /// page to fill a hole within a range, and never breaks copy-on-write sharing.
+    pub fn try_get_page(&self, pn: PageNumber) -> Option<(PageRef, bool)> {
+        let range = self.get(pn)?;
+        let page = range.try_get_page(pn)?;
+        Some((page, range.is_shared()))
+    }
+
+    /// Iterate over the pages present in the tree within a range of page numbers, along with
+    /// whether each is shared. Like [Self::try_get_page], this never allocates a page.
+    pub fn present_pages(
+        &self,
+        r: core::ops::Range<PageNumber>,
+    ) -> impl Iterator<Item = (PageNumber, PageRef, bool)> + '_ {
+        self.tree.range(r.clone()).flat_map(move |(start, range)| {
+            let range = range.value();
+            let first = (*start).max(r.start);
+            let end = start.offset(range.length).min(r.end).max(first);
+            let shared = range.is_shared();
+            (first.num()..end.num()).filter_map(move |pn| {
+                let pn = PageNumber::from(pn);
+                range.try_get_page(pn).map(|page| (pn, page, shared))
+            })
+        })
+    }
+
     pub fn get_or_add_page(
         &mut self,
         pn: PageNumber,
         is_write: bool,
         if_not_present: impl Fn(PageNumber, bool) -> Page,
     ) -> (PageRef, bool) {
-        if let Some(out) = self.get_page(pn, is_write) {
-            return out;
+        let (page, shared, _) = self
+            .try_get_or_add_page(pn, is_write, |pn| Some(if_not_present(pn, is_write)))
+            .unwrap();
+        (page, shared)
+    }
+
+    /// Get a page like [Self::get_page], but call if_not_present to provide the page if it is not
+    /// present, whether it lies outside every range or in a hole within one. Returns the page,
+    /// whether it is shared, and whether it filled a hole. If if_not_present fails, or a shared
+    /// page can't be copied for a write, nothing is changed, and None is returned.
+    pub fn try_get_or_add_page(
+        &mut self,
+        pn: PageNumber,
+        is_write: bool,
+        if_not_present: impl FnOnce(PageNumber) -> Option<Page>,
+    ) -> Option<(PageRef, bool, bool)> {
+        let filled = self.try_get_page(pn).is_none();
+        if filled {
+            // A hole in a shared range is split out of it by add_page, so the new page is never
+            // shared.
+            self.add_page(pn, if_not_present(pn)?);
+        }
+        let (page, shared) = self.do_get_page(pn)?;
+        if !shared || !is_write {
+            return Some((page, shared, filled));
         }
-        self.add_page(pn, if_not_present(pn, is_write));
-        self.get_page(pn, is_write).unwrap()
+        let (page, shared) = self.break_sharing(pn)?;
+        Some((page, shared, filled))
     }
 
     pub fn insert_replace(
@@ -248,6 +311,7 @@ impl PageRangeTree {
         let range = self.tree.get(&pn);
         if let Some(mut range) = range {
             if range.is_shared() {
+                // Discarding the shared pages never allocates, so this can't fail.
                 self.split_into_three(pn, true);
                 range = self.tree.get(&pn).unwrap();
             }
@@ -14,7 +14,7 @@ pub fn map_object_into_context(
     perms: Protections,
 ) -> Result<(), ()> {
     let r = vmc.insert_object(
-        slot.try_into()?,
+        slot.try_into().map_err(|_| ())?,
         &ObjectContextInfo::new(obj, perms, twizzler_abi::device::CacheType::WriteBack),
     );
     r.map_err(|_| ())
@@ -55,6 +55,8 @@ pub struct Processor {
     pub stats: ProcessorStats,
     ipi_tasks: Spinlock<Vec<Arc<IpiTask>>>,
     exited: Spinlock<Vec<ThreadRef>>,
+    // The active CPU set of the memory context currently loaded on this processor.
+    loaded_context: Spinlock<Option<Arc<AtomicCpuSet>>>,
 }
 
 const NR_QUEUES: usize = 32;
@@ -190,9 +192,24 @@ impl Processor {
             stats: ProcessorStats::default(),
             ipi_tasks: Spinlock::new(Vec::new()),
             exited: Spinlock::new(Vec::new()),
+            loaded_context: Spinlock::new(None),
         }
     }
 
+    /// Record that the memory context with the active CPU set `cpus` has been loaded on this
+    /// processor, removing this processor from the set of the previously loaded context.
+    pub fn set_loaded_context(&self, cpus: &Arc<AtomicCpuSet>) {
+        let mut loaded = self.loaded_context.lock();
+        if let Some(old) = loaded.as_ref() {
+            if Arc::ptr_eq(old, cpus) {
+                return;
+            }
+            old.remove(self.id);
+        }
+        cpus.insert(self.id);
+        *loaded = Some(cpus.clone());
+    }
+
     pub fn is_bsp(&self) -> bool {
         self.id == self.bsp_id
     }
@@ -264,6 +281,62 @@ impl Processor {
 
 const MAX_CPU_ID: usize = 1024;
 
+const CPU_SET_WORDS: usize = (MAX_CPU_ID + 1).div_ceil(64);
+
+/// A set of processors, by ID.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CpuSet {
+    bits: [u64; CPU_SET_WORDS],
+}
+
+impl CpuSet {
+    pub fn contains(&self, id: u32) -> bool {
+        let id = id as usize;
+        self.bits[id / 64] & (1 << (id % 64)) != 0
+    }
+
+    pub fn insert(&mut self, id: u32) {
+        let id = id as usize;
+        self.bits[id / 64] |= 1 << (id % 64);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bits.iter().all(|word| *word == 0)
+    }
+
+    /// Iterate over the IDs of the processors in the set, in order.
+    pub fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..=MAX_CPU_ID as u32).filter(|id| self.contains(*id))
+    }
+}
+
+/// A [CpuSet] that can be updated atomically.
+#[derive(Default)]
+pub struct AtomicCpuSet {
+    bits: [AtomicU64; CPU_SET_WORDS],
+}
+
+impl AtomicCpuSet {
+    pub fn insert(&self, id: u32) {
+        let id = id as usize;
+        self.bits[id / 64].fetch_or(1 << (id % 64), Ordering::SeqCst);
+    }
+
+    pub fn remove(&self, id: u32) {
+        let id = id as usize;
+        self.bits[id / 64].fetch_and(!(1 << (id % 64)), Ordering::SeqCst);
+    }
+
+    /// Get a snapshot of the set.
+    pub fn load(&self) -> CpuSet {
+        let mut set = CpuSet::default();
+        for (word, bits) in set.bits.iter_mut().zip(self.bits.iter()) {
+            *word = bits.load(Ordering::SeqCst);
+        }
+        set
+    }
+}
+
 pub fn current_processor() -> &'static Processor {
     if !tls_ready() {
         panic!("tried to read a thread-local value with no FS base set");
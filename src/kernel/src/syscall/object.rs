@@ -89,10 +89,10 @@ pub trait ObjectHandle {
     type HandleType;
     fn create_with_handle<NewFn>(obj: ObjectRef, new: NewFn) -> Arc<Self::HandleType>
     where
-        NewFn: FnOnce(ObjectRef) -> Self::HandleType,
+        NewFn: FnOnce(ObjectRef) -> Arc<Self::HandleType>,
         Self: Sized,
     {
-        Arc::new(new(obj))
+        new(obj)
     }
 }
 
@@ -104,7 +104,7 @@ struct Handle<T: ObjectHandle> {
 impl<T: ObjectHandle + Clone> Handle<T> {
     fn new<NewFn>(id: ObjID, new: NewFn) -> Result<Self, NewHandleError>
     where
-        NewFn: FnOnce(ObjectRef) -> T::HandleType,
+        NewFn: FnOnce(ObjectRef) -> Arc<T::HandleType>,
     {
         let obj = crate::obj::lookup_object(id, LookupFlags::empty());
         let obj = match obj {
@@ -147,9 +147,10 @@ pub fn sys_new_handle(id: ObjID, handle_type: HandleType) -> Result<u64, NewHand
         return Err(NewHandleError::AlreadyHandle);
     }
     match handle_type {
-        HandleType::VmContext => ah
-            .vm_contexts
-            .insert(id, Handle::new(id, |_obj| Context::new())?),
+        HandleType::VmContext => {
+            let handle: Handle<ContextRef> = Handle::new(id, |_obj| Context::new())?;
+            ah.vm_contexts.insert(id, handle)
+        }
         HandleType::PagerQueue => {
             if ah.pager_q_count == 2 {
                 return Err(NewHandleError::HandleSaturated);
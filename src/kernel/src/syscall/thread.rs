@@ -17,7 +17,7 @@ pub fn thread_ctrl(cmd: ThreadControl, arg: u64) -> (u64, u64) {
                 return (1, 1);
             };
             // TODO: verify args, check perms.
-            *current_thread_ref().unwrap().upcall_target.lock() = Some(*data);
+            current_thread_ref().unwrap().set_upcall(*data);
         }
         ThreadControl::ResumeFromUpcall => {
             let Some(data) = (unsafe { (arg as usize as *const UpcallFrame).as_ref() }) else {
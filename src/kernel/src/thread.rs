@@ -20,7 +20,10 @@ use self::{
 use crate::{
     idcounter::{Id, IdCounter},
     interrupt,
-    memory::context::{ContextRef, UserContext},
+    memory::{
+        context::{ContextRef, UserContext},
+        VirtAddr,
+    },
     obj::control::ControlObjectCacher,
     processor::{get_processor, KERNEL_STACK_SIZE},
     security::SecCtxMgr,
@@ -35,6 +38,15 @@ pub mod suspend;
 
 pub use flags::{enter_kernel, exit_kernel};
 
+/// Errors for installing an upcall target with [Thread::set_upcall_checked].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpcallTargetError {
+    /// The thread has no memory context to check the target against.
+    NoContext,
+    /// The given entry address is not mapped executable.
+    NotExecutable(usize),
+}
+
 #[derive(Debug, Default)]
 pub struct ThreadStats {
     pub user: AtomicU64,
@@ -268,6 +280,44 @@ impl Thread {
         self.id.value()
     }
 
+    /// Set the upcall target for this thread, replacing any existing target.
+    pub fn set_upcall(&self, target: UpcallTarget) {
+        *self.upcall_target.lock() = Some(target);
+    }
+
+    /// Set the upcall target for this thread, but only if it does not already have one. If it
+    /// does, the existing target is returned as the error.
+    pub fn try_set_upcall(&self, target: UpcallTarget) -> Result<(), UpcallTarget> {
+        let mut current = self.upcall_target.lock();
+        if let Some(existing) = *current {
+            return Err(existing);
+        }
+        *current = Some(target);
+        Ok(())
+    }
+
+    /// Like [Self::set_upcall], but first check that the target's entry addresses are mapped
+    /// executable in this thread's memory context. Addresses that are zero are not checked.
+    pub fn set_upcall_checked(&self, target: UpcallTarget) -> Result<(), UpcallTargetError> {
+        let ctx = self
+            .memory_context
+            .as_ref()
+            .ok_or(UpcallTargetError::NoContext)?;
+        for addr in [target.self_address, target.super_address] {
+            if addr == 0 {
+                continue;
+            }
+            let executable = VirtAddr::new(addr as u64)
+                .map(|vaddr| ctx.is_executable(vaddr))
+                .unwrap_or(false);
+            if !executable {
+                return Err(UpcallTargetError::NotExecutable(addr));
+            }
+        }
+        self.set_upcall(target);
+        Ok(())
+    }
+
     #[track_caller]
     pub fn send_upcall(self: &ThreadRef, info: UpcallInfo) {
         if !self.is_current_thread() {
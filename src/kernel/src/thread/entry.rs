@@ -65,11 +65,8 @@ pub fn start_new_user(args: ThreadSpawnArgs) -> Result<ObjID, ThreadSpawnError>
 }
 
 pub fn start_new_init() {
-    let mut thread = Thread::new(
-        Some(Arc::new(Context::new())),
-        None,
-        Priority::default_user(),
-    );
+    let ctx = Context::new();
+    let mut thread = Thread::new(Some(ctx), None, Priority::default_user());
     thread.secctx = SecCtxMgr::new(Arc::new(SecurityContext::new(None)));
     unsafe {
         thread.init(user_init);
@@ -32,6 +32,8 @@ pub struct ObjectMemoryFaultInfo {
     pub access: MemoryAccessKind,
     /// The virtual address at which the error occurred.
     pub addr: usize,
+    /// The instruction pointer of the faulting instruction.
+    pub ip: usize,
 }
 
 impl ObjectMemoryFaultInfo {
@@ -41,12 +43,14 @@ impl ObjectMemoryFaultInfo {
         error: ObjectMemoryError,
         access: MemoryAccessKind,
         addr: usize,
+        ip: usize,
     ) -> Self {
         Self {
             object_id,
             error,
             access,
             addr,
+            ip,
         }
     }
 }
@@ -59,6 +63,11 @@ pub enum ObjectMemoryError {
     NullPageAccess,
     /// Tried to access outside of an object
     OutOfBounds(usize),
+    /// Tried to access a guard page of an object mapping
+    GuardPageAccess,
+    /// Tried to access a page that is not present in a user-paged mapping, and must be supplied
+    /// by the handler
+    UserPagedRegion,
 }
 
 /// Information about a non-object-related memory access violation.
@@ -69,11 +78,96 @@ pub struct MemoryContextViolationInfo {
     pub address: u64,
     /// The kind of memory access.
     pub kind: MemoryAccessKind,
+    /// Why the access was a violation.
+    pub reason: MemoryContextViolationReason,
 }
 
 impl MemoryContextViolationInfo {
     pub fn new(address: u64, kind: MemoryAccessKind) -> Self {
-        Self { address, kind }
+        Self {
+            address,
+            kind,
+            reason: MemoryContextViolationReason::Inaccessible,
+        }
+    }
+
+    /// Construct info for an access to an address that is not canonical.
+    pub fn non_canonical(address: u64, kind: MemoryAccessKind) -> Self {
+        Self {
+            address,
+            kind,
+            reason: MemoryContextViolationReason::NonCanonical,
+        }
+    }
+}
+
+/// Possible reasons for a memory context violation.
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Ord, Eq)]
+#[repr(u8)]
+pub enum MemoryContextViolationReason {
+    /// The address is not mapped in the memory context, or may not be accessed from userspace.
+    Inaccessible,
+    /// The address is not a canonical address for this architecture.
+    NonCanonical,
+}
+
+/// Information about a page of an object that was allocated by a fault, sent when page sampling
+/// is enabled for a memory context.
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Ord, Eq)]
+#[repr(C)]
+pub struct PageSampleInfo {
+    /// The object the page was allocated for.
+    pub object_id: ObjID,
+    /// The page number of the page within the object.
+    pub page: usize,
+}
+
+impl PageSampleInfo {
+    pub fn new(object_id: ObjID, page: usize) -> Self {
+        Self { object_id, page }
+    }
+}
+
+/// Information about memory pressure in a memory context, sent when a fault would allocate a page
+/// past a limit set for the context. The faulting access is not performed.
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Ord, Eq)]
+#[repr(C)]
+pub struct MemoryPressureInfo {
+    /// The virtual address that caused the fault.
+    pub address: u64,
+    /// The number of pages currently counted against the limit.
+    pub pages: usize,
+    /// The limit that was reached.
+    pub limit: usize,
+}
+
+impl MemoryPressureInfo {
+    pub fn new(address: u64, pages: usize, limit: usize) -> Self {
+        Self {
+            address,
+            pages,
+            limit,
+        }
+    }
+}
+
+/// Information about a fault that could not be resolved because no physical memory was available
+/// for a new page. The faulting access is not performed.
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Ord, Eq)]
+#[repr(C)]
+pub struct OutOfMemoryInfo {
+    /// The object the page was needed for.
+    pub object_id: ObjID,
+    /// The virtual address that caused the fault.
+    pub address: u64,
+}
+
+impl OutOfMemoryInfo {
+    pub fn new(object_id: ObjID, address: u64) -> Self {
+        Self {
+            object_id,
+            address,
+        }
     }
 }
 
@@ -93,17 +187,23 @@ pub enum UpcallInfo {
     Exception(ExceptionInfo),
     ObjectMemoryFault(ObjectMemoryFaultInfo),
     MemoryContextViolation(MemoryContextViolationInfo),
+    PageSample(PageSampleInfo),
+    MemoryPressure(MemoryPressureInfo),
+    OutOfMemory(OutOfMemoryInfo),
 }
 
 impl UpcallInfo {
     /// The number of upcall info variants
-    pub const NR_UPCALLS: usize = 3;
+    pub const NR_UPCALLS: usize = 6;
     /// Get the number associated with this variant
     pub fn number(&self) -> usize {
         match self {
             UpcallInfo::Exception(_) => 0,
             UpcallInfo::ObjectMemoryFault(_) => 1,
             UpcallInfo::MemoryContextViolation(_) => 2,
+            UpcallInfo::PageSample(_) => 3,
+            UpcallInfo::MemoryPressure(_) => 4,
+            UpcallInfo::OutOfMemory(_) => 5,
         }
     }
 }